@@ -1,10 +1,67 @@
 //ref:: https://github.com/andre-richter/qemu-exit
+use crate::drivers::block::{BLOCK_DRIVER, BLOCK_DRIVER1};
+use crate::fs::TTY;
+use crate::drivers::plic::{IntrTargetPriority, PLIC};
+
 pub const MEMORY_END: usize = 0x81000000;
 pub const CLOCK_FREQ: usize = 12500000;
+// number of harts, should be the same as `-smp` of qemu and boot stacks in `entry.asm`
+pub const MAX_HARTS: usize = 4;
+
+pub const VIRT_PLIC: usize = 0x0C00_0000;
+pub const VIRT_UART: usize = 0x1000_0000;
+// virtio-mmio-bus.0 and virtio-mmio-bus.1
+pub const VIRTIO0: usize = 0x1000_1000;
+pub const VIRTIO1: usize = 0x1000_2000;
 
 pub const MMIO: &[(usize, usize)] = &[
     (0x0010_0000, 0x00_2000), // VIRT_TEST/RTC in virt machine
+    (0x0C00_0000, 0x21_0000), // PLIC in virt machine
+    (0x1000_0000, 0x00_1000), // NS16550A UART in virt machine
     (0x1000_1000, 0x00_1000), // Virtio Block in virt machine
+    (0x1000_2000, 0x00_1000), // second Virtio Block in virt machine
 ];
 
-pub type BlockDeviceImpl = crate::drivers::block::VirtIOBlock;
\ No newline at end of file
+pub type BlockDeviceImpl = crate::drivers::block::VirtIOBlock;
+pub type CharDeviceImpl = crate::drivers::chardev::NS16550a<VIRT_UART>;
+
+// interrupt source ids of devices in PLIC
+const VIRTIO0_IRQ: usize = 1;
+const VIRTIO1_IRQ: usize = 2;
+const UART0_IRQ: usize = 10;
+const DEVICE_IRQS: &[usize] = &[VIRTIO0_IRQ, VIRTIO1_IRQ, UART0_IRQ];
+
+// route interrupts of devices to the supervisor mode of all harts
+pub fn device_init() {
+    let mut plic = unsafe { PLIC::new(VIRT_PLIC) };
+    for hart_id in 0..MAX_HARTS {
+        plic.set_threshold(hart_id, IntrTargetPriority::Supervisor, 0);
+        plic.set_threshold(hart_id, IntrTargetPriority::Machine, 1);
+        for intr_src_id in DEVICE_IRQS {
+            plic.enable(hart_id, IntrTargetPriority::Supervisor, *intr_src_id);
+        }
+    }
+    for intr_src_id in DEVICE_IRQS {
+        plic.set_priority(*intr_src_id, 1);
+    }
+}
+
+// handle an external interrupt taken by current hart
+pub fn irq_handler() {
+    let hart_id = crate::task::hart_id();
+    let mut plic = unsafe { PLIC::new(VIRT_PLIC) };
+    let intr_src_id = plic.claim(hart_id, IntrTargetPriority::Supervisor);
+    match intr_src_id as usize {
+        // claimed by another hart already
+        0 => return,
+        VIRTIO0_IRQ => BLOCK_DRIVER.handle_irq(),
+        VIRTIO1_IRQ => {
+            if let Some(driver) = BLOCK_DRIVER1.as_ref() {
+                driver.handle_irq();
+            }
+        }
+        UART0_IRQ => TTY.handle_irq(),
+        _ => panic!("unsupported IRQ {}", intr_src_id),
+    }
+    plic.complete(hart_id, IntrTargetPriority::Supervisor, intr_src_id);
+}
\ No newline at end of file
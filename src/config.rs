@@ -1,6 +1,6 @@
 //! Constants used in rCore
 
-pub const USER_STACK_SIZE: usize = 4096 * 2;
+pub const USER_STACK_SIZE: usize = 4096 * 16;
 pub const KERNEL_STACK_SIZE: usize = 4096 * 2;
 pub const KERNEL_HEAP_SIZE: usize = 0x20_0000;
 // the end of physical memory space, start from 0x80000000 (8MiB)
@@ -8,6 +8,11 @@ pub const PAGE_SIZE: usize = 0x1000;
 pub const PAGE_SIZE_BITS: usize = 0xc;
 
 pub const TRAMPOLINE: usize = usize::MAX - PAGE_SIZE + 1;
-pub const TRAP_CONTEXT: usize = TRAMPOLINE - PAGE_SIZE;
+// trap context of thread `tid` is at `TRAP_CONTEXT_BASE - tid * PAGE_SIZE`
+pub const TRAP_CONTEXT_BASE: usize = TRAMPOLINE - PAGE_SIZE;
+// the bottom of user stack of the main thread, stacks of other threads are above it
+pub const USER_STACK_BASE: usize = 0x30_0000_0000;
+// the lowest address which mmap chooses
+pub const MMAP_BASE: usize = 0x20_0000_0000;
 
-pub use crate::board::{CLOCK_FREQ, MMIO, MEMORY_END};
\ No newline at end of file
+pub use crate::board::{CLOCK_FREQ, MAX_HARTS, MMIO, MEMORY_END};
\ No newline at end of file
@@ -1,15 +1,30 @@
 mod virtio_blk;
 
 use alloc::sync::Arc;
-use easy_fs::BlockDevice;
 use lazy_static::lazy_static;
 pub use virtio_blk::VirtIOBlock;
 
-use crate::board::BlockDeviceImpl;
+use crate::board::{BlockDeviceImpl, VIRTIO0, VIRTIO1};
+
+/// A device of 512-byte blocks, unlike the block device of easy-fs its failures are reported
+pub trait BlockDevice: Send + Sync {
+    /// Read block `block_id` to `buf`, return false if the device fails
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool;
+    /// Write block `block_id` from `buf`, return false if the device fails
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> bool;
+}
 
 lazy_static! {
+    /// Global instance of block device driver, which handles its interrupts
+    pub static ref BLOCK_DRIVER: Arc<BlockDeviceImpl> = Arc::new(BlockDeviceImpl::new(VIRTIO0));
     /// Global instance of block device
-    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = Arc::new(BlockDeviceImpl::new());
+    pub static ref BLOCK_DEVICE: Arc<dyn BlockDevice> = BLOCK_DRIVER.clone();
+    /// Driver of the second block device, if there is one
+    pub static ref BLOCK_DRIVER1: Option<Arc<BlockDeviceImpl>> = if BlockDeviceImpl::probe(VIRTIO1) {
+        Some(Arc::new(BlockDeviceImpl::new(VIRTIO1)))
+    } else {
+        None
+    };
 }
 
 #[allow(unused)]
@@ -21,8 +36,8 @@ pub fn block_device_test() {
         for byte in write_buffer.iter_mut() {
             *byte = i as u8;
         }
-        block_device.write_block(i as usize, &write_buffer);
-        block_device.read_block(i as usize, &mut read_buffer);
+        assert!(block_device.write_block(i as usize, &write_buffer));
+        assert!(block_device.read_block(i as usize, &mut read_buffer));
         assert_eq!(write_buffer, read_buffer);
     }
     println!("block device test passed!");
@@ -1,47 +1,138 @@
 use alloc::vec::Vec;
-use easy_fs::BlockDevice;
 use lazy_static::lazy_static;
-use virtio_drivers::{ Hal, VirtIOBlk, VirtIOHeader };
+use riscv::register::sstatus;
+use virtio_drivers::{ BlkResp, Hal, RespStatus, VirtIOBlk, VirtIOHeader };
 
+use super::BlockDevice;
 use crate::mm::{frame_alloc, frame_dealloc, kernel_token, FrameTracker, PageTable, PhysAddr, PhysPageNum, StepByOne, VirtAddr};
-use crate::sync::UPSafeCell;
+use crate::sync::{irq_restore, irq_save, SpinNoIrqLock, SpinNoIrqLockGuard, WaitQueue};
+use crate::task::current_task;
 
-#[allow(unused)]
-const VIRTIO0: usize = 0x1000_1000;
+// offsets of MMIO registers
+const VIRTIO_MAGIC: usize = 0x000;
+const VIRTIO_DEVICE_ID: usize = 0x008;
+// the device configuration
+const VIRTIO_CONFIG: usize = 0x100;
+// "virt" in little endian
+const MAGIC_VALUE: u32 = 0x7472_6976;
+const DEVICE_ID_BLOCK: u32 = 2;
 
-pub struct VirtIOBlock(UPSafeCell<VirtIOBlk<'static, VirtioHal>>);
+pub struct VirtIOBlock {
+    // base address of MMIO registers
+    base_addr: usize,
+    virtio_blk: SpinNoIrqLock<VirtIOBlk<'static, VirtioHal>>,
+    // the task waiting for each request, indexed by the token of the request
+    wait_queues: Vec<WaitQueue>,
+}
 
 lazy_static!{
     /// Place allocated frames for virtio reqeust/result queue
-    static ref QUEUE_FRAMES: UPSafeCell<Vec<FrameTracker>> = unsafe {
-        UPSafeCell::new(Vec::new())
-    };
+    static ref QUEUE_FRAMES: SpinNoIrqLock<Vec<FrameTracker>> = SpinNoIrqLock::new(Vec::new());
 }
 
 impl BlockDevice for VirtIOBlock {
-    fn read_block(&self, block_id: usize, buf: &mut [u8]) {
+    fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool {
         //trace!("kernel #0", "read_block at block_id = {}", block_id);
-        self.0
-            .exclusive_access()
-            .read_block(block_id, buf)
-            .expect("Error when reading VirtIOBlock");
+        let mut resp = BlkResp::default();
+        if !self.request(|blk| unsafe { blk.read_block_nb(block_id, buf, &mut resp) })
+            || resp.status() != RespStatus::Ok
+        {
+            debug!("kernel #0", "VirtIOBlock: failed to read block {}", block_id);
+            return false;
+        }
+        true
     }
-    fn write_block(&self, block_id: usize, buf: &[u8]) {
+    fn write_block(&self, block_id: usize, buf: &[u8]) -> bool {
         //trace!("kernel #0", "write_block at block_id = {}", block_id);
-        self.0
-            .exclusive_access()
-            .write_block(block_id, buf)
-            .expect("Error when writing VirtIOBlock");
+        let mut resp = BlkResp::default();
+        if !self.request(|blk| unsafe { blk.write_block_nb(block_id, buf, &mut resp) })
+            || resp.status() != RespStatus::Ok
+        {
+            debug!("kernel #0", "VirtIOBlock: failed to write block {}", block_id);
+            return false;
+        }
+        true
     }
 }
 
 impl VirtIOBlock {
-    #[allow(unused)]
-    pub fn new() -> Self {
+    // `base_addr` should be the MMIO address of a virtio block device
+    pub fn new(base_addr: usize) -> Self {
+        let virtio_blk = unsafe {
+            VirtIOBlk::<VirtioHal>::new(&mut *(base_addr as *mut VirtIOHeader)).unwrap()
+        };
+        let wait_queues = (0..virtio_blk.virt_queue_size())
+            .map(|_| WaitQueue::new())
+            .collect();
+        Self {
+            base_addr,
+            virtio_blk: SpinNoIrqLock::new(virtio_blk),
+            wait_queues,
+        }
+    }
+
+    // if there is a block device at `base_addr`, an empty virtio-mmio slot has device id 0
+    pub fn probe(base_addr: usize) -> bool {
         unsafe {
-            Self(UPSafeCell::new(
-                VirtIOBlk::<VirtioHal>::new(&mut *(VIRTIO0 as *mut VirtIOHeader)).unwrap(),
-            ))
+            ((base_addr + VIRTIO_MAGIC) as *const u32).read_volatile() == MAGIC_VALUE
+                && ((base_addr + VIRTIO_DEVICE_ID) as *const u32).read_volatile() == DEVICE_ID_BLOCK
+        }
+    }
+
+    // number of blocks of the device, read from the capacity field of the configuration
+    pub fn capacity(&self) -> usize {
+        unsafe { ((self.base_addr + VIRTIO_CONFIG) as *const u64).read_volatile() as usize }
+    }
+
+    // submit a request by `submit` and return after it is done, false if it can't be submitted.
+    // current task sleeps until the completion interrupt if it can be blocked, otherwise
+    // (at boot, or in a critical section with interrupts disabled) the device is polled
+    fn request<F>(&self, submit: F) -> bool
+    where
+        F: FnOnce(&mut VirtIOBlk<'static, VirtioHal>) -> virtio_drivers::Result<u16>,
+    {
+        let can_block = sstatus::read().sie() && current_task().is_some();
+        // disabled until current task is blocked, then the completion can't be handled
+        // before it is in the wait queue
+        let sie = irq_save();
+        let mut blk = self.virtio_blk.lock();
+        let token = match submit(&mut blk) {
+            Ok(token) => token,
+            Err(_) => {
+                drop(blk);
+                irq_restore(sie);
+                return false;
+            }
+        };
+        if can_block {
+            self.wait_queues[token as usize].wait_with(blk);
+        } else {
+            self.poll(blk, token);
+        }
+        irq_restore(sie);
+        true
+    }
+
+    // poll the device until the request `token` is done, the device is locked all the time
+    fn poll(&self, mut blk: SpinNoIrqLockGuard<'_, VirtIOBlk<'static, VirtioHal>>, token: u16) {
+        loop {
+            match blk.pop_used() {
+                Ok(used) if used == token => break,
+                // requests of blocked tasks
+                Ok(used) => {
+                    self.wait_queues[used as usize].wake_one();
+                }
+                Err(_) => core::hint::spin_loop(),
+            }
+        }
+    }
+
+    // handle the completion interrupt, wake up tasks whose requests are done
+    pub fn handle_irq(&self) {
+        let mut blk = self.virtio_blk.lock();
+        blk.ack_interrupt();
+        while let Ok(token) = blk.pop_used() {
+            self.wait_queues[token as usize].wake_one();
         }
     }
 }
@@ -60,7 +151,7 @@ impl Hal for VirtioHal {
             }
             // make sure the frame is continuous in physical memory
             assert_eq!(frame.ppn.0, ppn_base.0 + i);
-            QUEUE_FRAMES.exclusive_access().push(frame);
+            QUEUE_FRAMES.lock().push(frame);
         }
         let pa: PhysAddr = ppn_base.into();
         pa.0
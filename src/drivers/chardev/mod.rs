@@ -0,0 +1,20 @@
+mod ns16550a;
+
+use lazy_static::lazy_static;
+pub use ns16550a::NS16550a;
+
+use crate::board::CharDeviceImpl;
+
+// A device read and written byte by byte
+pub trait CharDevice {
+    // read a received byte if there is any, never blocks
+    fn try_read(&self) -> Option<u8>;
+    fn write(&self, ch: u8);
+    // handle the interrupt of the device, received bytes are buffered
+    fn handle_irq(&self);
+}
+
+lazy_static! {
+    /// Global instance of the console UART, it is used by `print` before the heap is ready
+    pub static ref UART: CharDeviceImpl = CharDeviceImpl::new();
+}
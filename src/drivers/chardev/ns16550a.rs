@@ -0,0 +1,111 @@
+// NS16550A UART, which is the console of qemu virt machine
+// ref: http://byterunner.com/16550.html
+
+use alloc::collections::VecDeque;
+
+use super::CharDevice;
+use crate::sync::SpinNoIrqLock;
+
+// registers, offsets from the base address
+const RBR: usize = 0; // receive buffer, read only
+const THR: usize = 0; // transmit holding, write only
+const DLL: usize = 0; // divisor latch low, when DLAB is set
+const IER: usize = 1; // interrupt enable
+const DLM: usize = 1; // divisor latch high, when DLAB is set
+const FCR: usize = 2; // FIFO control, write only
+const LCR: usize = 3; // line control
+const MCR: usize = 4; // modem control
+const LSR: usize = 5; // line status
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const FCR_ENABLE_AND_CLEAR: u8 = 0b111;
+const LCR_EIGHT_BITS: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+// data terminal ready, request to send, and aux output 2 which enables interrupts
+const MCR_DTR_RTS_OUT2: u8 = 0b1011;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+// bytes received but not read are dropped if there are more than it
+const READ_BUFFER_SIZE: usize = 1024;
+
+// registers of a NS16550A
+struct NS16550aRaw {
+    base_addr: usize,
+}
+
+impl NS16550aRaw {
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { ((self.base_addr + reg) as *const u8).read_volatile() }
+    }
+    fn write_reg(&mut self, reg: usize, value: u8) {
+        unsafe {
+            ((self.base_addr + reg) as *mut u8).write_volatile(value);
+        }
+    }
+    // 8 data bits, no parity, one stop bit, FIFOs enabled, interrupt when data arrives
+    fn init(&mut self) {
+        self.write_reg(IER, 0);
+        self.write_reg(LCR, LCR_DLAB);
+        // 38.4K baud, it is ignored by qemu
+        self.write_reg(DLL, 0x03);
+        self.write_reg(DLM, 0x00);
+        self.write_reg(LCR, LCR_EIGHT_BITS);
+        self.write_reg(FCR, FCR_ENABLE_AND_CLEAR);
+        self.write_reg(MCR, MCR_DTR_RTS_OUT2);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+    fn read(&mut self) -> Option<u8> {
+        if self.read_reg(LSR) & LSR_DATA_READY != 0 {
+            Some(self.read_reg(RBR))
+        } else {
+            None
+        }
+    }
+    fn write(&mut self, ch: u8) {
+        while self.read_reg(LSR) & LSR_THR_EMPTY == 0 {
+            core::hint::spin_loop();
+        }
+        self.write_reg(THR, ch);
+    }
+}
+
+struct NS16550aInner {
+    ns16550a: NS16550aRaw,
+    // bytes received by interrupts but not read yet
+    read_buffer: VecDeque<u8>,
+}
+
+pub struct NS16550a<const BASE_ADDR: usize> {
+    inner: SpinNoIrqLock<NS16550aInner>,
+}
+
+impl<const BASE_ADDR: usize> NS16550a<BASE_ADDR> {
+    pub fn new() -> Self {
+        let mut ns16550a = NS16550aRaw { base_addr: BASE_ADDR };
+        ns16550a.init();
+        Self {
+            inner: SpinNoIrqLock::new(NS16550aInner {
+                ns16550a,
+                read_buffer: VecDeque::new(),
+            }),
+        }
+    }
+}
+
+impl<const BASE_ADDR: usize> CharDevice for NS16550a<BASE_ADDR> {
+    fn try_read(&self) -> Option<u8> {
+        self.inner.lock().read_buffer.pop_front()
+    }
+    fn write(&self, ch: u8) {
+        self.inner.lock().ns16550a.write(ch);
+    }
+    fn handle_irq(&self) {
+        let mut inner = self.inner.lock();
+        while let Some(ch) = inner.ns16550a.read() {
+            if inner.read_buffer.len() < READ_BUFFER_SIZE {
+                inner.read_buffer.push_back(ch);
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
 pub mod block;
+pub mod chardev;
+pub mod plic;
 
-pub use block::BLOCK_DEVICE;
\ No newline at end of file
+pub use block::BLOCK_DEVICE;
@@ -1,12 +1,27 @@
     .section .text.entry
     .globl _start
 _start:
-    la sp, boot_stack_top
-    call rust_main
+    # the boot hart, initializes the kernel
+    la t1, rust_main
+    j set_boot_stack
+    .globl _start_secondary
+_start_secondary:
+    # other harts, started by the boot hart through SBI HSM
+    la t1, rust_main_secondary
+set_boot_stack:
+    # a0 = hart id, set by SBI, keep it in tp
+    mv tp, a0
+    # each hart uses its own boot stack, sp = boot_stack_lower_bound + (hart id + 1) * 64KiB
+    addi t0, a0, 1
+    slli t0, t0, 16
+    la sp, boot_stack_lower_bound
+    add sp, sp, t0
+    jalr t1
 
     .section .bss.stack
     .globl boot_stack_lower_bound
 boot_stack_lower_bound:
-    .space 4096 * 16
+    # boot stacks of MAX_HARTS harts
+    .space 4096 * 16 * 4
     .globl boot_stack_top
-boot_stack_top:
\ No newline at end of file
+boot_stack_top:
@@ -0,0 +1,358 @@
+//! Device file system, its nodes are opened as device files
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use lazy_static::lazy_static;
+
+use crate::drivers::block::BLOCK_DRIVER;
+use crate::mm::UserBuffer;
+use crate::sync::{Mutex, MutexBlocking, SpinNoIrqLock};
+use crate::timer::get_time;
+
+use super::easyfs;
+use super::stdio::Stdout;
+use super::tty::TTY;
+use super::vfs::{FileSystem, VfsInode};
+use super::{File, SEEK_CUR, SEEK_END, SEEK_SET};
+
+// size of blocks of the raw block device
+const BLOCK_SIZE: usize = 512;
+
+/// The device file system, it has a fixed set of devices
+pub struct DevFs {
+    root: Arc<DevDir>,
+}
+
+impl DevFs {
+    /// Create the file system with all devices
+    pub fn new() -> Self {
+        let devices: [(&'static str, fn() -> Arc<dyn File + Send + Sync>); 5] = [
+            ("null", || Arc::new(Null)),
+            ("zero", || Arc::new(Zero)),
+            ("tty", || Arc::new(Console)),
+            ("random", || Arc::new(Random)),
+            ("vda", || Arc::new(RawBlock::new())),
+        ];
+        let devices = devices
+            .iter()
+            .enumerate()
+            .map(|(i, (name, open))| Arc::new(DevNode { ino: i + 1, name, open: *open }))
+            .collect();
+        Self {
+            root: Arc::new(DevDir { devices }),
+        }
+    }
+}
+
+impl FileSystem for DevFs {
+    fn fs_type(&self) -> &'static str {
+        "devfs"
+    }
+    fn root(&self) -> Arc<dyn VfsInode> {
+        self.root.clone()
+    }
+}
+
+// the root directory, devices are all in it
+struct DevDir {
+    devices: Vec<Arc<DevNode>>,
+}
+
+// a device, each open creates a new device file
+struct DevNode {
+    ino: usize,
+    name: &'static str,
+    open: fn() -> Arc<dyn File + Send + Sync>,
+}
+
+impl VfsInode for DevDir {
+    fn ino(&self) -> usize {
+        0
+    }
+    fn is_dir(&self) -> bool {
+        true
+    }
+    fn size(&self) -> usize {
+        0
+    }
+    fn lookup(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.devices
+            .iter()
+            .find(|device| device.name == name)
+            .map(|device| device.clone() as Arc<dyn VfsInode>)
+    }
+    fn list(&self) -> Vec<(String, bool)> {
+        self.devices
+            .iter()
+            .map(|device| (String::from(device.name), false))
+            .collect()
+    }
+}
+
+impl VfsInode for DevNode {
+    fn ino(&self) -> usize {
+        self.ino
+    }
+    fn is_dir(&self) -> bool {
+        false
+    }
+    fn size(&self) -> usize {
+        0
+    }
+    fn is_device(&self) -> bool {
+        true
+    }
+    fn open_device(&self, readable: bool, writable: bool) -> Option<Arc<dyn File + Send + Sync>> {
+        let device = (self.open)();
+        if (readable && !device.readable()) || (writable && !device.writable()) {
+            return None;
+        }
+        Some(Arc::new(DevFile { readable, writable, device }))
+    }
+}
+
+// an opened device, it can only be read or written as it is opened
+struct DevFile {
+    readable: bool,
+    writable: bool,
+    device: Arc<dyn File + Send + Sync>,
+}
+
+impl File for DevFile {
+    fn readable(&self) -> bool {
+        self.readable
+    }
+    fn writable(&self) -> bool {
+        self.writable
+    }
+    fn read(&self, buf: UserBuffer) -> Option<usize> {
+        self.device.read(buf)
+    }
+    fn write(&self, buf: UserBuffer) -> Option<usize> {
+        self.device.write(buf)
+    }
+    fn seekable(&self) -> bool {
+        self.device.seekable()
+    }
+    fn lseek(&self, offset: isize, whence: usize) -> Option<usize> {
+        self.device.lseek(offset, whence)
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        self.device.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Option<usize> {
+        self.device.write_at(offset, buf)
+    }
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        self.device.ioctl(cmd, arg)
+    }
+    fn path(&self) -> Option<String> {
+        self.device.path()
+    }
+}
+
+// reads nothing and discards writes
+struct Null;
+
+impl File for Null {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/null"))
+    }
+    fn read(&self, _buf: UserBuffer) -> Option<usize> {
+        Some(0)
+    }
+    fn write(&self, buf: UserBuffer) -> Option<usize> {
+        Some(buf.len())
+    }
+}
+
+// reads zeros and discards writes
+struct Zero;
+
+impl File for Zero {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/zero"))
+    }
+    fn read(&self, mut buf: UserBuffer) -> Option<usize> {
+        for slice in buf.buffers.iter_mut() {
+            slice.fill(0);
+        }
+        Some(buf.len())
+    }
+    fn write(&self, buf: UserBuffer) -> Option<usize> {
+        Some(buf.len())
+    }
+}
+
+// the console terminal, the same as stdin and stdout
+struct Console;
+
+impl File for Console {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/tty"))
+    }
+    fn read(&self, buf: UserBuffer) -> Option<usize> {
+        Some(TTY.read(buf))
+    }
+    fn write(&self, buf: UserBuffer) -> Option<usize> {
+        Stdout.write(buf)
+    }
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        TTY.ioctl(cmd, arg)
+    }
+}
+
+lazy_static! {
+    // state of the pseudo-random generator, it is seeded when it is first read
+    static ref RANDOM_STATE: SpinNoIrqLock<u64> = SpinNoIrqLock::new(get_time() as u64 | 1);
+}
+
+// pseudo-random bytes from xorshift64*, not suitable for cryptography.
+// writes are mixed into the state
+struct Random;
+
+impl File for Random {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        true
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/random"))
+    }
+    fn read(&self, mut buf: UserBuffer) -> Option<usize> {
+        let mut state = RANDOM_STATE.lock();
+        for slice in buf.buffers.iter_mut() {
+            for byte in slice.iter_mut() {
+                *state ^= *state >> 12;
+                *state ^= *state << 25;
+                *state ^= *state >> 27;
+                *byte = (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8;
+            }
+        }
+        Some(buf.len())
+    }
+    fn write(&self, buf: UserBuffer) -> Option<usize> {
+        let mut state = RANDOM_STATE.lock();
+        for slice in buf.buffers.iter() {
+            for byte in slice.iter() {
+                *state = (state.rotate_left(8) ^ *byte as u64) | 1;
+            }
+        }
+        Some(buf.len())
+    }
+}
+
+// the raw block device, it is read only, since writes would corrupt easy-fs on it
+struct RawBlock {
+    // held across reading the offset, I/O and updating the offset, see `with_offset`
+    offset_lock: MutexBlocking,
+    offset: SpinNoIrqLock<usize>,
+}
+
+impl RawBlock {
+    fn new() -> Self {
+        Self {
+            offset_lock: MutexBlocking::new(),
+            offset: SpinNoIrqLock::new(0),
+        }
+    }
+    fn size() -> usize {
+        BLOCK_DRIVER.capacity() * BLOCK_SIZE
+    }
+    // call `f` with the offset and store the offset changed by it, the offset itself
+    // isn't held during I/O, so that the device can sleep
+    fn with_offset<T>(&self, f: impl FnOnce(&mut usize) -> T) -> T {
+        self.offset_lock.lock();
+        let mut offset = *self.offset.lock();
+        let ret = f(&mut offset);
+        *self.offset.lock() = offset;
+        self.offset_lock.unlock();
+        ret
+    }
+}
+
+impl File for RawBlock {
+    fn readable(&self) -> bool {
+        true
+    }
+    fn writable(&self) -> bool {
+        false
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/vda"))
+    }
+    fn read(&self, mut buf: UserBuffer) -> Option<usize> {
+        self.with_offset(|offset| {
+            let mut total_read_size = 0usize;
+            for slice in buf.buffers.iter_mut() {
+                let read_size = match self.read_at(*offset, slice) {
+                    Some(read_size) => read_size,
+                    None if total_read_size == 0 => return None,
+                    None => break,
+                };
+                *offset += read_size;
+                total_read_size += read_size;
+                if read_size < slice.len() {
+                    break;
+                }
+            }
+            Some(total_read_size)
+        })
+    }
+    fn write(&self, _buf: UserBuffer) -> Option<usize> {
+        Some(0)
+    }
+    fn seekable(&self) -> bool {
+        true
+    }
+    fn lseek(&self, offset: isize, whence: usize) -> Option<usize> {
+        self.with_offset(|current| {
+            let base = match whence {
+                SEEK_SET => 0,
+                SEEK_CUR => *current,
+                SEEK_END => Self::size(),
+                _ => return None,
+            };
+            let new_offset = (base as isize).checked_add(offset)?;
+            if new_offset < 0 {
+                return None;
+            }
+            *current = new_offset as usize;
+            Some(new_offset as usize)
+        })
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let end = Self::size().min(offset.saturating_add(buf.len()));
+        let mut block = [0u8; BLOCK_SIZE];
+        let mut pos = offset;
+        while pos < end {
+            let (block_id, block_offset) = (pos / BLOCK_SIZE, pos % BLOCK_SIZE);
+            let len = (BLOCK_SIZE - block_offset).min(end - pos);
+            if !easyfs::read_block(block_id, &mut block) {
+                return None;
+            }
+            buf[pos - offset..pos - offset + len].copy_from_slice(&block[block_offset..block_offset + len]);
+            pos += len;
+        }
+        Some(end.saturating_sub(offset))
+    }
+}
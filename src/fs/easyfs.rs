@@ -0,0 +1,202 @@
+//! easy-fs on the block device, adapted to the VFS
+
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+
+use crate::{drivers::BLOCK_DEVICE, sync::SpinNoIrqLock};
+
+use super::efs::{Efs, ROOT_INO};
+use super::vfs::{FileSystem, VfsInode};
+
+lazy_static! {
+    static ref EFS: Efs = Efs::open(BLOCK_DEVICE.clone()).expect("no easy-fs on the block device");
+    // inodes in use by id, so that all users of a file share one `EasyFsInode`
+    static ref INODES: SpinNoIrqLock<BTreeMap<u32, Weak<EasyFsInode>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+}
+
+/// easy-fs on the block device
+pub struct EasyFs;
+
+impl FileSystem for EasyFs {
+    fn fs_type(&self) -> &'static str {
+        "easyfs"
+    }
+    fn root(&self) -> Arc<dyn VfsInode> {
+        get_inode(ROOT_INO, true)
+    }
+}
+
+/// A file or directory of easy-fs
+pub struct EasyFsInode {
+    ino: u32,
+    is_dir: bool,
+    // all its links are removed, it is freed when it is dropped
+    unlinked: AtomicBool,
+}
+
+// the `EasyFsInode` of inode id `ino`
+fn get_inode(ino: u32, is_dir: bool) -> Arc<EasyFsInode> {
+    let mut inodes = INODES.lock();
+    if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+        return inode;
+    }
+    let inode = Arc::new(EasyFsInode {
+        ino,
+        is_dir,
+        unlinked: AtomicBool::new(false),
+    });
+    inodes.insert(ino, Arc::downgrade(&inode));
+    inode
+}
+
+impl EasyFsInode {
+    // remove entry `name` of the directory if it is a directory (`is_dir`) or a file,
+    // directories must be empty
+    fn remove(&self, name: &str, is_dir: bool) -> bool {
+        if !self.is_dir {
+            return false;
+        }
+        let _guard = EFS.lock();
+        let (ino, last_link) = match EFS.remove(self.ino, name, is_dir) {
+            Some(removed) => removed,
+            None => return false,
+        };
+        if last_link {
+            // an open file is freed when its last user is gone
+            let opened = INODES.lock().get(&ino).and_then(Weak::upgrade);
+            match opened {
+                Some(opened) => opened.unlinked.store(true, Ordering::Relaxed),
+                None => {
+                    // the entry is removed already, the inode is lost if it can't be freed
+                    if !EFS.free(ino) {
+                        warn!("kernel #0", "easy-fs: failed to free inode {}", ino);
+                    }
+                }
+            }
+        }
+        true
+    }
+}
+
+impl VfsInode for EasyFsInode {
+    fn ino(&self) -> usize {
+        self.ino as usize
+    }
+    fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+    fn size(&self) -> usize {
+        let _guard = EFS.lock();
+        EFS.size(self.ino).unwrap_or(0)
+    }
+    fn nlink(&self) -> u32 {
+        let _guard = EFS.lock();
+        EFS.nlink(self.ino).unwrap_or(1)
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        if self.is_dir {
+            return Some(0);
+        }
+        let _guard = EFS.lock();
+        EFS.read_at(self.ino, offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Option<usize> {
+        if self.is_dir {
+            return Some(0);
+        }
+        let _guard = EFS.lock();
+        EFS.write_at(self.ino, offset, buf)
+    }
+    fn append(&self, buf: &[u8]) -> Option<(usize, usize)> {
+        if self.is_dir {
+            return Some((0, 0));
+        }
+        let _guard = EFS.lock();
+        let offset = EFS.size(self.ino)?;
+        Some((offset, EFS.write_at(self.ino, offset, buf)?))
+    }
+    fn clear(&self) -> bool {
+        if self.is_dir {
+            return true;
+        }
+        let _guard = EFS.lock();
+        EFS.clear(self.ino)
+    }
+    fn lookup(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        if !self.is_dir {
+            return None;
+        }
+        // looked up with the file system locked, so that it can't be freed by `remove` before
+        let _guard = EFS.lock();
+        let entry = EFS.lookup(self.ino, name)?;
+        Some(get_inode(entry.ino, entry.is_dir))
+    }
+    fn list(&self) -> Vec<(String, bool)> {
+        if !self.is_dir {
+            return Vec::new();
+        }
+        let _guard = EFS.lock();
+        // a directory which can't be read is listed as empty
+        EFS.dir_entries(self.ino)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|entry| (entry.name, entry.is_dir))
+            .collect()
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        if !self.is_dir {
+            return None;
+        }
+        let _guard = EFS.lock();
+        let ino = EFS.create(self.ino, name, false)?;
+        Some(get_inode(ino, false))
+    }
+    fn mkdir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        if !self.is_dir {
+            return None;
+        }
+        let _guard = EFS.lock();
+        let ino = EFS.create(self.ino, name, true)?;
+        Some(get_inode(ino, true))
+    }
+    fn link(&self, name: &str, target: &Arc<dyn VfsInode>) -> bool {
+        if !self.is_dir {
+            return false;
+        }
+        let _guard = EFS.lock();
+        EFS.link(self.ino, name, target.ino() as u32)
+    }
+    fn unlink(&self, name: &str) -> bool {
+        self.remove(name, false)
+    }
+    fn rmdir(&self, name: &str) -> bool {
+        self.remove(name, true)
+    }
+}
+
+impl Drop for EasyFsInode {
+    fn drop(&mut self) {
+        let mut inodes = INODES.lock();
+        // it may be looked up again after the last reference is dropped
+        if inodes.get(&self.ino).map_or(false, |inode| inode.strong_count() == 0) {
+            inodes.remove(&self.ino);
+        }
+        drop(inodes);
+        // the last user of an unlinked file is gone
+        if *self.unlinked.get_mut() {
+            let _guard = EFS.lock();
+            if !EFS.free(self.ino) {
+                warn!("kernel #0", "easy-fs: failed to free inode {}", self.ino);
+            }
+        }
+    }
+}
+
+/// Read block `block_id` of the device under easy-fs, after a change in progress,
+/// return false if the device fails
+pub fn read_block(block_id: usize, buf: &mut [u8]) -> bool {
+    let _guard = EFS.lock();
+    EFS.read_block(block_id, buf)
+}
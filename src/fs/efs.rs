@@ -0,0 +1,544 @@
+//! easy-fs on disk. the easy-fs crate only gives out files of its root directory,
+//! so its inodes, directories and bitmaps are read and written here, and the types
+//! of inodes, their sizes and their link counts are taken from their disk inodes
+
+use alloc::{string::String, sync::Arc, vec::Vec};
+use crate::drivers::block::BlockDevice;
+use crate::sync::{Mutex, MutexBlocking};
+
+use super::{io, IoResult};
+
+const EFS_MAGIC: u32 = 0x3b80_0001;
+const BLOCK_SZ: usize = 512;
+const BLOCK_BITS: usize = BLOCK_SZ * 8;
+const INODE_SIZE: usize = 128;
+const INODES_PER_BLOCK: usize = BLOCK_SZ / INODE_SIZE;
+const INODE_DIRECT_COUNT: usize = 28;
+const INODE_INDIRECT1_COUNT: usize = BLOCK_SZ / 4;
+const INDIRECT1_BOUND: usize = INODE_DIRECT_COUNT + INODE_INDIRECT1_COUNT;
+const MAX_FILE_BLOCKS: usize = INDIRECT1_BOUND + INODE_INDIRECT1_COUNT * INODE_INDIRECT1_COUNT;
+// layout of a disk inode: size, direct blocks, indirect block, doubly indirect block
+// and type. easy-fs pads the type to 4 bytes, the link count is kept in the padding
+const DIRECT_OFFSET: usize = 4;
+const INDIRECT1_OFFSET: usize = 116;
+const INDIRECT2_OFFSET: usize = 120;
+const TYPE_OFFSET: usize = 124;
+const LINKS_OFFSET: usize = 126;
+const TYPE_DIRECTORY: u8 = 1;
+// layout of directory entries, a name ended by 0 and an inode id
+const DIRENT_SZ: usize = 32;
+const INO_OFFSET: usize = 28;
+
+/// Longest name of a directory entry
+pub const NAME_LENGTH_LIMIT: usize = 27;
+/// Inode id of the root directory
+pub const ROOT_INO: u32 = 0;
+
+/// easy-fs on a block device
+pub struct Efs {
+    device: Arc<dyn BlockDevice>,
+    inode_bitmap: Bitmap,
+    inode_area_start: usize,
+    data_bitmap: Bitmap,
+    data_area_start: usize,
+    // serializes all operations, it sleeps so that the device can sleep with it held
+    op_lock: MutexBlocking,
+}
+
+/// `Efs::op_lock` is held until it is dropped
+pub struct OpGuard<'a>(&'a Efs);
+
+impl Drop for OpGuard<'_> {
+    fn drop(&mut self) {
+        self.0.op_lock.unlock();
+    }
+}
+
+// bits of free and used inodes or data blocks
+struct Bitmap {
+    start: usize,
+    blocks: usize,
+    // bits which have inodes or blocks behind them
+    bits: usize,
+}
+
+// an inode on disk
+struct DiskInode {
+    size: usize,
+    direct: [u32; INODE_DIRECT_COUNT],
+    indirect1: u32,
+    indirect2: u32,
+    is_dir: bool,
+    // links besides the first one, inodes created by easy-fs itself have 0 there
+    extra_links: u16,
+}
+
+/// An entry of a directory
+pub struct DirEntry {
+    pub name: String,
+    pub ino: u32,
+    pub is_dir: bool,
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([buf[offset], buf[offset + 1], buf[offset + 2], buf[offset + 3]])
+}
+
+fn set_u32_at(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+// data blocks of a file of `size` bytes
+fn data_blocks(size: usize) -> usize {
+    (size + BLOCK_SZ - 1) / BLOCK_SZ
+}
+
+impl DiskInode {
+    fn new(is_dir: bool) -> Self {
+        Self {
+            size: 0,
+            direct: [0; INODE_DIRECT_COUNT],
+            indirect1: 0,
+            indirect2: 0,
+            is_dir,
+            extra_links: 0,
+        }
+    }
+    fn from_bytes(raw: &[u8]) -> Self {
+        Self {
+            size: u32_at(raw, 0) as usize,
+            direct: core::array::from_fn(|i| u32_at(raw, DIRECT_OFFSET + i * 4)),
+            indirect1: u32_at(raw, INDIRECT1_OFFSET),
+            indirect2: u32_at(raw, INDIRECT2_OFFSET),
+            is_dir: raw[TYPE_OFFSET] == TYPE_DIRECTORY,
+            extra_links: u16::from_le_bytes([raw[LINKS_OFFSET], raw[LINKS_OFFSET + 1]]),
+        }
+    }
+    fn to_bytes(&self, raw: &mut [u8]) {
+        set_u32_at(raw, 0, self.size as u32);
+        for (i, block) in self.direct.iter().enumerate() {
+            set_u32_at(raw, DIRECT_OFFSET + i * 4, *block);
+        }
+        set_u32_at(raw, INDIRECT1_OFFSET, self.indirect1);
+        set_u32_at(raw, INDIRECT2_OFFSET, self.indirect2);
+        raw[TYPE_OFFSET] = self.is_dir as u8;
+        raw[LINKS_OFFSET..LINKS_OFFSET + 2].copy_from_slice(&self.extra_links.to_le_bytes());
+    }
+}
+
+impl Efs {
+    /// Open easy-fs on `device`, None if it is not easy-fs or it can't be read
+    pub fn open(device: Arc<dyn BlockDevice>) -> Option<Self> {
+        let mut block = [0u8; BLOCK_SZ];
+        if !device.read_block(0, &mut block) {
+            return None;
+        }
+        let field = |i: usize| u32_at(&block, i * 4) as usize;
+        // magic, total blocks, inode bitmap blocks, inode area blocks, data bitmap blocks, data area blocks
+        if field(0) as u32 != EFS_MAGIC {
+            return None;
+        }
+        let (inode_bitmap_blocks, inode_area_blocks) = (field(2), field(3));
+        let (data_bitmap_blocks, data_area_blocks) = (field(4), field(5));
+        let inode_area_start = 1 + inode_bitmap_blocks;
+        let data_bitmap_start = inode_area_start + inode_area_blocks;
+        let data_area_start = data_bitmap_start + data_bitmap_blocks;
+        Some(Self {
+            device,
+            inode_bitmap: Bitmap {
+                start: 1,
+                blocks: inode_bitmap_blocks,
+                bits: (inode_bitmap_blocks * BLOCK_BITS).min(inode_area_blocks * INODES_PER_BLOCK),
+            },
+            inode_area_start,
+            data_bitmap: Bitmap {
+                start: data_bitmap_start,
+                blocks: data_bitmap_blocks,
+                bits: (data_bitmap_blocks * BLOCK_BITS).min(data_area_blocks),
+            },
+            data_area_start,
+            op_lock: MutexBlocking::new(),
+        })
+    }
+    /// Lock the file system, the operations below are called with it locked.
+    /// they return None or false if the device fails
+    pub fn lock(&self) -> OpGuard<'_> {
+        self.op_lock.lock();
+        OpGuard(self)
+    }
+    /// Read block `block_id` of the device, blocks are written directly,
+    /// so it is up to date when the file system is locked
+    pub fn read_block(&self, block_id: usize, buf: &mut [u8]) -> bool {
+        self.device.read_block(block_id, buf)
+    }
+
+    /// Size of inode `ino` in bytes
+    pub fn size(&self, ino: u32) -> Option<usize> {
+        Some(self.read_inode(ino).ok()?.size)
+    }
+    /// Number of links of inode `ino`
+    pub fn nlink(&self, ino: u32) -> Option<u32> {
+        Some(self.read_inode(ino).ok()?.extra_links as u32 + 1)
+    }
+    /// Read file `ino` from `offset` to `buf`, return the number of bytes read
+    pub fn read_at(&self, ino: u32, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let inode = self.read_inode(ino).ok()?;
+        self.read_data(&inode, offset, buf).ok()
+    }
+    /// Write file `ino` at `offset` from `buf`, return the number of bytes written,
+    /// which is less than the length of `buf` if the device is full
+    pub fn write_at(&self, ino: u32, offset: usize, buf: &[u8]) -> Option<usize> {
+        self.write_file(ino, offset, buf).ok()
+    }
+    /// Remove all data of file `ino`
+    pub fn clear(&self, ino: u32) -> bool {
+        let clear = || -> IoResult<()> {
+            let mut inode = self.read_inode(ino)?;
+            self.shrink(&mut inode, 0)?;
+            inode.size = 0;
+            self.write_inode(ino, &inode)
+        };
+        clear().is_ok()
+    }
+    /// All entries of directory `dir`
+    pub fn dir_entries(&self, dir: u32) -> Option<Vec<DirEntry>> {
+        self.entries(dir).ok()
+    }
+    /// Entry `name` of directory `dir`
+    pub fn lookup(&self, dir: u32, name: &str) -> Option<DirEntry> {
+        self.entries(dir).ok()?.into_iter().find(|entry| entry.name == name)
+    }
+    /// Create a file or an empty directory (`is_dir`) `name` in directory `dir`,
+    /// return its inode id, or None if `name` exists or there is no space
+    pub fn create(&self, dir: u32, name: &str, is_dir: bool) -> Option<u32> {
+        if !self.is_new_name(dir, name).ok()? {
+            return None;
+        }
+        let ino = self.inode_bitmap.alloc(&self.device).ok()?? as u32;
+        let create = || -> IoResult<bool> {
+            self.write_inode(ino, &DiskInode::new(is_dir))?;
+            self.add_entry(dir, name, ino)
+        };
+        if create().ok() != Some(true) {
+            let _ = self.inode_bitmap.free(&self.device, ino as usize);
+            return None;
+        }
+        Some(ino)
+    }
+    /// Add entry `name` of file `ino` to directory `dir`, return false if `name` exists
+    /// or there is no space
+    pub fn link(&self, dir: u32, name: &str, ino: u32) -> bool {
+        let link = || -> IoResult<bool> {
+            if !self.is_new_name(dir, name)? {
+                return Ok(false);
+            }
+            let mut inode = self.read_inode(ino)?;
+            if inode.is_dir || inode.extra_links == u16::MAX || !self.add_entry(dir, name, ino)? {
+                return Ok(false);
+            }
+            inode.extra_links += 1;
+            self.write_inode(ino, &inode)?;
+            Ok(true)
+        };
+        link().ok() == Some(true)
+    }
+    /// Remove entry `name` of directory `dir` if it is a directory (`is_dir`) or a file,
+    /// directories must be empty. return the inode id of the entry and if its last link
+    /// is removed, it should be freed by `free` then
+    pub fn remove(&self, dir: u32, name: &str, is_dir: bool) -> Option<(u32, bool)> {
+        let mut dir_inode = self.read_inode(dir).ok()?;
+        let entries = self.entries(dir).ok()?;
+        let index = entries.iter().position(|entry| entry.name == name)?;
+        let ino = entries[index].ino;
+        let mut inode = self.read_inode(ino).ok()?;
+        if inode.is_dir != is_dir || (is_dir && inode.size > 0) {
+            return None;
+        }
+        let mut remove = || -> IoResult<bool> {
+            // the last entry takes its place, and the directory shrinks
+            let last = (entries.len() - 1) * DIRENT_SZ;
+            if index * DIRENT_SZ != last {
+                let mut buf = [0u8; DIRENT_SZ];
+                self.read_data(&dir_inode, last, &mut buf)?;
+                self.write_data(&mut dir_inode, index * DIRENT_SZ, &buf)?;
+            }
+            self.shrink(&mut dir_inode, data_blocks(last))?;
+            dir_inode.size = last;
+            self.write_inode(dir, &dir_inode)?;
+            if inode.extra_links > 0 {
+                inode.extra_links -= 1;
+                self.write_inode(ino, &inode)?;
+                return Ok(false);
+            }
+            Ok(true)
+        };
+        Some((ino, remove().ok()?))
+    }
+    /// Free inode `ino` and its data, after its last link is removed
+    pub fn free(&self, ino: u32) -> bool {
+        let free = || -> IoResult<()> {
+            let mut inode = self.read_inode(ino)?;
+            self.shrink(&mut inode, 0)?;
+            self.inode_bitmap.free(&self.device, ino as usize)
+        };
+        free().is_ok()
+    }
+
+    // block and offset of inode `ino` in it
+    fn inode_pos(&self, ino: u32) -> (usize, usize) {
+        let ino = ino as usize;
+        (self.inode_area_start + ino / INODES_PER_BLOCK, ino % INODES_PER_BLOCK * INODE_SIZE)
+    }
+    fn read_inode(&self, ino: u32) -> IoResult<DiskInode> {
+        let (block_id, offset) = self.inode_pos(ino);
+        let mut block = [0u8; BLOCK_SZ];
+        io(self.device.read_block(block_id, &mut block))?;
+        Ok(DiskInode::from_bytes(&block[offset..offset + INODE_SIZE]))
+    }
+    fn write_inode(&self, ino: u32, inode: &DiskInode) -> IoResult<()> {
+        let (block_id, offset) = self.inode_pos(ino);
+        let mut block = [0u8; BLOCK_SZ];
+        io(self.device.read_block(block_id, &mut block))?;
+        inode.to_bytes(&mut block[offset..offset + INODE_SIZE]);
+        io(self.device.write_block(block_id, &block))
+    }
+    fn write_file(&self, ino: u32, offset: usize, buf: &[u8]) -> IoResult<usize> {
+        let mut inode = self.read_inode(ino)?;
+        let size = inode.size;
+        let len = self.write_data(&mut inode, offset, buf)?;
+        if inode.size != size {
+            self.write_inode(ino, &inode)?;
+        }
+        Ok(len)
+    }
+    fn entries(&self, dir: u32) -> IoResult<Vec<DirEntry>> {
+        let inode = self.read_inode(dir)?;
+        let mut entries = Vec::new();
+        let mut buf = [0u8; DIRENT_SZ];
+        while self.read_data(&inode, entries.len() * DIRENT_SZ, &mut buf)? == DIRENT_SZ {
+            let name = &buf[..NAME_LENGTH_LIMIT];
+            let len = name.iter().position(|c| *c == 0).unwrap_or(name.len());
+            let ino = u32_at(&buf, INO_OFFSET);
+            entries.push(DirEntry {
+                name: String::from_utf8_lossy(&name[..len]).into_owned(),
+                ino,
+                is_dir: self.read_inode(ino)?.is_dir,
+            });
+        }
+        Ok(entries)
+    }
+    // if `name` can be a new entry of directory `dir`
+    fn is_new_name(&self, dir: u32, name: &str) -> IoResult<bool> {
+        if name.is_empty() || name.len() > NAME_LENGTH_LIMIT {
+            return Ok(false);
+        }
+        Ok(!self.entries(dir)?.iter().any(|entry| entry.name == name))
+    }
+    // add entry `name` of inode `ino` to the end of directory `dir`, false if there is no space
+    fn add_entry(&self, dir: u32, name: &str, ino: u32) -> IoResult<bool> {
+        let mut buf = [0u8; DIRENT_SZ];
+        buf[..name.len()].copy_from_slice(name.as_bytes());
+        set_u32_at(&mut buf, INO_OFFSET, ino);
+        let size = self.read_inode(dir)?.size;
+        Ok(self.write_file(dir, size, &buf)? == DIRENT_SZ)
+    }
+
+    // a zeroed data block, None if the device is full
+    fn alloc_block(&self) -> IoResult<Option<u32>> {
+        let block_id = match self.data_bitmap.alloc(&self.device)? {
+            Some(bit) => (self.data_area_start + bit) as u32,
+            None => return Ok(None),
+        };
+        io(self.device.write_block(block_id as usize, &[0u8; BLOCK_SZ]))?;
+        Ok(Some(block_id))
+    }
+    fn free_block(&self, block_id: u32) -> IoResult<()> {
+        self.data_bitmap.free(&self.device, block_id as usize - self.data_area_start)
+    }
+    // entry `i` of index block `block_id`
+    fn index_entry(&self, block_id: u32, i: usize) -> IoResult<u32> {
+        let mut block = [0u8; BLOCK_SZ];
+        io(self.device.read_block(block_id as usize, &mut block))?;
+        Ok(u32_at(&block, i * 4))
+    }
+    fn set_index_entry(&self, block_id: u32, i: usize, value: u32) -> IoResult<()> {
+        let mut block = [0u8; BLOCK_SZ];
+        io(self.device.read_block(block_id as usize, &mut block))?;
+        set_u32_at(&mut block, i * 4, value);
+        io(self.device.write_block(block_id as usize, &block))
+    }
+    // block id of data block `index` of `inode`, it must be allocated
+    fn block_id(&self, inode: &DiskInode, index: usize) -> IoResult<u32> {
+        if index < INODE_DIRECT_COUNT {
+            Ok(inode.direct[index])
+        } else if index < INDIRECT1_BOUND {
+            self.index_entry(inode.indirect1, index - INODE_DIRECT_COUNT)
+        } else {
+            let index = index - INDIRECT1_BOUND;
+            let indirect1 = self.index_entry(inode.indirect2, index / INODE_INDIRECT1_COUNT)?;
+            self.index_entry(indirect1, index % INODE_INDIRECT1_COUNT)
+        }
+    }
+    // allocate data block `index` of `inode`, which has `index` blocks, and the index
+    // blocks it needs. return false if the device is full
+    fn add_block(&self, inode: &mut DiskInode, index: usize) -> IoResult<bool> {
+        // index blocks are allocated with their first entries, and freed with them by `shrink`
+        let (new_indirect1, new_indirect2, new_inner) = if index < INDIRECT1_BOUND {
+            (index == INODE_DIRECT_COUNT, false, false)
+        } else {
+            let index = index - INDIRECT1_BOUND;
+            (false, index == 0, index % INODE_INDIRECT1_COUNT == 0)
+        };
+        let count = 1 + new_indirect1 as usize + new_indirect2 as usize + new_inner as usize;
+        let mut blocks = Vec::with_capacity(count);
+        while blocks.len() < count {
+            match self.alloc_block()? {
+                Some(block_id) => blocks.push(block_id),
+                None => {
+                    for block_id in blocks {
+                        self.free_block(block_id)?;
+                    }
+                    return Ok(false);
+                }
+            }
+        }
+        let block_id = blocks.pop().unwrap();
+        if index < INODE_DIRECT_COUNT {
+            inode.direct[index] = block_id;
+        } else if index < INDIRECT1_BOUND {
+            if new_indirect1 {
+                inode.indirect1 = blocks.pop().unwrap();
+            }
+            self.set_index_entry(inode.indirect1, index - INODE_DIRECT_COUNT, block_id)?;
+        } else {
+            let index = index - INDIRECT1_BOUND;
+            let outer = index / INODE_INDIRECT1_COUNT;
+            if new_indirect2 {
+                inode.indirect2 = blocks.pop().unwrap();
+            }
+            if new_inner {
+                self.set_index_entry(inode.indirect2, outer, blocks.pop().unwrap())?;
+            }
+            let indirect1 = self.index_entry(inode.indirect2, outer)?;
+            self.set_index_entry(indirect1, index % INODE_INDIRECT1_COUNT, block_id)?;
+        }
+        Ok(true)
+    }
+    // free data blocks of `inode` from `blocks` on, and the index blocks which
+    // are not needed then. its size is set by the caller
+    fn shrink(&self, inode: &mut DiskInode, blocks: usize) -> IoResult<()> {
+        let count = data_blocks(inode.size);
+        if blocks >= count {
+            return Ok(());
+        }
+        for index in blocks..count {
+            self.free_block(self.block_id(inode, index)?)?;
+        }
+        if blocks <= INODE_DIRECT_COUNT && count > INODE_DIRECT_COUNT {
+            self.free_block(inode.indirect1)?;
+            inode.indirect1 = 0;
+        }
+        if count > INDIRECT1_BOUND {
+            let indirect1_count = |blocks: usize| {
+                (blocks.saturating_sub(INDIRECT1_BOUND) + INODE_INDIRECT1_COUNT - 1) / INODE_INDIRECT1_COUNT
+            };
+            for outer in indirect1_count(blocks)..indirect1_count(count) {
+                self.free_block(self.index_entry(inode.indirect2, outer)?)?;
+            }
+            if blocks <= INDIRECT1_BOUND {
+                self.free_block(inode.indirect2)?;
+                inode.indirect2 = 0;
+            }
+        }
+        Ok(())
+    }
+    fn read_data(&self, inode: &DiskInode, offset: usize, buf: &mut [u8]) -> IoResult<usize> {
+        let end = inode.size.min(offset.saturating_add(buf.len()));
+        let mut block = [0u8; BLOCK_SZ];
+        let mut pos = offset;
+        while pos < end {
+            let (index, block_offset) = (pos / BLOCK_SZ, pos % BLOCK_SZ);
+            let len = (BLOCK_SZ - block_offset).min(end - pos);
+            io(self.device.read_block(self.block_id(inode, index)? as usize, &mut block))?;
+            buf[pos - offset..pos - offset + len].copy_from_slice(&block[block_offset..block_offset + len]);
+            pos += len;
+        }
+        Ok(end.saturating_sub(offset))
+    }
+    // write `inode` at `offset` from `buf`, it grows as far as the device allows
+    fn write_data(&self, inode: &mut DiskInode, offset: usize, buf: &[u8]) -> IoResult<usize> {
+        let mut end = offset.saturating_add(buf.len()).min(MAX_FILE_BLOCKS * BLOCK_SZ);
+        if end <= offset {
+            return Ok(0);
+        }
+        let size = inode.size;
+        if end > size {
+            let old_blocks = data_blocks(size);
+            let mut blocks = old_blocks;
+            while blocks < data_blocks(end) && self.add_block(inode, blocks)? {
+                blocks += 1;
+            }
+            end = end.min(blocks * BLOCK_SZ);
+            if end <= offset {
+                debug!("kernel #0", "easy-fs: no free block to write at offset {}", offset);
+                inode.size = blocks * BLOCK_SZ;
+                self.shrink(inode, old_blocks)?;
+                inode.size = size;
+                return Ok(0);
+            }
+            // the rest of the last block may have stale data of removed directory entries,
+            // blocks after it are zeroed when they are allocated
+            let gap_end = offset.min(old_blocks * BLOCK_SZ);
+            if size < gap_end {
+                let mut block = [0u8; BLOCK_SZ];
+                let block_id = self.block_id(inode, size / BLOCK_SZ)? as usize;
+                io(self.device.read_block(block_id, &mut block))?;
+                block[size % BLOCK_SZ..(gap_end - 1) % BLOCK_SZ + 1].fill(0);
+                io(self.device.write_block(block_id, &block))?;
+            }
+            inode.size = end;
+        }
+        let mut block = [0u8; BLOCK_SZ];
+        let mut pos = offset;
+        while pos < end {
+            let (index, block_offset) = (pos / BLOCK_SZ, pos % BLOCK_SZ);
+            let len = (BLOCK_SZ - block_offset).min(end - pos);
+            let block_id = self.block_id(inode, index)? as usize;
+            if len < BLOCK_SZ {
+                io(self.device.read_block(block_id, &mut block))?;
+            }
+            block[block_offset..block_offset + len].copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            io(self.device.write_block(block_id, &block))?;
+            pos += len;
+        }
+        Ok(end - offset)
+    }
+}
+
+impl Bitmap {
+    // take a free bit, None if there is none
+    fn alloc(&self, device: &Arc<dyn BlockDevice>) -> IoResult<Option<usize>> {
+        let mut block = [0u8; BLOCK_SZ];
+        for i in 0..self.blocks {
+            let valid = self.bits.saturating_sub(i * BLOCK_BITS).min(BLOCK_BITS);
+            if valid == 0 {
+                break;
+            }
+            io(device.read_block(self.start + i, &mut block))?;
+            if let Some(bit) = (0..valid).find(|bit| block[bit / 8] & (1 << (bit % 8)) == 0) {
+                block[bit / 8] |= 1 << (bit % 8);
+                io(device.write_block(self.start + i, &block))?;
+                return Ok(Some(i * BLOCK_BITS + bit));
+            }
+        }
+        Ok(None)
+    }
+    fn free(&self, device: &Arc<dyn BlockDevice>, bit: usize) -> IoResult<()> {
+        let mut block = [0u8; BLOCK_SZ];
+        let block_id = self.start + bit / BLOCK_BITS;
+        let bit = bit % BLOCK_BITS;
+        io(device.read_block(block_id, &mut block))?;
+        block[bit / 8] &= !(1 << (bit % 8));
+        io(device.write_block(block_id, &block))
+    }
+}
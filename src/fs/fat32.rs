@@ -0,0 +1,866 @@
+//! FAT32 file system on a block device, with long file names.
+//! files which are open can't be removed, as FAT has no inodes to keep them
+
+use alloc::{collections::BTreeMap, format, string::String, sync::{Arc, Weak}, vec, vec::Vec};
+use core::sync::atomic::{AtomicU32, Ordering};
+use lazy_static::lazy_static;
+
+use crate::drivers::block::BlockDevice;
+use crate::sync::{Mutex, MutexBlocking, SpinNoIrqLock};
+
+use super::vfs::{FileSystem, VfsInode};
+use super::{io, IoResult};
+
+const SECTOR_SIZE: usize = 512;
+const DIRENT_SIZE: usize = 32;
+// attributes of directory entries
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_LONG_NAME: u8 = 0x0f;
+// first byte of a removed entry, and of the entry after the last one
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+// first byte of a long name entry which is the last part of the name
+const LFN_LAST: u8 = 0x40;
+// characters of a long name in each long name entry
+const LFN_CHARS: usize = 13;
+const MAX_NAME_LEN: usize = 255;
+// values of FAT entries, only the low 28 bits are used
+const FAT_FREE: u32 = 0;
+const FAT_EOC: u32 = 0x0fff_ffff;
+const FAT_MASK: u32 = 0x0fff_ffff;
+// clusters are numbered from 2
+const FIRST_CLUSTER: u32 = 2;
+// flags of short names whose base or extension is in lower case
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+// 1980-01-01, there is no real time clock
+const FAT_DATE: u16 = 0x0021;
+// characters allowed in short names besides letters and digits
+const SHORT_NAME_SPECIAL: &[u8] = b"$%'-_@~`!(){}^#&";
+
+lazy_static! {
+    // file systems in use, a device which is mounted again shares its `Fat`,
+    // otherwise their free clusters and open inodes would be separate
+    static ref FATS: SpinNoIrqLock<Vec<Weak<Fat>>> = SpinNoIrqLock::new(Vec::new());
+}
+
+/// A FAT32 file system
+pub struct FatFs {
+    fat: Arc<Fat>,
+}
+
+// layout of the file system on the device
+struct Fat {
+    device: Arc<dyn BlockDevice>,
+    sectors_per_cluster: usize,
+    // first sector of the first FAT
+    fat_start: usize,
+    // sectors of each FAT
+    fat_sectors: usize,
+    fat_count: usize,
+    // first sector of cluster 2
+    data_start: usize,
+    // clusters are numbered below it
+    cluster_end: u32,
+    root_cluster: u32,
+    // serializes all operations, it sleeps so that the device can sleep with it held
+    op_lock: MutexBlocking,
+    // where the search of free clusters starts, changed with `op_lock` held
+    next_free: AtomicU32,
+    // inodes in use by their ids, it is locked without I/O
+    inodes: SpinNoIrqLock<BTreeMap<usize, Weak<FatInode>>>,
+}
+
+// `Fat::op_lock` is held until it is dropped
+struct OpGuard<'a>(&'a Fat);
+
+impl Drop for OpGuard<'_> {
+    fn drop(&mut self) {
+        self.0.op_lock.unlock();
+    }
+}
+
+/// A file or directory of FAT32
+pub struct FatInode {
+    fat: Arc<Fat>,
+    // byte offset of its short entry on the device, None for the root directory
+    entry: Option<usize>,
+    dir: bool,
+}
+
+// an entry of a directory with its long name entries
+struct DirEntry {
+    name: String,
+    short_name: [u8; 11],
+    attr: u8,
+    first_cluster: u32,
+    // index of the first long name entry, and of the short entry in the directory
+    first_index: usize,
+    index: usize,
+}
+
+impl FatFs {
+    /// Open the FAT32 file system on `device`, None if it is not FAT32
+    pub fn open(device: Arc<dyn BlockDevice>) -> Option<Self> {
+        if let Some(fat) = find_fat(&device) {
+            return Some(Self { fat });
+        }
+        let fat = Arc::new(Fat::open(device)?);
+        let mut fats = FATS.lock();
+        // opened by another task in between
+        if let Some(fat) = find_fat_in(&fats, &fat.device) {
+            return Some(Self { fat });
+        }
+        fats.retain(|fat| fat.strong_count() > 0);
+        fats.push(Arc::downgrade(&fat));
+        Some(Self { fat })
+    }
+}
+
+fn find_fat(device: &Arc<dyn BlockDevice>) -> Option<Arc<Fat>> {
+    find_fat_in(&FATS.lock(), device)
+}
+
+fn find_fat_in(fats: &[Weak<Fat>], device: &Arc<dyn BlockDevice>) -> Option<Arc<Fat>> {
+    // devices are compared by address, without vtables
+    let address = |device: &Arc<dyn BlockDevice>| Arc::as_ptr(device) as *const u8;
+    fats.iter()
+        .filter_map(Weak::upgrade)
+        .find(|fat| address(&fat.device) == address(device))
+}
+
+impl Fat {
+    // read the layout of FAT32 on `device`
+    fn open(device: Arc<dyn BlockDevice>) -> Option<Self> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        if !device.read_block(0, &mut boot) {
+            return None;
+        }
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]) as usize;
+        let u32_at = |offset: usize| u32_from(&boot[offset..]) as usize;
+        let sectors_per_cluster = boot[13] as usize;
+        let reserved_sectors = u16_at(14);
+        let fat_count = boot[16] as usize;
+        let fat_sectors = u32_at(36);
+        // FAT12 and FAT16 have root entries out of clusters and 16 bits FAT size
+        if boot[510..512] != [0x55, 0xaa]
+            || u16_at(11) != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_count == 0
+            || u16_at(17) != 0
+            || u16_at(22) != 0
+            || fat_sectors == 0
+        {
+            return None;
+        }
+        let total_sectors = if u16_at(19) != 0 { u16_at(19) } else { u32_at(32) };
+        let data_start = reserved_sectors + fat_count * fat_sectors;
+        let clusters = total_sectors.checked_sub(data_start)? / sectors_per_cluster;
+        let cluster_end = (clusters + FIRST_CLUSTER as usize).min(fat_sectors * SECTOR_SIZE / 4) as u32;
+        let root_cluster = u32_at(44) as u32;
+        if !(FIRST_CLUSTER..cluster_end).contains(&root_cluster) {
+            return None;
+        }
+        let fat = Fat {
+            device,
+            sectors_per_cluster,
+            fat_start: reserved_sectors,
+            fat_sectors,
+            fat_count,
+            data_start,
+            cluster_end,
+            root_cluster,
+            op_lock: MutexBlocking::new(),
+            next_free: AtomicU32::new(FIRST_CLUSTER),
+            inodes: SpinNoIrqLock::new(BTreeMap::new()),
+        };
+        fat.invalidate_fs_info(u16_at(48)).ok()?;
+        Some(fat)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn fs_type(&self) -> &'static str {
+        "vfat"
+    }
+    fn root(&self) -> Arc<dyn VfsInode> {
+        get_inode(&self.fat, None, true)
+    }
+}
+
+fn u32_from(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// the shared `FatInode` of the entry at `entry`
+fn get_inode(fat: &Arc<Fat>, entry: Option<usize>, dir: bool) -> Arc<FatInode> {
+    let ino = entry.unwrap_or(0);
+    let mut inodes = fat.inodes.lock();
+    if let Some(inode) = inodes.get(&ino).and_then(Weak::upgrade) {
+        return inode;
+    }
+    let inode = Arc::new(FatInode {
+        fat: fat.clone(),
+        entry,
+        dir,
+    });
+    inodes.insert(ino, Arc::downgrade(&inode));
+    inode
+}
+
+impl Fat {
+    fn lock(&self) -> OpGuard<'_> {
+        self.op_lock.lock();
+        OpGuard(self)
+    }
+    // read `buf.len()` bytes at byte `offset` of the device
+    fn read_bytes(&self, offset: usize, buf: &mut [u8]) -> IoResult<()> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let (sector_id, sector_offset) = (pos / SECTOR_SIZE, pos % SECTOR_SIZE);
+            let len = (SECTOR_SIZE - sector_offset).min(offset + buf.len() - pos);
+            io(self.device.read_block(sector_id, &mut sector))?;
+            buf[pos - offset..pos - offset + len].copy_from_slice(&sector[sector_offset..sector_offset + len]);
+            pos += len;
+        }
+        Ok(())
+    }
+    // write `buf` at byte `offset` of the device
+    fn write_bytes(&self, offset: usize, buf: &[u8]) -> IoResult<()> {
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let (sector_id, sector_offset) = (pos / SECTOR_SIZE, pos % SECTOR_SIZE);
+            let len = (SECTOR_SIZE - sector_offset).min(offset + buf.len() - pos);
+            // a part of a sector is read, modified and written back
+            if len < SECTOR_SIZE {
+                io(self.device.read_block(sector_id, &mut sector))?;
+            }
+            sector[sector_offset..sector_offset + len].copy_from_slice(&buf[pos - offset..pos - offset + len]);
+            io(self.device.write_block(sector_id, &sector))?;
+            pos += len;
+        }
+        Ok(())
+    }
+    // the free cluster count in FSInfo is not maintained, so mark it unknown
+    fn invalidate_fs_info(&self, fs_info_sector: usize) -> IoResult<()> {
+        if fs_info_sector == 0 || fs_info_sector == 0xffff {
+            return Ok(());
+        }
+        let mut sector = [0u8; SECTOR_SIZE];
+        io(self.device.read_block(fs_info_sector, &mut sector))?;
+        if u32_from(&sector[0..]) != 0x4161_5252 || u32_from(&sector[484..]) != 0x6141_7272 {
+            return Ok(());
+        }
+        sector[488..496].fill(0xff);
+        io(self.device.write_block(fs_info_sector, &sector))
+    }
+
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+    // byte offset of `cluster` on the device
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        (self.data_start + (cluster - FIRST_CLUSTER) as usize * self.sectors_per_cluster) * SECTOR_SIZE
+    }
+    fn fat_entry(&self, cluster: u32) -> IoResult<u32> {
+        let mut buf = [0u8; 4];
+        self.read_bytes(self.fat_start * SECTOR_SIZE + cluster as usize * 4, &mut buf)?;
+        Ok(u32::from_le_bytes(buf) & FAT_MASK)
+    }
+    // set the entry of `cluster` in all FATs, the high 4 bits are reserved
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> IoResult<()> {
+        for i in 0..self.fat_count {
+            let offset = (self.fat_start + i * self.fat_sectors) * SECTOR_SIZE + cluster as usize * 4;
+            let mut buf = [0u8; 4];
+            self.read_bytes(offset, &mut buf)?;
+            let value = (u32::from_le_bytes(buf) & !FAT_MASK) | (value & FAT_MASK);
+            self.write_bytes(offset, &value.to_le_bytes())?;
+        }
+        Ok(())
+    }
+    // clusters of the chain starting at `first`, 0 is an empty chain
+    fn chain(&self, first: u32) -> IoResult<Vec<u32>> {
+        let mut chain = Vec::new();
+        let mut cluster = first;
+        // a broken chain may be a loop
+        while cluster >= FIRST_CLUSTER && cluster < self.cluster_end && chain.len() < self.cluster_end as usize {
+            chain.push(cluster);
+            cluster = self.fat_entry(cluster)?;
+        }
+        Ok(chain)
+    }
+    // allocate a zeroed cluster at the end of a chain
+    fn alloc_cluster(&self) -> IoResult<Option<u32>> {
+        let next_free = self.next_free.load(Ordering::Relaxed);
+        let count = self.cluster_end - FIRST_CLUSTER;
+        let mut sector = [0u8; SECTOR_SIZE];
+        let mut sector_id = usize::MAX;
+        for i in 0..count {
+            let cluster = FIRST_CLUSTER + (next_free - FIRST_CLUSTER + i) % count;
+            // FAT sectors are read once for 128 clusters
+            let offset = cluster as usize * 4;
+            if offset / SECTOR_SIZE != sector_id {
+                sector_id = offset / SECTOR_SIZE;
+                io(self.device.read_block(self.fat_start + sector_id, &mut sector))?;
+            }
+            if u32_from(&sector[offset % SECTOR_SIZE..]) & FAT_MASK != FAT_FREE {
+                continue;
+            }
+            self.set_fat_entry(cluster, FAT_EOC)?;
+            let zero = [0u8; SECTOR_SIZE];
+            let first_sector = self.cluster_offset(cluster) / SECTOR_SIZE;
+            for sector_id in first_sector..first_sector + self.sectors_per_cluster {
+                io(self.device.write_block(sector_id, &zero))?;
+            }
+            self.next_free.store(FIRST_CLUSTER + (cluster + 1 - FIRST_CLUSTER) % count, Ordering::Relaxed);
+            return Ok(Some(cluster));
+        }
+        Ok(None)
+    }
+    // extend `chain` to `len` clusters, return false if the device is full
+    fn extend_chain(&self, chain: &mut Vec<u32>, len: usize) -> IoResult<bool> {
+        while chain.len() < len {
+            let cluster = match self.alloc_cluster()? {
+                Some(cluster) => cluster,
+                None => return Ok(false),
+            };
+            if let Some(last) = chain.last() {
+                self.set_fat_entry(*last, cluster)?;
+            }
+            chain.push(cluster);
+        }
+        Ok(true)
+    }
+    fn free_chain(&self, first: u32) -> IoResult<()> {
+        for cluster in self.chain(first)? {
+            self.set_fat_entry(cluster, FAT_FREE)?;
+        }
+        Ok(())
+    }
+    // read data at `offset` of `chain`, the range should be in the chain
+    fn read_data(&self, chain: &[u32], offset: usize, buf: &mut [u8]) -> IoResult<()> {
+        let cluster_size = self.cluster_size();
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let (index, cluster_offset) = (pos / cluster_size, pos % cluster_size);
+            let len = (cluster_size - cluster_offset).min(offset + buf.len() - pos);
+            let device_offset = self.cluster_offset(chain[index]) + cluster_offset;
+            self.read_bytes(device_offset, &mut buf[pos - offset..pos - offset + len])?;
+            pos += len;
+        }
+        Ok(())
+    }
+    // write data at `offset` of `chain`, the range should be in the chain
+    fn write_data(&self, chain: &[u32], offset: usize, buf: &[u8]) -> IoResult<()> {
+        let cluster_size = self.cluster_size();
+        let mut pos = offset;
+        while pos < offset + buf.len() {
+            let (index, cluster_offset) = (pos / cluster_size, pos % cluster_size);
+            let len = (cluster_size - cluster_offset).min(offset + buf.len() - pos);
+            let device_offset = self.cluster_offset(chain[index]) + cluster_offset;
+            self.write_bytes(device_offset, &buf[pos - offset..pos - offset + len])?;
+            pos += len;
+        }
+        Ok(())
+    }
+    // byte offset on the device of entry `index` of the directory `chain`
+    fn entry_offset(&self, chain: &[u32], index: usize) -> usize {
+        let offset = index * DIRENT_SIZE;
+        self.cluster_offset(chain[offset / self.cluster_size()]) + offset % self.cluster_size()
+    }
+    // raw data of the directory `chain`
+    fn dir_data(&self, chain: &[u32]) -> IoResult<Vec<u8>> {
+        let mut data = vec![0u8; chain.len() * self.cluster_size()];
+        self.read_data(chain, 0, &mut data)?;
+        Ok(data)
+    }
+    // entries of the directory `chain` except `.` and `..`
+    fn dir_entries(&self, chain: &[u32]) -> IoResult<Vec<DirEntry>> {
+        let data = self.dir_data(chain)?;
+        let mut entries = Vec::new();
+        // long name parts, its checksum and index of its first entry
+        let mut long_name: Vec<u16> = Vec::new();
+        let mut long_name_checksum = 0u8;
+        let mut first_index = 0;
+        for (index, raw) in data.chunks(DIRENT_SIZE).enumerate() {
+            match raw[0] {
+                ENTRY_END => break,
+                ENTRY_FREE => {
+                    long_name.clear();
+                    continue;
+                }
+                _ => {}
+            }
+            if raw[11] == ATTR_LONG_NAME {
+                let order = (raw[0] & !LFN_LAST) as usize;
+                if raw[0] & LFN_LAST != 0 {
+                    long_name = vec![0xffff; order * LFN_CHARS];
+                    long_name_checksum = raw[13];
+                    first_index = index;
+                }
+                if order == 0 || order * LFN_CHARS > long_name.len() || raw[13] != long_name_checksum {
+                    long_name.clear();
+                    continue;
+                }
+                let chars = raw[1..11].chunks(2).chain(raw[14..26].chunks(2)).chain(raw[28..32].chunks(2));
+                for (i, ch) in chars.enumerate() {
+                    long_name[(order - 1) * LFN_CHARS + i] = u16::from_le_bytes([ch[0], ch[1]]);
+                }
+                continue;
+            }
+            let mut short_name = [0u8; 11];
+            short_name.copy_from_slice(&raw[..11]);
+            let attr = raw[11];
+            let has_long_name = !long_name.is_empty() && checksum(&short_name) == long_name_checksum;
+            let name = if has_long_name {
+                let len = long_name.iter().position(|ch| *ch == 0 || *ch == 0xffff).unwrap_or(long_name.len());
+                String::from_utf16_lossy(&long_name[..len])
+            } else {
+                first_index = index;
+                display_short_name(&short_name, raw[12])
+            };
+            long_name.clear();
+            if attr & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+                continue;
+            }
+            let first_cluster = (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                | u16::from_le_bytes([raw[26], raw[27]]) as u32;
+            entries.push(DirEntry {
+                name,
+                short_name,
+                attr,
+                first_cluster,
+                first_index,
+                index,
+            });
+        }
+        Ok(entries)
+    }
+    // add an entry `name` to the directory `chain`, return the offset of its short entry,
+    // or None if `name` can't be added
+    fn add_entry(&self, chain: &mut Vec<u32>, name: &str, attr: u8, first_cluster: u32) -> IoResult<Option<usize>> {
+        let entries = self.dir_entries(chain)?;
+        if !valid_name(name) || entries.iter().any(|entry| entry.name.eq_ignore_ascii_case(name)) {
+            return Ok(None);
+        }
+        let short_names: Vec<[u8; 11]> = entries.iter().map(|entry| entry.short_name).collect();
+        // a name which is a taken short name needs a long name as well
+        let (short_name, case, long_name) = match short_name_of(name) {
+            Some((short_name, case)) if !short_names.contains(&short_name) => (short_name, case, Vec::new()),
+            _ => (generate_short_name(name, &short_names), 0, name.encode_utf16().collect()),
+        };
+        let long_count = (long_name.len() + LFN_CHARS - 1) / LFN_CHARS;
+        let count = long_count + 1;
+        // find `count` free entries in a row, or those after the last entry
+        let data = self.dir_data(chain)?;
+        let mut start = 0;
+        let mut found = false;
+        for (index, raw) in data.chunks(DIRENT_SIZE).enumerate() {
+            if raw[0] == ENTRY_END {
+                break;
+            }
+            if raw[0] != ENTRY_FREE {
+                start = index + 1;
+            } else if index + 1 - start == count {
+                found = true;
+                break;
+            }
+        }
+        if !found {
+            let len = ((start + count) * DIRENT_SIZE + self.cluster_size() - 1) / self.cluster_size();
+            if !self.extend_chain(chain, len)? {
+                return Ok(None);
+            }
+        }
+        let checksum = checksum(&short_name);
+        for i in 0..long_count {
+            let order = long_count - i;
+            let mut raw = [0u8; DIRENT_SIZE];
+            raw[0] = order as u8 | if i == 0 { LFN_LAST } else { 0 };
+            raw[11] = ATTR_LONG_NAME;
+            raw[13] = checksum;
+            // the name ends with a zero and is padded with 0xffff
+            let char_at = |j: usize| {
+                let pos = (order - 1) * LFN_CHARS + j;
+                match pos.cmp(&long_name.len()) {
+                    core::cmp::Ordering::Less => long_name[pos],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xffff,
+                }
+            };
+            let slots = (1..11).step_by(2).chain((14..26).step_by(2)).chain((28..32).step_by(2));
+            for (j, slot) in slots.enumerate() {
+                raw[slot..slot + 2].copy_from_slice(&char_at(j).to_le_bytes());
+            }
+            self.write_bytes(self.entry_offset(chain, start + i), &raw)?;
+        }
+        let offset = self.entry_offset(chain, start + long_count);
+        self.write_bytes(offset, &short_entry(&short_name, attr, case, first_cluster))?;
+        Ok(Some(offset))
+    }
+    // remove `entry` and its long name entries from the directory `chain`
+    fn remove_entry(&self, chain: &[u32], entry: &DirEntry) -> IoResult<()> {
+        for index in entry.first_index..=entry.index {
+            self.write_bytes(self.entry_offset(chain, index), &[ENTRY_FREE])?;
+        }
+        Ok(())
+    }
+    // if the inode of the entry at `offset` is in use
+    fn in_use(&self, offset: usize) -> bool {
+        self.inodes.lock().get(&offset).map_or(false, |inode| inode.strong_count() > 0)
+    }
+}
+
+fn short_entry(short_name: &[u8; 11], attr: u8, case: u8, first_cluster: u32) -> [u8; DIRENT_SIZE] {
+    let mut raw = [0u8; DIRENT_SIZE];
+    raw[..11].copy_from_slice(short_name);
+    raw[11] = attr;
+    raw[12] = case;
+    // creation, access and modification dates
+    for offset in [16, 18, 24] {
+        raw[offset..offset + 2].copy_from_slice(&FAT_DATE.to_le_bytes());
+    }
+    raw[20..22].copy_from_slice(&((first_cluster >> 16) as u16).to_le_bytes());
+    raw[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+    raw
+}
+
+// checksum of a short name stored in its long name entries
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, ch| (sum >> 1 | sum << 7).wrapping_add(*ch))
+}
+
+fn display_short_name(short_name: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| {
+        let mut part: Vec<u8> = bytes.iter().rev().skip_while(|ch| **ch == b' ').cloned().collect();
+        part.reverse();
+        if lower {
+            part.make_ascii_lowercase();
+        }
+        String::from_utf8_lossy(&part).into_owned()
+    };
+    let mut base = short_name[..8].to_vec();
+    // 0x05 stands for a leading 0xe5
+    if base[0] == 0x05 {
+        base[0] = ENTRY_FREE;
+    }
+    let base = part(&base, case & CASE_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], case & CASE_LOWER_EXT != 0);
+    if ext.is_empty() {
+        base
+    } else {
+        format!("{}.{}", base, ext)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && name.encode_utf16().count() <= MAX_NAME_LEN
+        && !name.chars().any(|ch| (ch as u32) < 0x20 || "\"*/:<>?\\|".contains(ch))
+}
+
+fn is_short_name_char(ch: u8) -> bool {
+    ch.is_ascii_alphanumeric() || SHORT_NAME_SPECIAL.contains(&ch)
+}
+
+// the short name of `name` and its case flags, None if it needs a long name
+fn short_name_of(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) => (&name[..i], &name[i + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    let mut short_name = [b' '; 11];
+    let mut case = 0;
+    for (part, range, lower_flag) in [(base, 0..8, CASE_LOWER_BASE), (ext, 8..11, CASE_LOWER_EXT)] {
+        let bytes = part.as_bytes();
+        if !bytes.iter().all(|ch| is_short_name_char(*ch)) {
+            return None;
+        }
+        let has_lower = bytes.iter().any(|ch| ch.is_ascii_lowercase());
+        let has_upper = bytes.iter().any(|ch| ch.is_ascii_uppercase());
+        // mixed case is kept in long names only
+        if has_lower && has_upper {
+            return None;
+        }
+        if has_lower {
+            case |= lower_flag;
+        }
+        short_name[range.start..range.start + bytes.len()].copy_from_slice(&bytes.to_ascii_uppercase());
+    }
+    Some((short_name, case))
+}
+
+// a short name like `BASENA~1.EXT` of a long name, which is not in `short_names`
+fn generate_short_name(name: &str, short_names: &[[u8; 11]]) -> [u8; 11] {
+    let convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|ch| *ch != ' ' && *ch != '.')
+            .map(|ch| {
+                let ch = if ch.is_ascii() { ch.to_ascii_uppercase() as u8 } else { b'_' };
+                if is_short_name_char(ch) { ch } else { b'_' }
+            })
+            .collect()
+    };
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (convert(&name[..i]), convert(&name[i + 1..])),
+        _ => (convert(name), Vec::new()),
+    };
+    let mut short_name = [b' '; 11];
+    let ext_len = ext.len().min(3);
+    short_name[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let keep = base.len().min(8 - tail.len());
+        short_name[..8].fill(b' ');
+        short_name[..keep].copy_from_slice(&base[..keep]);
+        short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !short_names.contains(&short_name) {
+            break;
+        }
+    }
+    short_name
+}
+
+impl FatInode {
+    // (first cluster, size) of it
+    fn info(&self) -> IoResult<(u32, usize)> {
+        match self.entry {
+            Some(offset) => {
+                let mut raw = [0u8; DIRENT_SIZE];
+                self.fat.read_bytes(offset, &mut raw)?;
+                let first_cluster = (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                    | u16::from_le_bytes([raw[26], raw[27]]) as u32;
+                Ok((first_cluster, u32_from(&raw[28..]) as usize))
+            }
+            None => Ok((self.fat.root_cluster, 0)),
+        }
+    }
+    fn set_info(&self, first_cluster: u32, size: usize) -> IoResult<()> {
+        if let Some(offset) = self.entry {
+            self.fat.write_bytes(offset + 20, &((first_cluster >> 16) as u16).to_le_bytes())?;
+            self.fat.write_bytes(offset + 26, &(first_cluster as u16).to_le_bytes())?;
+            self.fat.write_bytes(offset + 28, &(size as u32).to_le_bytes())?;
+        }
+        Ok(())
+    }
+    // read the file from `offset` to `buf`, with the file system locked
+    fn read(&self, offset: usize, buf: &mut [u8]) -> IoResult<usize> {
+        let (first_cluster, size) = self.info()?;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buf.len().min(size - offset);
+        let chain = self.fat.chain(first_cluster)?;
+        // the chain may be shorter than the size if the file system is broken
+        let len = len.min((chain.len() * self.fat.cluster_size()).saturating_sub(offset));
+        self.fat.read_data(&chain, offset, &mut buf[..len])?;
+        Ok(len)
+    }
+    // write the file at `offset` from `buf`, with the file system locked
+    fn write(&self, offset: usize, buf: &[u8]) -> IoResult<usize> {
+        let (first_cluster, size) = self.info()?;
+        let cluster_size = self.fat.cluster_size();
+        let mut chain = self.fat.chain(first_cluster)?;
+        // the size of FAT32 files is 32 bits
+        let end = match offset.checked_add(buf.len()) {
+            Some(end) if end <= u32::MAX as usize => end,
+            _ => return Ok(0),
+        };
+        // write as much as possible if the device is full
+        self.fat.extend_chain(&mut chain, (end + cluster_size - 1) / cluster_size)?;
+        let end = end.min(chain.len() * cluster_size);
+        if end <= offset {
+            // keep clusters which are allocated already
+            self.set_info(chain.first().cloned().unwrap_or(0), size)?;
+            return Ok(0);
+        }
+        // the gap after the end of file may have stale data in its last cluster,
+        // clusters after it are zeroed when they are allocated
+        let gap_end = offset.min((size + cluster_size - 1) / cluster_size * cluster_size);
+        let zero = [0u8; SECTOR_SIZE];
+        let mut pos = size;
+        while pos < gap_end {
+            let len = (gap_end - pos).min(SECTOR_SIZE);
+            self.fat.write_data(&chain, pos, &zero[..len])?;
+            pos += len;
+        }
+        self.fat.write_data(&chain, offset, &buf[..end - offset])?;
+        self.set_info(chain.first().cloned().unwrap_or(0), size.max(end))?;
+        Ok(end - offset)
+    }
+    // entry `name` of the directory, with the cluster chain of the directory
+    fn find(&self, name: &str) -> IoResult<Option<(Vec<u32>, DirEntry)>> {
+        if !self.dir {
+            return Ok(None);
+        }
+        let chain = self.fat.chain(self.info()?.0)?;
+        let entry = self
+            .fat
+            .dir_entries(&chain)?
+            .into_iter()
+            .find(|entry| entry.name.eq_ignore_ascii_case(name));
+        Ok(entry.map(|entry| (chain, entry)))
+    }
+    // remove entry `name` if `remove` returns true for it and it is not in use
+    fn remove(&self, name: &str, remove: impl FnOnce(&DirEntry) -> IoResult<bool>) -> IoResult<bool> {
+        let _guard = self.fat.lock();
+        let (chain, entry) = match self.find(name)? {
+            Some(found) => found,
+            None => return Ok(false),
+        };
+        let offset = self.fat.entry_offset(&chain, entry.index);
+        if !remove(&entry)? || self.fat.in_use(offset) {
+            return Ok(false);
+        }
+        self.fat.free_chain(entry.first_cluster)?;
+        self.fat.remove_entry(&chain, &entry)?;
+        Ok(true)
+    }
+    // create a directory `name` in the directory, with the file system locked
+    fn mkdir(&self, name: &str) -> IoResult<Option<usize>> {
+        let parent_cluster = self.info()?.0;
+        let cluster = match self.fat.alloc_cluster()? {
+            Some(cluster) => cluster,
+            None => return Ok(None),
+        };
+        let mut chain = self.fat.chain(parent_cluster)?;
+        let offset = match self.fat.add_entry(&mut chain, name, ATTR_DIRECTORY, cluster)? {
+            Some(offset) => offset,
+            None => {
+                self.fat.free_chain(cluster)?;
+                return Ok(None);
+            }
+        };
+        // `..` of a directory in the root refers to cluster 0
+        let parent_cluster = if self.entry.is_none() { 0 } else { parent_cluster };
+        let dot = |name: &[u8], cluster: u32| {
+            let mut short_name = [b' '; 11];
+            short_name[..name.len()].copy_from_slice(name);
+            short_entry(&short_name, ATTR_DIRECTORY, 0, cluster)
+        };
+        let cluster_offset = self.fat.cluster_offset(cluster);
+        self.fat.write_bytes(cluster_offset, &dot(b".", cluster))?;
+        self.fat.write_bytes(cluster_offset + DIRENT_SIZE, &dot(b"..", parent_cluster))?;
+        Ok(Some(offset))
+    }
+}
+
+impl Drop for FatInode {
+    fn drop(&mut self) {
+        let mut inodes = self.fat.inodes.lock();
+        let ino = self.entry.unwrap_or(0);
+        // it may be looked up again after the last reference is dropped
+        if inodes.get(&ino).map_or(false, |inode| inode.strong_count() == 0) {
+            inodes.remove(&ino);
+        }
+    }
+}
+
+impl VfsInode for FatInode {
+    fn ino(&self) -> usize {
+        self.entry.unwrap_or(0)
+    }
+    fn is_dir(&self) -> bool {
+        self.dir
+    }
+    fn size(&self) -> usize {
+        if self.dir {
+            return 0;
+        }
+        let _guard = self.fat.lock();
+        self.info().map_or(0, |info| info.1)
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        if self.dir {
+            return Some(0);
+        }
+        let _guard = self.fat.lock();
+        self.read(offset, buf).ok()
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Option<usize> {
+        if self.dir {
+            return Some(0);
+        }
+        let _guard = self.fat.lock();
+        self.write(offset, buf).ok()
+    }
+    fn append(&self, buf: &[u8]) -> Option<(usize, usize)> {
+        if self.dir {
+            return Some((0, 0));
+        }
+        let _guard = self.fat.lock();
+        let offset = self.info().ok()?.1;
+        Some((offset, self.write(offset, buf).ok()?))
+    }
+    fn clear(&self) -> bool {
+        if self.dir {
+            return true;
+        }
+        let _guard = self.fat.lock();
+        let clear = || -> IoResult<()> {
+            self.fat.free_chain(self.info()?.0)?;
+            self.set_info(0, 0)
+        };
+        clear().is_ok()
+    }
+    fn lookup(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        let _guard = self.fat.lock();
+        let (chain, entry) = self.find(name).ok()??;
+        let offset = self.fat.entry_offset(&chain, entry.index);
+        Some(get_inode(&self.fat, Some(offset), entry.attr & ATTR_DIRECTORY != 0))
+    }
+    fn list(&self) -> Vec<(String, bool)> {
+        if !self.dir {
+            return Vec::new();
+        }
+        let _guard = self.fat.lock();
+        let list = || -> IoResult<Vec<(String, bool)>> {
+            let chain = self.fat.chain(self.info()?.0)?;
+            Ok(self
+                .fat
+                .dir_entries(&chain)?
+                .into_iter()
+                .map(|entry| (entry.name, entry.attr & ATTR_DIRECTORY != 0))
+                .collect())
+        };
+        // a directory which can't be read is listed as empty
+        list().unwrap_or_default()
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        if !self.dir {
+            return None;
+        }
+        let _guard = self.fat.lock();
+        let mut chain = self.fat.chain(self.info().ok()?.0).ok()?;
+        let offset = self.fat.add_entry(&mut chain, name, 0, 0).ok()??;
+        Some(get_inode(&self.fat, Some(offset), false))
+    }
+    fn mkdir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        if !self.dir {
+            return None;
+        }
+        let _guard = self.fat.lock();
+        let offset = FatInode::mkdir(self, name).ok()??;
+        Some(get_inode(&self.fat, Some(offset), true))
+    }
+    fn unlink(&self, name: &str) -> bool {
+        self.remove(name, |entry| Ok(entry.attr & ATTR_DIRECTORY == 0)).unwrap_or(false)
+    }
+    fn rmdir(&self, name: &str) -> bool {
+        let fat = self.fat.clone();
+        self.remove(name, |entry| {
+            Ok(entry.attr & ATTR_DIRECTORY != 0 && fat.dir_entries(&fat.chain(entry.first_cluster)?)?.is_empty())
+        })
+        .unwrap_or(false)
+    }
+}
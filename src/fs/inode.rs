@@ -1,10 +1,10 @@
-use alloc::{sync::Arc, vec::Vec};
-use lazy_static::lazy_static;
+use alloc::{string::String, sync::Arc, vec::Vec};
 
-use crate::{drivers::BLOCK_DEVICE, sync::UPSafeCell};
-use easy_fs::{EasyFileSystem, Inode};
+use crate::sync::{Mutex, MutexBlocking, SpinNoIrqLock};
 
-use super::File;
+use super::path::{join, resolve};
+use super::vfs::{lookup, lookup_parent, mount_points_in, Mount, VfsInode};
+use super::{Dirent, File, Stat, StatMode, SEEK_CUR, SEEK_END, SEEK_SET};
 
 
 /// A Wrapper around a filesystem inode
@@ -12,47 +12,75 @@ use super::File;
 pub struct OSInode {
     readable: bool,
     writable: bool,
-    inner: UPSafeCell<OSInodeInner>,
+    // writes always go to the end of the file
+    append: bool,
+    // the file system it is in
+    mount: Arc<Mount>,
+    // components of the absolute path it is opened with
+    path: Vec<String>,
+    // held across reading the offset, I/O and updating the offset, so that threads
+    // sharing this description don't read or write at the same offset
+    offset_lock: MutexBlocking,
+    inner: SpinNoIrqLock<OSInodeInner>,
 }
 /// The OSInode inner
 pub struct OSInodeInner {
+    // number of entries read for a directory
     offset: usize,
-    inode: Arc<Inode>,
-}
-
-lazy_static! {
-    /// The root of the file system
-    pub static ref ROOT_INODE: Arc<Inode> = {
-        let efs = EasyFileSystem::open(BLOCK_DEVICE.clone());
-        Arc::new(EasyFileSystem::root_inode(&efs))
-    };
+    inode: Arc<dyn VfsInode>,
 }
 
 impl OSInode {
-    /// Construct an OS inode from an inode
-    pub fn new(readable: bool, writable: bool, inode: Arc<Inode>) -> Self {
+    /// Construct an OS inode from `inode` at absolute path `path` in `mount`
+    pub fn new(
+        readable: bool,
+        writable: bool,
+        append: bool,
+        mount: Arc<Mount>,
+        path: Vec<String>,
+        inode: Arc<dyn VfsInode>,
+    ) -> Self {
         Self {
             readable,
             writable,
-            inner: unsafe {
-                UPSafeCell::new(OSInodeInner { offset: 0, inode })
-            }
+            append,
+            mount,
+            path,
+            offset_lock: MutexBlocking::new(),
+            inner: SpinNoIrqLock::new(OSInodeInner { offset: 0, inode })
         }
     }
-    /// Read all data inside an inode into vector
-    pub fn read_all(&self) -> Vec<u8> {
-        let mut inner = self.inner.exclusive_access();
-        let mut buffer = [0u8; 512];
-        let mut v: Vec<u8> = Vec::new();
-        loop {
-            let len = inner.inode.read_at(inner.offset, &mut buffer);
-            if len == 0 {
-                break;
+    /// Read all data inside an inode into vector, None if the device fails
+    pub fn read_all(&self) -> Option<Vec<u8>> {
+        self.with_offset(|inode, offset| {
+            let mut buffer = [0u8; 512];
+            let mut v: Vec<u8> = Vec::new();
+            loop {
+                let len = inode.read_at(*offset, &mut buffer)?;
+                if len == 0 {
+                    break;
+                }
+                *offset += len;
+                v.extend_from_slice(&buffer[..len])
             }
-            inner.offset += len;
-            v.extend_from_slice(&buffer[..len])
-        }
-        v
+            Some(v)
+        })
+    }
+    // the inode and the offset, the inner isn't held during I/O since it disables
+    // interrupts, and block devices can only sleep with interrupts enabled
+    fn inode_and_offset(&self) -> (Arc<dyn VfsInode>, usize) {
+        let inner = self.inner.lock();
+        (inner.inode.clone(), inner.offset)
+    }
+    // call `f` with the inode and the offset, and store the offset changed by it.
+    // the description is locked by `offset_lock` meanwhile, which sleeps
+    fn with_offset<T>(&self, f: impl FnOnce(&Arc<dyn VfsInode>, &mut usize) -> T) -> T {
+        self.offset_lock.lock();
+        let (inode, mut offset) = self.inode_and_offset();
+        let ret = f(&inode, &mut offset);
+        self.inner.lock().offset = offset;
+        self.offset_lock.unlock();
+        ret
     }
 }
 
@@ -63,30 +91,115 @@ impl File for OSInode {
     fn writable(&self) -> bool {
         self.writable
     }
-    fn read(&self, mut buf: crate::mm::UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_read_size = 0usize;
-        for slice in buf.buffers.iter_mut() {
-            let read_size = inner.inode.read_at(inner.offset, *slice);
-            if read_size == 0 {
-                break;
+    // a failure of the device after some bytes are transferred ends the transfer there
+    fn read(&self, mut buf: crate::mm::UserBuffer) -> Option<usize> {
+        self.with_offset(|inode, offset| {
+            let mut total_read_size = 0usize;
+            for slice in buf.buffers.iter_mut() {
+                let read_size = match inode.read_at(*offset, *slice) {
+                    Some(read_size) => read_size,
+                    None if total_read_size == 0 => return None,
+                    None => break,
+                };
+                if read_size == 0 {
+                    break;
+                }
+                *offset += read_size;
+                total_read_size += read_size;
             }
-            inner.offset += read_size;
-            total_read_size += read_size;
-        }
-        total_read_size
-    }
-    fn write(&self, buf: crate::mm::UserBuffer) -> usize {
-        let mut inner = self.inner.exclusive_access();
-        let mut total_write_size = 0usize;
-        for slice in buf.buffers.iter() {
-            trace!("kernel #0", "Write to inode at offset {}, content {:?}", inner.offset, *slice);
-            let write_size = inner.inode.write_at(inner.offset, *slice);
-            assert_eq!(write_size, slice.len());
-            inner.offset += write_size;
-            total_write_size += write_size;
-        }
-        total_write_size
+            Some(total_read_size)
+        })
+    }
+    fn write(&self, buf: crate::mm::UserBuffer) -> Option<usize> {
+        self.with_offset(|inode, offset| {
+            if self.append {
+                // the data is written in one piece, other appends can't come in between
+                let data: Vec<u8> = buf.buffers.iter().flat_map(|slice| slice.iter().copied()).collect();
+                let (start, write_size) = inode.append(&data)?;
+                *offset = start + write_size;
+                return Some(write_size);
+            }
+            let mut total_write_size = 0usize;
+            for slice in buf.buffers.iter() {
+                trace!("kernel #0", "Write to inode at offset {}, content {:?}", *offset, *slice);
+                let write_size = match inode.write_at(*offset, *slice) {
+                    Some(write_size) => write_size,
+                    None if total_write_size == 0 => return None,
+                    None => break,
+                };
+                *offset += write_size;
+                total_write_size += write_size;
+                if write_size < slice.len() {
+                    break;
+                }
+            }
+            Some(total_write_size)
+        })
+    }
+    fn seekable(&self) -> bool {
+        true
+    }
+    fn lseek(&self, offset: isize, whence: usize) -> Option<usize> {
+        self.with_offset(|inode, current| {
+            if inode.is_dir() {
+                return None;
+            }
+            let base = match whence {
+                SEEK_SET => 0,
+                SEEK_CUR => *current,
+                SEEK_END => inode.size(),
+                _ => return None,
+            };
+            // the offset can be beyond the end, but not before the start
+            let new_offset = (base as isize).checked_add(offset)?;
+            if new_offset < 0 {
+                return None;
+            }
+            *current = new_offset as usize;
+            Some(new_offset as usize)
+        })
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        self.inode_and_offset().0.read_at(offset, buf)
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Option<usize> {
+        self.inode_and_offset().0.write_at(offset, buf)
+    }
+    fn getdents(&self, count: usize) -> Option<Vec<Dirent>> {
+        self.with_offset(|inode, offset| {
+            if !inode.is_dir() {
+                return None;
+            }
+            let mut entries = inode.list();
+            // mount points are directories even if they don't exist in this file system
+            for name in mount_points_in(&self.path) {
+                if !entries.iter().any(|(entry_name, _)| *entry_name == name) {
+                    entries.push((name, true));
+                }
+            }
+            let dirents: Vec<Dirent> = entries
+                .iter()
+                .skip(*offset)
+                .take(count)
+                .map(|(name, is_dir)| Dirent::new(name, *is_dir))
+                .collect();
+            *offset += dirents.len();
+            Some(dirents)
+        })
+    }
+    fn stat(&self) -> Option<Stat> {
+        let (inode, _) = self.inode_and_offset();
+        let mode = if inode.is_dir() { StatMode::DIR } else { StatMode::FILE };
+        Some(Stat {
+            dev: self.mount.id as u64,
+            ino: inode.ino() as u64,
+            mode,
+            nlink: inode.nlink(),
+            size: inode.size() as u64,
+        })
+    }
+    fn path(&self) -> Option<String> {
+        Some(join(&self.path))
     }
 }
 
@@ -103,6 +216,8 @@ bitflags! {
         const CREATE = 1 << 9;
         /// Clear file and return an empty one
         const TRUNC = 1 << 10;
+        /// Write to the end of file every time
+        const APPEND = 1 << 11;
     }
 }
 
@@ -118,36 +233,143 @@ impl OpenFlags{
         }
     }
 }
-/// Open file with flag
-pub fn open_file(name: &str, flags: OpenFlags) -> Option<Arc<OSInode>> {
+/// Working directory of a process, it holds the mount it is in like an open file,
+/// so that the file system can't be unmounted under it
+#[derive(Clone)]
+pub struct WorkDir {
+    path: String,
+    _mount: Arc<Mount>,
+}
+
+impl WorkDir {
+    /// The root directory
+    pub fn root() -> Self {
+        Self::open("/").unwrap()
+    }
+    /// The directory at `path`, relative paths are relative to the root.
+    /// None if it is not a directory
+    pub fn open(path: &str) -> Option<Self> {
+        let components = resolve("/", path);
+        let (mount, inode) = lookup(&components)?;
+        if !inode.is_dir() {
+            return None;
+        }
+        Some(Self {
+            path: join(&components),
+            _mount: mount,
+        })
+    }
+    /// Absolute path of the directory
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Create a directory at `path`, return false if the path exists or its parent is not
+/// a directory which subdirectories can be created in
+pub fn create_dir(path: &str) -> bool {
+    let components = resolve("/", path);
+    if lookup(&components).is_some() {
+        return false;
+    }
+    match lookup_parent(&components) {
+        Some((_, parent, name)) => parent.mkdir(name).is_some(),
+        None => false,
+    }
+}
+
+/// Open file with flag, relative paths are relative to the root.
+/// directories can only be opened read only, and device nodes are opened as their devices
+pub fn open_file(path: &str, flags: OpenFlags) -> Option<Arc<dyn File + Send + Sync>> {
+    let components = resolve("/", path);
     let (readable, writable) = flags.read_write();
-    if flags.contains(OpenFlags::CREATE) {
-        if let Some(inode) = ROOT_INODE.find(name) {
-            trace!("kernel#0", "File {} already exists and cleared", name);
-            // clear file
-            inode.clear();
-            Some(Arc::new(OSInode::new(readable, writable, inode)))
-        } else {
-            trace!("kernel#0", "File {} not exists, create it", name);
-            // create new file
-            ROOT_INODE
-                .create(name)
-                .map(|inode| Arc::new(OSInode::new(readable, writable, inode)))
+    let append = flags.contains(OpenFlags::APPEND);
+    let (mount, inode) = match lookup(&components) {
+        Some((mount, inode)) => {
+            if inode.is_device() {
+                return inode.open_device(readable, writable);
+            }
+            if inode.is_dir() {
+                if (readable, writable) != (true, false) {
+                    return None;
+                }
+            } else if flags.contains(OpenFlags::CREATE) || flags.contains(OpenFlags::TRUNC) {
+                trace!("kernel#0", "File {} already exists and cleared", path);
+                // clear file
+                if !inode.clear() {
+                    return None;
+                }
+            }
+            (mount, inode)
         }
-    } else {
-        ROOT_INODE.find(name).map(|inode| {
-            if flags.contains(OpenFlags::TRUNC) {
-                inode.clear();
+        None => {
+            if !flags.contains(OpenFlags::CREATE) {
+                return None;
             }
-            Arc::new(OSInode::new(readable, writable, inode))
-        })
+            trace!("kernel#0", "File {} not exists, create it", path);
+            let (mount, parent, name) = lookup_parent(&components)?;
+            // it may be created by another task in between
+            let inode = parent.create(name).or_else(|| parent.lookup(name))?;
+            (mount, inode)
+        }
+    };
+    Some(Arc::new(OSInode::new(readable, writable, append, mount, components, inode)))
+}
+
+/// Read all data of the regular file `path`, relative paths are relative to the root,
+/// None if it is not a regular file or the device fails
+pub fn read_file(path: &str) -> Option<Vec<u8>> {
+    let components = resolve("/", path);
+    let (mount, inode) = lookup(&components)?;
+    if inode.is_dir() || inode.is_device() {
+        return None;
+    }
+    OSInode::new(true, false, false, mount, components, inode).read_all()
+}
+
+/// Create a hard link `new_path` to the file `old_path`, relative paths are relative
+/// to the root. return false if `old_path` is not a file, `new_path` exists,
+/// or they are in different file systems
+pub fn link(old_path: &str, new_path: &str) -> bool {
+    let (old_mount, inode) = match lookup(&resolve("/", old_path)) {
+        Some((mount, inode)) if !inode.is_dir() => (mount, inode),
+        _ => return false,
+    };
+    let new_components = resolve("/", new_path);
+    let (new_mount, dir, name) = match lookup_parent(&new_components) {
+        Some(parent) => parent,
+        None => return false,
+    };
+    if !Arc::ptr_eq(&old_mount, &new_mount) {
+        return false;
+    }
+    dir.link(name, &inode)
+}
+
+/// Remove the link `path` to a file, relative paths are relative to the root.
+/// data of the file is freed after its last link is removed and it is closed.
+/// return false if `path` is not a file
+pub fn unlink(path: &str) -> bool {
+    match lookup_parent(&resolve("/", path)) {
+        Some((_, dir, name)) => dir.unlink(name),
+        None => false,
+    }
+}
+/// Remove the empty directory `path`, relative paths are relative to the root.
+/// return false if `path` is not an empty directory or it is a mount point
+pub fn remove_dir(path: &str) -> bool {
+    match lookup_parent(&resolve("/", path)) {
+        Some((_, dir, name)) => dir.rmdir(name),
+        None => false,
     }
 }
 /// List all files in the filesystem
 pub fn list_apps() {
     println!("/******* APPS *******");
-    for app in ROOT_INODE.ls() {
-        println!("{}", app);
+    if let Some((_, root)) = lookup(&[]) {
+        for (app, _) in root.list() {
+            println!("{}", app);
+        }
     }
     println!("********************/");
-}
\ No newline at end of file
+}
@@ -1,13 +1,61 @@
 //! File system in os
 
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::drivers::block::BLOCK_DRIVER1;
 use crate::mm::UserBuffer;
 
+mod devfs;
+mod easyfs;
+mod efs;
+mod fat32;
 mod inode;
+mod path;
 mod stdio;
 mod pipe;
-pub use inode::{ open_file, OpenFlags, list_apps};
+mod procfs;
+mod tmpfs;
+mod tty;
+mod vfs;
+pub use inode::{ create_dir, link, open_file, read_file, remove_dir, unlink, OpenFlags, WorkDir, list_apps};
+pub use vfs::{mount, umount, FileSystem, VfsInode};
+pub use path::{join, resolve};
 pub use stdio::{Stdin, Stdout, Stderr};
 pub use pipe::{make_pipe, Pipe};
+pub use tty::TTY;
+
+/// Mount file systems other than the root
+pub fn init() {
+    // scratch files are kept in memory
+    if !mount("", "/tmp", "tmpfs") {
+        warn!("kernel #0", "failed to mount tmpfs at /tmp");
+    }
+    if !mount("", "/dev", "devfs") {
+        warn!("kernel #0", "failed to mount devfs at /dev");
+    }
+    if !mount("", "/proc", "proc") {
+        warn!("kernel #0", "failed to mount procfs at /proc");
+    }
+    // the second block device is a FAT32 disk if it is attached
+    if BLOCK_DRIVER1.is_some() && !mount("/dev/vdb", "/mnt", "vfat") {
+        warn!("kernel #0", "failed to mount /dev/vdb at /mnt");
+    }
+}
+
+// a block device failed, file systems stop at the first failure and report it
+struct IoError;
+
+type IoResult<T> = Result<T, IoError>;
+
+// the result of a block device operation which returns false if it fails
+fn io(ok: bool) -> IoResult<()> {
+    if ok {
+        Ok(())
+    } else {
+        Err(IoError)
+    }
+}
 
 /// File trait
 pub trait File: Send + Sync {
@@ -15,8 +63,105 @@ pub trait File: Send + Sync {
     fn readable(&self) -> bool;
     /// If writable
     fn writable(&self) -> bool;
-    /// Read file to `UserBuffer`
-    fn read(&self, buf: UserBuffer) -> usize;
-    /// Write file from `UserBuffer`
-    fn write(&self, buf: UserBuffer) -> usize;
+    /// Read file to `UserBuffer`, return the number of bytes read, or None if the device fails
+    fn read(&self, buf: UserBuffer) -> Option<usize>;
+    /// Write file from `UserBuffer`, return the number of bytes written, or None if the device fails
+    fn write(&self, buf: UserBuffer) -> Option<usize>;
+    /// If the file supports random access by offset
+    fn seekable(&self) -> bool {
+        false
+    }
+    /// Move the file offset by `whence`, return the new offset, or None if it is illegal
+    fn lseek(&self, _offset: isize, _whence: usize) -> Option<usize> {
+        None
+    }
+    /// Read file from `offset` to kernel buffer, the file offset is not changed
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Option<usize> {
+        Some(0)
+    }
+    /// Write file at `offset` from kernel buffer, the file offset is not changed
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Option<usize> {
+        Some(0)
+    }
+    /// Device-specific control, `arg` is a pointer in user space, return -1 if not supported
+    fn ioctl(&self, _cmd: usize, _arg: usize) -> isize {
+        -1
+    }
+    /// Read at most `count` entries from the offset of a directory, None if it isn't a directory
+    fn getdents(&self, _count: usize) -> Option<Vec<Dirent>> {
+        None
+    }
+    /// Status of the file, None if it is not in a file system
+    fn stat(&self) -> Option<Stat> {
+        None
+    }
+    /// Absolute path the file is opened with, None if it has no path, e.g. a pipe
+    fn path(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Status of a file
+#[repr(C)]
+#[derive(Debug)]
+pub struct Stat {
+    /// ID of the device containing the file
+    pub dev: u64,
+    /// Inode number
+    pub ino: u64,
+    /// File type
+    pub mode: StatMode,
+    /// Number of hard links
+    pub nlink: u32,
+    /// Size in bytes
+    pub size: u64,
+}
+
+bitflags! {
+    /// Type of a file
+    pub struct StatMode: u32 {
+        /// Unknown
+        const NULL = 0;
+        /// Directory
+        const DIR = 0o040000;
+        /// Regular file
+        const FILE = 0o100000;
+    }
+}
+
+/// `whence` of lseek, the offset is set to `offset`
+pub const SEEK_SET: usize = 0;
+/// `whence` of lseek, the offset is set to current offset plus `offset`
+pub const SEEK_CUR: usize = 1;
+/// `whence` of lseek, the offset is set to the size of file plus `offset`
+pub const SEEK_END: usize = 2;
+
+/// Max length of a name in `Dirent`, including the ending zero
+pub const DIRENT_NAME_LEN: usize = 256;
+/// Type of a regular file in `Dirent`
+pub const DT_REG: u32 = 8;
+/// Type of a directory in `Dirent`
+pub const DT_DIR: u32 = 4;
+
+/// Directory entry read by getdents
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Dirent {
+    /// `DT_REG` or `DT_DIR`
+    pub d_type: u32,
+    /// Name ended with zero, it is truncated if too long
+    pub d_name: [u8; DIRENT_NAME_LEN],
+}
+
+impl Dirent {
+    /// Create an entry of `name`
+    pub fn new(name: &str, is_dir: bool) -> Self {
+        let mut d_name = [0u8; DIRENT_NAME_LEN];
+        let len = name.len().min(DIRENT_NAME_LEN - 1);
+        d_name[..len].copy_from_slice(&name.as_bytes()[..len]);
+        Self {
+            d_type: if is_dir { DT_DIR } else { DT_REG },
+            d_name,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,32 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+// components of the absolute path of `path` relative to the absolute directory `cwd`,
+// `.` and `..` are resolved, and `..` of the root is the root
+pub fn resolve(cwd: &str, path: &str) -> Vec<String> {
+    let base = if path.starts_with('/') { "" } else { cwd };
+    let mut components: Vec<String> = Vec::new();
+    for name in base.split('/').chain(path.split('/')) {
+        match name {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            _ => components.push(String::from(name)),
+        }
+    }
+    components
+}
+
+// the absolute path made of `components`
+pub fn join(components: &[String]) -> String {
+    if components.is_empty() {
+        return String::from("/");
+    }
+    let mut path = String::new();
+    for name in components {
+        path.push('/');
+        path.push_str(name);
+    }
+    path
+}
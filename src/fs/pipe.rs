@@ -1,29 +1,57 @@
 use super::File;
 use crate::mm::UserBuffer;
-use crate::sync::UPSafeCell;
+use crate::sync::{irq_restore, irq_save, SpinNoIrqLock, WaitQueue};
 use alloc::sync::{Arc, Weak};
 
-use crate::task::suspend_and_run_next;
-
 pub struct Pipe {
     readable: bool,
     writable: bool,
-    buffer: Arc<UPSafeCell<PipeRingBuffer>>,
+    buffer: Arc<SpinNoIrqLock<PipeRingBuffer>>,
+    // readers sleep here when the buffer is empty
+    read_wait: Arc<WaitQueue>,
+    // writers sleep here when the buffer is full
+    write_wait: Arc<WaitQueue>,
 }
 
 impl Pipe {
-    pub fn read_end_with_buffer(buffer: Arc<UPSafeCell<PipeRingBuffer>>) -> Self {
+    pub fn read_end_with_buffer(
+        buffer: Arc<SpinNoIrqLock<PipeRingBuffer>>,
+        read_wait: Arc<WaitQueue>,
+        write_wait: Arc<WaitQueue>,
+    ) -> Self {
         Self {
             readable: true,
             writable: false,
             buffer,
+            read_wait,
+            write_wait,
         }
     }
-    pub fn write_end_with_buffer(buffer: Arc<UPSafeCell<PipeRingBuffer>>) -> Self {
+    pub fn write_end_with_buffer(
+        buffer: Arc<SpinNoIrqLock<PipeRingBuffer>>,
+        read_wait: Arc<WaitQueue>,
+        write_wait: Arc<WaitQueue>,
+    ) -> Self {
         Self {
             readable: false,
             writable: true,
             buffer,
+            read_wait,
+            write_wait,
+        }
+    }
+}
+
+impl Drop for Pipe {
+    // wake up the other end, so that it finds this end is closed
+    fn drop(&mut self) {
+        // a reader or writer which found this end open is in the wait queue after the lock
+        drop(self.buffer.lock());
+        if self.writable {
+            self.read_wait.wake_all();
+        }
+        if self.readable {
+            self.write_wait.wake_all();
         }
     }
 }
@@ -42,6 +70,7 @@ pub struct PipeRingBuffer {
     head: usize,
     tail: usize,
     status: RingBufferStatus,
+    read_end: Option<Weak<Pipe>>,
     write_end: Option<Weak<Pipe>>,
 }
 
@@ -52,9 +81,13 @@ impl PipeRingBuffer {
             head: 0,
             tail: 0,
             status: RingBufferStatus::Empty,
+            read_end: None,
             write_end: None,
         }
     }
+    pub fn set_read_end(&mut self, read_end: &Arc<Pipe>) {
+        self.read_end = Some(Arc::downgrade(read_end));
+    }
     pub fn set_write_end(&mut self, write_end: &Arc<Pipe>) {
         self.write_end = Some(Arc::downgrade(write_end));
     }
@@ -94,14 +127,28 @@ impl PipeRingBuffer {
     pub fn all_write_ends_closed(&self) -> bool {
         self.write_end.as_ref().unwrap().upgrade().is_none()
     }
+    pub fn all_read_ends_closed(&self) -> bool {
+        self.read_end.as_ref().unwrap().upgrade().is_none()
+    }
 }
 
 /// Return (read_end, write_end)
 pub fn make_pipe() -> (Arc<Pipe>, Arc<Pipe>) {
-    let buffer = Arc::new(unsafe { UPSafeCell::new(PipeRingBuffer::new()) });
-    let read_end = Arc::new(Pipe::read_end_with_buffer(buffer.clone()));
-    let write_end = Arc::new(Pipe::write_end_with_buffer(buffer.clone()));
-    buffer.exclusive_access().set_write_end(&write_end);
+    let buffer = Arc::new(SpinNoIrqLock::new(PipeRingBuffer::new()));
+    let read_wait = Arc::new(WaitQueue::new());
+    let write_wait = Arc::new(WaitQueue::new());
+    let read_end = Arc::new(Pipe::read_end_with_buffer(
+        buffer.clone(),
+        read_wait.clone(),
+        write_wait.clone(),
+    ));
+    let write_end = Arc::new(Pipe::write_end_with_buffer(
+        buffer.clone(),
+        read_wait,
+        write_wait,
+    ));
+    buffer.lock().set_read_end(&read_end);
+    buffer.lock().set_write_end(&write_end);
     (read_end, write_end)
 }
 
@@ -112,20 +159,25 @@ impl File for Pipe {
     fn writable(&self) -> bool {
         self.writable
     }
-    fn read(&self, buf: UserBuffer) -> usize {
+    fn read(&self, buf: UserBuffer) -> Option<usize> {
         assert!(self.readable());
         let want_to_read = buf.len();
         let mut buf_iter = buf.into_iter();
         let mut already_read = 0usize;
         loop {
-            let mut ring_buffer = self.buffer.exclusive_access();
+            let sie = irq_save();
+            let mut ring_buffer = self.buffer.lock();
             let loop_read = ring_buffer.available_read();
             if loop_read == 0 {
                 if ring_buffer.all_write_ends_closed() {
-                    return already_read;
+                    drop(ring_buffer);
+                    irq_restore(sie);
+                    return Some(already_read);
                 }
-                drop(ring_buffer);
-                suspend_and_run_next();
+                // sleep until the writer puts data in or closes the pipe,
+                // we are in the queue before the writer can get the buffer
+                self.read_wait.wait_with(ring_buffer);
+                irq_restore(sie);
                 continue;
             }
             for _ in 0..loop_read {
@@ -134,26 +186,39 @@ impl File for Pipe {
                         *byte_ref = ring_buffer.read_byte();
                     }
                     already_read += 1;
-                    if already_read == want_to_read {
-                        return want_to_read;
-                    }
                 } else {
-                    return already_read;
+                    break;
                 }
             }
+            drop(ring_buffer);
+            irq_restore(sie);
+            // there is space for writers now
+            self.write_wait.wake_all();
+            if already_read == want_to_read {
+                return Some(want_to_read);
+            }
         }
     }
-    fn write(&self, buf: UserBuffer) -> usize {
+    fn write(&self, buf: UserBuffer) -> Option<usize> {
         assert!(self.writable());
         let want_to_write = buf.len();
         let mut buf_iter = buf.into_iter();
         let mut already_write = 0usize;
         loop {
-            let mut ring_buffer = self.buffer.exclusive_access();
+            let sie = irq_save();
+            let mut ring_buffer = self.buffer.lock();
             let loop_write = ring_buffer.available_write();
             if loop_write == 0 {
-                drop(ring_buffer);
-                suspend_and_run_next();
+                // nobody will read the data
+                if ring_buffer.all_read_ends_closed() {
+                    drop(ring_buffer);
+                    irq_restore(sie);
+                    return Some(already_write);
+                }
+                // sleep until the reader takes data out or closes the pipe,
+                // we are in the queue before the reader can get the buffer
+                self.write_wait.wait_with(ring_buffer);
+                irq_restore(sie);
                 continue;
             }
             // write at most loop_write bytes
@@ -161,13 +226,17 @@ impl File for Pipe {
                 if let Some(byte_ref) = buf_iter.next() {
                     ring_buffer.write_byte(unsafe { *byte_ref });
                     already_write += 1;
-                    if already_write == want_to_write {
-                        return want_to_write;
-                    }
                 } else {
-                    return already_write;
+                    break;
                 }
             }
+            drop(ring_buffer);
+            irq_restore(sie);
+            // there is data for readers now
+            self.read_wait.wake_all();
+            if already_write == want_to_write {
+                return Some(want_to_write);
+            }
         }
     }
 }
@@ -0,0 +1,237 @@
+//! Process file system, read-only files of processes and the kernel.
+//! contents of a file are generated when it is looked up, so an open file is a snapshot
+
+use alloc::{format, string::String, string::ToString, sync::Arc, vec::Vec};
+use core::fmt::Write;
+
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_stats, heap_stats, MapPermission};
+use crate::task::{all_pids, pid2process, ProcessControlBlock, TaskStatus};
+use crate::timer::get_time_ms;
+
+use super::vfs::{FileSystem, VfsInode};
+
+// inode numbers, those of a process are `(pid + 1) << PID_SHIFT` plus one of the kinds
+const ROOT_INO: usize = 1;
+const MEMINFO_INO: usize = 2;
+const UPTIME_INO: usize = 3;
+const PID_SHIFT: usize = 16;
+const PID_DIR: usize = 0;
+const STATUS: usize = 1;
+const MAPS: usize = 2;
+const FD_DIR: usize = 3;
+// fd `n` of a process is `FD_BASE + n`
+const FD_BASE: usize = 0x100;
+
+/// The process file system
+pub struct ProcFs;
+
+impl FileSystem for ProcFs {
+    fn fs_type(&self) -> &'static str {
+        "proc"
+    }
+    fn root(&self) -> Arc<dyn VfsInode> {
+        Arc::new(ProcDir::Root)
+    }
+}
+
+// directories of procfs
+enum ProcDir {
+    Root,
+    // directory of a process by pid
+    Process(usize),
+    // fds of a process by pid
+    Fd(usize),
+}
+
+// a file whose content is generated when it is looked up
+struct ProcFile {
+    ino: usize,
+    content: String,
+}
+
+fn file(ino: usize, content: String) -> Option<Arc<dyn VfsInode>> {
+    Some(Arc::new(ProcFile { ino, content }))
+}
+
+fn process_ino(pid: usize, kind: usize) -> usize {
+    ((pid + 1) << PID_SHIFT) + kind
+}
+
+impl VfsInode for ProcDir {
+    fn ino(&self) -> usize {
+        match self {
+            ProcDir::Root => ROOT_INO,
+            ProcDir::Process(pid) => process_ino(*pid, PID_DIR),
+            ProcDir::Fd(pid) => process_ino(*pid, FD_DIR),
+        }
+    }
+    fn is_dir(&self) -> bool {
+        true
+    }
+    fn size(&self) -> usize {
+        0
+    }
+    fn lookup(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        match self {
+            ProcDir::Root => match name {
+                "meminfo" => file(MEMINFO_INO, meminfo()),
+                "uptime" => file(UPTIME_INO, uptime()),
+                _ => {
+                    let pid: usize = name.parse().ok()?;
+                    pid2process(pid)?;
+                    Some(Arc::new(ProcDir::Process(pid)))
+                }
+            },
+            // the process is only held while a snapshot is taken, the parent may be
+            // reaping it on another hart
+            ProcDir::Process(pid) => match name {
+                "status" => file(process_ino(*pid, STATUS), status(pid2process(*pid)?)),
+                "maps" => file(process_ino(*pid, MAPS), maps(pid2process(*pid)?)),
+                "fd" => {
+                    pid2process(*pid)?;
+                    Some(Arc::new(ProcDir::Fd(*pid)))
+                }
+                _ => None,
+            },
+            ProcDir::Fd(pid) => {
+                let fd: usize = name.parse().ok()?;
+                let fd_file = pid2process(*pid)?.inner_exclusive_access().fd_table.get(fd)?.clone()?;
+                let file_path = fd_file.path();
+                drop(fd_file);
+                let content = file_path.unwrap_or_else(|| String::from("anon_inode"));
+                file(process_ino(*pid, FD_BASE + fd), content + "\n")
+            }
+        }
+    }
+    fn list(&self) -> Vec<(String, bool)> {
+        match self {
+            ProcDir::Root => {
+                let mut entries = Vec::from([(String::from("meminfo"), false), (String::from("uptime"), false)]);
+                entries.extend(all_pids().into_iter().map(|pid| (pid.to_string(), true)));
+                entries
+            }
+            ProcDir::Process(_) => Vec::from([
+                (String::from("status"), false),
+                (String::from("maps"), false),
+                (String::from("fd"), true),
+            ]),
+            ProcDir::Fd(pid) => match pid2process(*pid) {
+                Some(process) => process
+                    .inner_exclusive_access()
+                    .fd_table
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, file)| file.is_some())
+                    .map(|(fd, _)| (fd.to_string(), false))
+                    .collect(),
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+impl VfsInode for ProcFile {
+    fn ino(&self) -> usize {
+        self.ino
+    }
+    fn is_dir(&self) -> bool {
+        false
+    }
+    fn size(&self) -> usize {
+        self.content.len()
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let content = self.content.as_bytes();
+        if offset >= content.len() {
+            return Some(0);
+        }
+        let len = buf.len().min(content.len() - offset);
+        buf[..len].copy_from_slice(&content[offset..offset + len]);
+        Some(len)
+    }
+}
+
+// state, parent, exit code and signals of a process
+fn status(process: Arc<ProcessControlBlock>) -> String {
+    let pid = process.getpid();
+    let inner = process.inner_exclusive_access();
+    let threads = inner.tasks.iter().flatten().count();
+    let state = if inner.is_zombie {
+        "Z (zombie)"
+    } else if inner.frozen {
+        "T (stopped)"
+    } else if inner
+        .tasks
+        .iter()
+        .flatten()
+        .any(|task| task.inner_exclusive_access().task_status != TaskStatus::Blocked)
+    {
+        "R (running)"
+    } else {
+        "S (sleeping)"
+    };
+    let ppid = inner
+        .parent
+        .as_ref()
+        .and_then(|parent| parent.upgrade())
+        .map_or(0, |parent| parent.getpid());
+    let (pgid, exit_code, signals, signal_mask) =
+        (inner.pgid, inner.exit_code, inner.signals.bits(), inner.signal_mask.bits());
+    let cwd = String::from(inner.cwd.path());
+    drop(inner);
+    drop(process);
+    let mut status = String::new();
+    writeln!(status, "Pid:\t{}", pid).unwrap();
+    writeln!(status, "PPid:\t{}", ppid).unwrap();
+    writeln!(status, "Pgid:\t{}", pgid).unwrap();
+    writeln!(status, "State:\t{}", state).unwrap();
+    writeln!(status, "Threads:\t{}", threads).unwrap();
+    writeln!(status, "ExitCode:\t{}", exit_code).unwrap();
+    writeln!(status, "SigPnd:\t{:08x}", signals).unwrap();
+    writeln!(status, "SigBlk:\t{:08x}", signal_mask).unwrap();
+    writeln!(status, "Cwd:\t{}", cwd).unwrap();
+    status
+}
+
+// ranges and permissions of all areas in the address space of a process
+fn maps(process: Arc<ProcessControlBlock>) -> String {
+    let areas = process.inner_exclusive_access().memory_set.area_info();
+    drop(process);
+    let mut maps = String::new();
+    for (start, end, perm, shared) in areas {
+        let flag = |perm_bit: MapPermission, ch: char| if perm.contains(perm_bit) { ch } else { '-' };
+        writeln!(
+            maps,
+            "{:016x}-{:016x} {}{}{}{} {}",
+            start.0,
+            end.0,
+            flag(MapPermission::R, 'r'),
+            flag(MapPermission::W, 'w'),
+            flag(MapPermission::X, 'x'),
+            if shared { 's' } else { 'p' },
+            if perm.contains(MapPermission::U) { "user" } else { "kernel" },
+        )
+        .unwrap();
+    }
+    maps
+}
+
+// free frames and heap usage
+fn meminfo() -> String {
+    let (free_frames, total_frames) = frame_stats();
+    let (heap_used, heap_total) = heap_stats();
+    format!(
+        "MemTotal:\t{} kB\nMemFree:\t{} kB\nHeapTotal:\t{} kB\nHeapUsed:\t{} kB\n",
+        total_frames * PAGE_SIZE / 1024,
+        free_frames * PAGE_SIZE / 1024,
+        heap_total / 1024,
+        heap_used / 1024,
+    )
+}
+
+// seconds since boot
+fn uptime() -> String {
+    let ms = get_time_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
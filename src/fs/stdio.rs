@@ -1,8 +1,8 @@
-#[allow(deprecated)]
-use sbi_rt::legacy::console_getchar;
+use alloc::string::String;
 
-use crate::task::{exit_current_and_run_next, suspend_and_run_next};
+use crate::task::exit_current_and_run_next;
 
+use super::tty::TTY;
 use super::File;
 
 /// Standard input
@@ -20,35 +20,19 @@ impl File for Stdin {
     fn writable(&self) -> bool {
         false
     }
-    #[allow(deprecated)]
-    fn read(&self, mut user_buf: crate::mm::UserBuffer) -> usize {
-        if user_buf.len() == 0 {
-            return 0;
-        }
-        if user_buf.len() != 1 {
-            warn!("kernel #0", "Stdin: only support read size = 1, kill this call");
-            exit_current_and_run_next(-9);
-        }
-        let mut c: usize;
-        loop {
-            c = console_getchar();
-            if c == 0 {
-                suspend_and_run_next();
-                continue;
-            } else {
-                break;
-            }
-        }
-        let ch = c as u8;
-        unsafe {
-            user_buf.buffers[0].as_mut_ptr().write_volatile(ch);
-        }
-        1
+    fn read(&self, user_buf: crate::mm::UserBuffer) -> Option<usize> {
+        Some(TTY.read(user_buf))
     }
-    fn write(&self, _buf: crate::mm::UserBuffer) -> usize {
+    fn write(&self, _buf: crate::mm::UserBuffer) -> Option<usize> {
         warn!("kernel #0", "Stdin: not writable, kill this call");
         exit_current_and_run_next(-9);
-        0
+        Some(0)
+    }
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        TTY.ioctl(cmd, arg)
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/tty"))
     }
 }
 
@@ -59,15 +43,21 @@ impl File for Stdout {
     fn writable(&self) -> bool {
         true
     }
-    fn read(&self, _buf: crate::mm::UserBuffer) -> usize {
+    fn read(&self, _buf: crate::mm::UserBuffer) -> Option<usize> {
         warn!("kernel #0", "Stdout: not readable, kill this call");
         exit_current_and_run_next(-10);
-        0
+        Some(0)
     }
-    fn write(&self, user_buf: crate::mm::UserBuffer) -> usize {
+    fn write(&self, user_buf: crate::mm::UserBuffer) -> Option<usize> {
         for buffer in user_buf.buffers.iter() {
             print!("{}", core::str::from_utf8(*buffer).unwrap());
         }
-        user_buf.len()
+        Some(user_buf.len())
+    }
+    fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        TTY.ioctl(cmd, arg)
+    }
+    fn path(&self) -> Option<String> {
+        Some(String::from("/dev/tty"))
     }
 }
\ No newline at end of file
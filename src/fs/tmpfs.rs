@@ -0,0 +1,264 @@
+//! In-memory file system, data of files is in frames and lost at shutdown
+
+use alloc::{collections::BTreeMap, string::String, sync::{Arc, Weak}, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+
+use crate::config::PAGE_SIZE;
+use crate::mm::{frame_alloc, FrameTracker};
+use crate::sync::SpinNoIrqLock;
+
+use super::vfs::{FileSystem, VfsInode};
+
+static NEXT_INO: AtomicUsize = AtomicUsize::new(0);
+// frames taken by files of all tmpfs instances
+static PAGES: AtomicUsize = AtomicUsize::new(0);
+
+// files of all tmpfs instances together take at most a quarter of the physical memory,
+// writes beyond it are cut short
+const MAX_PAGES: usize = 0x40_0000 / PAGE_SIZE;
+const MAX_FILE_SIZE: usize = MAX_PAGES * PAGE_SIZE;
+
+lazy_static! {
+    // inodes of all tmpfs instances by inode number, to find the target of a hard link
+    static ref INODES: SpinNoIrqLock<BTreeMap<usize, Weak<TmpInode>>> =
+        SpinNoIrqLock::new(BTreeMap::new());
+}
+
+/// An in-memory file system
+pub struct TmpFs {
+    root: Arc<TmpInode>,
+}
+
+impl TmpFs {
+    /// Create an empty file system
+    pub fn new() -> Self {
+        Self {
+            root: TmpInode::new(TmpInodeKind::Dir(BTreeMap::new())),
+        }
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn fs_type(&self) -> &'static str {
+        "tmpfs"
+    }
+    fn root(&self) -> Arc<dyn VfsInode> {
+        self.root.clone()
+    }
+}
+
+enum TmpInodeKind {
+    // frames of data by page and size in bytes, frames are allocated when they are written
+    File(BTreeMap<usize, FrameTracker>, usize),
+    // entries by name
+    Dir(BTreeMap<String, Arc<TmpInode>>),
+}
+
+struct TmpInodeInner {
+    kind: TmpInodeKind,
+    // number of directory entries of it
+    nlink: u32,
+}
+
+/// A file or directory of tmpfs, it is freed when it is unlinked and closed
+pub struct TmpInode {
+    ino: usize,
+    inner: SpinNoIrqLock<TmpInodeInner>,
+}
+
+impl TmpInode {
+    fn new(kind: TmpInodeKind) -> Arc<Self> {
+        let inode = Arc::new(Self {
+            ino: NEXT_INO.fetch_add(1, Ordering::Relaxed),
+            inner: SpinNoIrqLock::new(TmpInodeInner { kind, nlink: 1 }),
+        });
+        INODES.lock().insert(inode.ino, Arc::downgrade(&inode));
+        inode
+    }
+    // add a new file or directory `name` to the directory
+    fn add(&self, name: &str, kind: TmpInodeKind) -> Option<Arc<dyn VfsInode>> {
+        let mut inner = self.inner.lock();
+        let entries = match &mut inner.kind {
+            TmpInodeKind::Dir(entries) => entries,
+            TmpInodeKind::File(..) => return None,
+        };
+        if entries.contains_key(name) {
+            return None;
+        }
+        let inode = TmpInode::new(kind);
+        entries.insert(String::from(name), inode.clone());
+        Some(inode)
+    }
+    // remove entry `name` of the directory if `remove` returns true for it
+    fn remove(&self, name: &str, remove: impl FnOnce(&TmpInodeInner) -> bool) -> bool {
+        let mut inner = self.inner.lock();
+        let entries = match &mut inner.kind {
+            TmpInodeKind::Dir(entries) => entries,
+            TmpInodeKind::File(..) => return false,
+        };
+        let inode = match entries.get(name) {
+            Some(inode) => inode.clone(),
+            None => return false,
+        };
+        let mut inode_inner = inode.inner.lock();
+        if !remove(&inode_inner) {
+            return false;
+        }
+        inode_inner.nlink -= 1;
+        drop(inode_inner);
+        entries.remove(name);
+        drop(inner);
+        // frames are freed here if it is not open
+        drop(inode);
+        true
+    }
+}
+
+impl Drop for TmpInode {
+    fn drop(&mut self) {
+        INODES.lock().remove(&self.ino);
+        if let TmpInodeKind::File(frames, _) = &self.inner.lock().kind {
+            PAGES.fetch_sub(frames.len(), Ordering::Relaxed);
+        }
+    }
+}
+
+impl VfsInode for TmpInode {
+    fn ino(&self) -> usize {
+        self.ino
+    }
+    fn is_dir(&self) -> bool {
+        matches!(self.inner.lock().kind, TmpInodeKind::Dir(_))
+    }
+    fn size(&self) -> usize {
+        match self.inner.lock().kind {
+            TmpInodeKind::File(_, size) => size,
+            TmpInodeKind::Dir(_) => 0,
+        }
+    }
+    fn nlink(&self) -> u32 {
+        self.inner.lock().nlink
+    }
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Option<usize> {
+        let inner = self.inner.lock();
+        let (frames, size) = match &inner.kind {
+            TmpInodeKind::File(frames, size) => (frames, *size),
+            TmpInodeKind::Dir(_) => return Some(0),
+        };
+        let end = size.min(offset.saturating_add(buf.len()));
+        let mut pos = offset;
+        while pos < end {
+            let (page, page_offset) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+            let len = (PAGE_SIZE - page_offset).min(end - pos);
+            let dst = &mut buf[pos - offset..pos - offset + len];
+            match frames.get(&page) {
+                Some(frame) => dst.copy_from_slice(&frame.ppn.get_bytes_array()[page_offset..page_offset + len]),
+                // holes which are never written are zeros
+                None => dst.fill(0),
+            }
+            pos += len;
+        }
+        Some(end.saturating_sub(offset))
+    }
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Option<usize> {
+        Some(write_file(&mut self.inner.lock().kind, offset, buf))
+    }
+    fn append(&self, buf: &[u8]) -> Option<(usize, usize)> {
+        let mut inner = self.inner.lock();
+        let offset = match inner.kind {
+            TmpInodeKind::File(_, size) => size,
+            TmpInodeKind::Dir(_) => return Some((0, 0)),
+        };
+        Some((offset, write_file(&mut inner.kind, offset, buf)))
+    }
+    fn clear(&self) -> bool {
+        if let TmpInodeKind::File(frames, size) = &mut self.inner.lock().kind {
+            PAGES.fetch_sub(frames.len(), Ordering::Relaxed);
+            frames.clear();
+            *size = 0;
+        }
+        true
+    }
+    fn lookup(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        match &self.inner.lock().kind {
+            TmpInodeKind::Dir(entries) => entries.get(name).map(|inode| inode.clone() as Arc<dyn VfsInode>),
+            TmpInodeKind::File(..) => None,
+        }
+    }
+    fn list(&self) -> Vec<(String, bool)> {
+        match &self.inner.lock().kind {
+            TmpInodeKind::Dir(entries) => entries
+                .iter()
+                .map(|(name, inode)| (name.clone(), inode.is_dir()))
+                .collect(),
+            TmpInodeKind::File(..) => Vec::new(),
+        }
+    }
+    fn create(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.add(name, TmpInodeKind::File(BTreeMap::new(), 0))
+    }
+    fn mkdir(&self, name: &str) -> Option<Arc<dyn VfsInode>> {
+        self.add(name, TmpInodeKind::Dir(BTreeMap::new()))
+    }
+    fn link(&self, name: &str, target: &Arc<dyn VfsInode>) -> bool {
+        let target = match INODES.lock().get(&target.ino()).and_then(Weak::upgrade) {
+            Some(target) => target,
+            None => return false,
+        };
+        let mut inner = self.inner.lock();
+        let entries = match &mut inner.kind {
+            TmpInodeKind::Dir(entries) => entries,
+            TmpInodeKind::File(..) => return false,
+        };
+        if entries.contains_key(name) {
+            return false;
+        }
+        target.inner.lock().nlink += 1;
+        entries.insert(String::from(name), target);
+        true
+    }
+    fn unlink(&self, name: &str) -> bool {
+        self.remove(name, |inner| matches!(inner.kind, TmpInodeKind::File(..)))
+    }
+    fn rmdir(&self, name: &str) -> bool {
+        self.remove(name, |inner| match &inner.kind {
+            TmpInodeKind::Dir(entries) => entries.is_empty(),
+            TmpInodeKind::File(..) => false,
+        })
+    }
+}
+
+// write file `kind` at `offset` from `buf`, return the number of bytes written
+fn write_file(kind: &mut TmpInodeKind, offset: usize, buf: &[u8]) -> usize {
+    let (frames, size) = match kind {
+        TmpInodeKind::File(frames, size) => (frames, size),
+        TmpInodeKind::Dir(_) => return 0,
+    };
+    let end = offset.saturating_add(buf.len()).min(MAX_FILE_SIZE);
+    let mut pos = offset;
+    while pos < end {
+        let (page, page_offset) = (pos / PAGE_SIZE, pos % PAGE_SIZE);
+        if !frames.contains_key(&page) {
+            // out of the budget or of memory, write as much as possible
+            if PAGES.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |pages| (pages < MAX_PAGES).then_some(pages + 1)).is_err() {
+                break;
+            }
+            match frame_alloc() {
+                Some(frame) => frames.insert(page, frame),
+                None => {
+                    PAGES.fetch_sub(1, Ordering::Relaxed);
+                    break;
+                }
+            };
+        }
+        let len = (PAGE_SIZE - page_offset).min(end - pos);
+        frames[&page].ppn.get_bytes_array()[page_offset..page_offset + len]
+            .copy_from_slice(&buf[pos - offset..pos - offset + len]);
+        pos += len;
+    }
+    if pos > offset {
+        *size = (*size).max(pos);
+    }
+    pos - offset
+}
@@ -0,0 +1,266 @@
+// Terminal on the console UART, with a line discipline between the device and readers
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use lazy_static::*;
+
+use crate::drivers::chardev::{CharDevice, UART};
+use crate::mm::{translated_refmut, UserBuffer};
+use crate::sync::{irq_restore, irq_save, SpinNoIrqLock, WaitQueue};
+use crate::task::{current_prepare_user_buffer, current_signal_pending, current_user_token, send_signal_to_group, SignalFlags, IDLE_PID};
+
+// ioctl commands of terminals
+const TCGETS: usize = 0x5401;
+const TCSETS: usize = 0x5402;
+const TIOCGPGRP: usize = 0x540F;
+const TIOCSPGRP: usize = 0x5410;
+
+bitflags! {
+    // local modes of a terminal
+    pub struct LocalFlags: u32 {
+        // Ctrl-C and Ctrl-Z send signals
+        const ISIG = 0o1;
+        // canonical mode, input is edited and read line by line
+        const ICANON = 0o2;
+        // echo input characters
+        const ECHO = 0o10;
+    }
+}
+
+// terminal settings read and written by ioctl, raw mode clears all local modes
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct Termios {
+    pub lflag: u32,
+}
+
+const CTRL_C: u8 = 0x03;
+const CTRL_U: u8 = 0x15;
+const CTRL_Z: u8 = 0x1a;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+// characters of a line which is being edited, more are dropped
+const MAX_LINE: usize = 1024;
+
+struct TtyInner {
+    lflag: LocalFlags,
+    // line being edited in canonical mode
+    line: Vec<u8>,
+    // input ready for readers, complete lines in canonical mode
+    read_buffer: VecDeque<u8>,
+    // process group receiving signals from the terminal, none until one is set
+    foreground_pgid: Option<usize>,
+}
+
+pub struct Tty {
+    inner: SpinNoIrqLock<TtyInner>,
+    // tasks waiting for input
+    read_wait: WaitQueue,
+}
+
+lazy_static! {
+    /// the console terminal, it is stdin, stdout and stderr of processes
+    pub static ref TTY: Tty = Tty::new();
+}
+
+impl TtyInner {
+    fn echo(&self, ch: u8) {
+        if self.lflag.contains(LocalFlags::ECHO) {
+            UART.write(ch);
+        }
+    }
+    // erase the last echoed character on the screen
+    fn echo_erase(&self) {
+        for ch in [BACKSPACE, b' ', BACKSPACE] {
+            self.echo(ch);
+        }
+    }
+    // process an input character, return the signal it sends and
+    // if there is new input for readers
+    fn input(&mut self, ch: u8) -> (Option<SignalFlags>, bool) {
+        if self.lflag.contains(LocalFlags::ISIG) {
+            let signal = match ch {
+                CTRL_C => Some(SignalFlags::SIGINT),
+                CTRL_Z => Some(SignalFlags::SIGTSTP),
+                _ => None,
+            };
+            if let Some(signal) = signal {
+                // the line being edited is discarded
+                self.line.clear();
+                self.echo(b'^');
+                self.echo(ch + b'@');
+                self.echo(b'\n');
+                return (Some(signal), false);
+            }
+        }
+        if !self.lflag.contains(LocalFlags::ICANON) {
+            self.echo(ch);
+            self.read_buffer.push_back(ch);
+            return (None, true);
+        }
+        match ch {
+            b'\r' | b'\n' => {
+                self.echo(b'\n');
+                self.line.push(b'\n');
+                self.read_buffer.extend(self.line.drain(..));
+                return (None, true);
+            }
+            BACKSPACE | DELETE => {
+                if self.line.pop().is_some() {
+                    self.echo_erase();
+                }
+            }
+            CTRL_U => {
+                for _ in 0..self.line.len() {
+                    self.echo_erase();
+                }
+                self.line.clear();
+            }
+            _ => {
+                if self.line.len() < MAX_LINE {
+                    self.echo(ch);
+                    self.line.push(ch);
+                }
+            }
+        }
+        (None, false)
+    }
+}
+
+impl Tty {
+    fn new() -> Self {
+        Self {
+            inner: SpinNoIrqLock::new(TtyInner {
+                lflag: LocalFlags::ISIG | LocalFlags::ICANON | LocalFlags::ECHO,
+                line: Vec::new(),
+                read_buffer: VecDeque::new(),
+                foreground_pgid: None,
+            }),
+            read_wait: WaitQueue::new(),
+        }
+    }
+
+    // handle the interrupt of the UART, and process the received characters
+    pub fn handle_irq(&self) {
+        UART.handle_irq();
+        let mut inner = self.inner.lock();
+        let mut ready = false;
+        while let Some(ch) = UART.try_read() {
+            let (signal, new_input) = inner.input(ch);
+            if let Some(signal) = signal {
+                if let Some(pgid) = inner.foreground_pgid {
+                    send_signal_to_group(pgid, signal);
+                }
+                // readers in the group return to handle the signal
+                ready = true;
+            }
+            ready |= new_input;
+        }
+        // woken up with the terminal locked, so readers checking input and signals
+        // under the lock never miss it
+        if ready {
+            self.read_wait.wake_all();
+        }
+    }
+
+    // read input to `user_buf`, sleep until there is any.
+    // at most one line is read in canonical mode, return 0 if a signal arrives first
+    pub fn read(&self, user_buf: UserBuffer) -> usize {
+        let len = user_buf.len();
+        if len == 0 {
+            return 0;
+        }
+        let mut user_buf = user_buf.into_iter();
+        loop {
+            let sie = irq_save();
+            let mut inner = self.inner.lock();
+            if !inner.read_buffer.is_empty() {
+                let canonical = inner.lflag.contains(LocalFlags::ICANON);
+                let mut count = 0;
+                while count < len {
+                    let ch = match inner.read_buffer.pop_front() {
+                        Some(ch) => ch,
+                        None => break,
+                    };
+                    unsafe {
+                        user_buf.next().unwrap().write_volatile(ch);
+                    }
+                    count += 1;
+                    if canonical && ch == b'\n' {
+                        break;
+                    }
+                }
+                drop(inner);
+                irq_restore(sie);
+                return count;
+            }
+            if current_signal_pending() {
+                drop(inner);
+                irq_restore(sie);
+                return 0;
+            }
+            self.read_wait.wait_with(inner);
+            irq_restore(sie);
+        }
+    }
+
+    // terminal control, `arg` is a pointer to the argument in user space.
+    // return -1 if `cmd` is not supported or `arg` is invalid
+    pub fn ioctl(&self, cmd: usize, arg: usize) -> isize {
+        match cmd {
+            TCGETS => {
+                let lflag = self.inner.lock().lflag;
+                match user_ref::<Termios>(arg, true) {
+                    Some(termios) => termios.lflag = lflag.bits(),
+                    None => return -1,
+                }
+            }
+            TCSETS => {
+                let lflag = match user_ref::<Termios>(arg, false) {
+                    Some(termios) => LocalFlags::from_bits_truncate(termios.lflag),
+                    None => return -1,
+                };
+                let mut inner = self.inner.lock();
+                inner.lflag = lflag;
+                // the line being edited is readable in raw mode
+                if !lflag.contains(LocalFlags::ICANON) && !inner.line.is_empty() {
+                    let line: Vec<u8> = inner.line.drain(..).collect();
+                    inner.read_buffer.extend(line);
+                    drop(inner);
+                    self.read_wait.wake_all();
+                }
+            }
+            TIOCGPGRP => {
+                let pgid = match self.inner.lock().foreground_pgid {
+                    Some(pgid) => pgid,
+                    None => return -1,
+                };
+                match user_ref::<usize>(arg, true) {
+                    Some(user_pgid) => *user_pgid = pgid,
+                    None => return -1,
+                }
+            }
+            TIOCSPGRP => {
+                let pgid = match user_ref::<usize>(arg, false) {
+                    Some(pgid) => *pgid,
+                    None => return -1,
+                };
+                // the group of initproc has every process which doesn't set its own
+                if pgid == IDLE_PID {
+                    return -1;
+                }
+                self.inner.lock().foreground_pgid = Some(pgid);
+            }
+            _ => return -1,
+        }
+        0
+    }
+}
+
+// get a reference of a `T` at `addr` in current user space, return None if it is invalid
+fn user_ref<T>(addr: usize, is_write: bool) -> Option<&'static mut T> {
+    if !current_prepare_user_buffer(addr, core::mem::size_of::<T>(), is_write) {
+        return None;
+    }
+    Some(translated_refmut(current_user_token(), addr as *mut T))
+}
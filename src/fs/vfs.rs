@@ -0,0 +1,261 @@
+//! Virtual file system, file systems are mounted at paths and found by them
+
+use alloc::{string::String, sync::Arc, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+
+use crate::drivers::block::{BlockDevice, BLOCK_DEVICE, BLOCK_DRIVER1};
+use crate::sync::SpinNoIrqLock;
+
+use super::devfs::DevFs;
+use super::easyfs::EasyFs;
+use super::fat32::FatFs;
+use super::tmpfs::TmpFs;
+use super::path::resolve;
+use super::procfs::ProcFs;
+use super::File;
+
+/// A file system which can be mounted
+pub trait FileSystem: Send + Sync {
+    /// Name of the type of the file system
+    fn fs_type(&self) -> &'static str;
+    /// Root directory of the file system
+    fn root(&self) -> Arc<dyn VfsInode>;
+}
+
+/// A file or directory in a file system. operations which are not supported
+/// read or write nothing and return None or false
+pub trait VfsInode: Send + Sync {
+    /// Inode number, unique in its file system
+    fn ino(&self) -> usize;
+    /// If it is a directory
+    fn is_dir(&self) -> bool;
+    /// Size in bytes
+    fn size(&self) -> usize;
+    /// Number of hard links
+    fn nlink(&self) -> u32 {
+        1
+    }
+    /// Read the file from `offset` to `buf`, return the number of bytes read,
+    /// or None if the device fails
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Option<usize> {
+        Some(0)
+    }
+    /// Write the file at `offset` from `buf`, return the number of bytes written,
+    /// or None if the device fails
+    fn write_at(&self, _offset: usize, _buf: &[u8]) -> Option<usize> {
+        Some(0)
+    }
+    /// Write `buf` at the end of the file, return the offset it is written at and
+    /// the number of bytes written. writable file systems find the end and write
+    /// there under one lock, so that other writes can't move the end meanwhile
+    fn append(&self, buf: &[u8]) -> Option<(usize, usize)> {
+        let offset = self.size();
+        Some((offset, self.write_at(offset, buf)?))
+    }
+    /// Remove all data of the file, return false if the device fails
+    fn clear(&self) -> bool {
+        true
+    }
+    /// Entry `name` of the directory
+    fn lookup(&self, _name: &str) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    /// Names of all entries of the directory, and if they are directories
+    fn list(&self) -> Vec<(String, bool)> {
+        Vec::new()
+    }
+    /// Create a file `name` in the directory, None if it exists
+    fn create(&self, _name: &str) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    /// Create a directory `name` in the directory, None if it exists
+    fn mkdir(&self, _name: &str) -> Option<Arc<dyn VfsInode>> {
+        None
+    }
+    /// Create a hard link `name` in the directory to the file `target` of the same
+    /// file system, return false if `name` exists
+    fn link(&self, _name: &str, _target: &Arc<dyn VfsInode>) -> bool {
+        false
+    }
+    /// Remove the link `name` to a file from the directory, return false if it is not a file
+    fn unlink(&self, _name: &str) -> bool {
+        false
+    }
+    /// Remove the empty directory `name` from the directory, return false if it is not one
+    fn rmdir(&self, _name: &str) -> bool {
+        false
+    }
+    /// If it is a device node
+    fn is_device(&self) -> bool {
+        false
+    }
+    /// Open the device file of a device node for reading and/or writing,
+    /// None if it is not a device or the device can't be opened so
+    fn open_device(&self, _readable: bool, _writable: bool) -> Option<Arc<dyn File + Send + Sync>> {
+        None
+    }
+}
+
+/// A file system mounted at a path
+pub struct Mount {
+    /// Device id of files in the file system
+    pub id: usize,
+    /// Components of the absolute path it is mounted at
+    pub path: Vec<String>,
+    /// The mounted file system
+    pub fs: Arc<dyn FileSystem>,
+}
+
+static NEXT_MOUNT_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Mount {
+    fn new(path: Vec<String>, fs: Arc<dyn FileSystem>) -> Arc<Self> {
+        Arc::new(Self {
+            id: NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed),
+            path,
+            fs,
+        })
+    }
+}
+
+lazy_static! {
+    // mounted file systems, easy-fs on the block device is the root
+    static ref MOUNTS: SpinNoIrqLock<Vec<Arc<Mount>>> =
+        SpinNoIrqLock::new(vec![Mount::new(Vec::new(), Arc::new(EasyFs))]);
+}
+
+// a new file system of type `fs_type` on device `source`
+fn new_fs(fs_type: &str, source: &str) -> Option<Arc<dyn FileSystem>> {
+    match fs_type {
+        // easy-fs on the block device is the root, mounting it twice would let two
+        // mounts share its inodes
+        "easyfs" => None,
+        "tmpfs" => Some(Arc::new(TmpFs::new())),
+        "devfs" => Some(Arc::new(DevFs::new())),
+        "proc" => Some(Arc::new(ProcFs)),
+        "vfat" => Some(Arc::new(FatFs::open(block_device(source)?)?)),
+        _ => None,
+    }
+}
+
+// the block device of a device file path
+fn block_device(source: &str) -> Option<Arc<dyn BlockDevice>> {
+    match source {
+        "/dev/vda" => Some(BLOCK_DEVICE.clone()),
+        "/dev/vdb" => BLOCK_DRIVER1.clone().map(|driver| driver as Arc<dyn BlockDevice>),
+        _ => None,
+    }
+}
+
+// the mount which absolute path `components` is in, the one mounted at the longest prefix
+fn find_mount(components: &[String]) -> Arc<Mount> {
+    MOUNTS
+        .lock()
+        .iter()
+        .filter(|mount| components.starts_with(&mount.path))
+        .max_by_key(|mount| mount.path.len())
+        .unwrap()
+        .clone()
+}
+
+fn is_mount_point(components: &[String]) -> bool {
+    MOUNTS.lock().iter().any(|mount| mount.path == components)
+}
+
+/// The inode at absolute path `components` and the mount it is in
+pub fn lookup(components: &[String]) -> Option<(Arc<Mount>, Arc<dyn VfsInode>)> {
+    let mount = find_mount(components);
+    let mut inode = mount.fs.root();
+    for name in &components[mount.path.len()..] {
+        if !inode.is_dir() {
+            return None;
+        }
+        inode = inode.lookup(name)?;
+    }
+    Some((mount, inode))
+}
+
+/// The parent directory of absolute path `components`, the mount it is in and the last
+/// component. None if the path is a mount point, which can't be changed in its parent
+pub fn lookup_parent(components: &[String]) -> Option<(Arc<Mount>, Arc<dyn VfsInode>, &str)> {
+    let (name, parent) = components.split_last()?;
+    if is_mount_point(components) {
+        return None;
+    }
+    let (mount, dir) = lookup(parent)?;
+    if !dir.is_dir() {
+        return None;
+    }
+    Some((mount, dir, name.as_str()))
+}
+
+/// Names of mount points in the directory at absolute path `components`
+pub fn mount_points_in(components: &[String]) -> Vec<String> {
+    MOUNTS
+        .lock()
+        .iter()
+        .filter(|mount| mount.path.len() == components.len() + 1 && mount.path.starts_with(components))
+        .map(|mount| mount.path.last().unwrap().clone())
+        .collect()
+}
+
+/// Mount a file system of type `fs_type` on device `source` at `target`, relative paths are
+/// relative to the root. `target` should be a directory or not exist in a directory,
+/// return false if it is already a mount point or the type is unknown
+pub fn mount(source: &str, target: &str, fs_type: &str) -> bool {
+    let components = resolve("/", target);
+    if is_mount_point(&components) {
+        return false;
+    }
+    match lookup(&components) {
+        Some((_, inode)) if !inode.is_dir() => return false,
+        Some(_) => {}
+        None => {
+            if lookup_parent(&components).is_none() {
+                return false;
+            }
+        }
+    }
+    let fs = match new_fs(fs_type, source) {
+        Some(fs) => fs,
+        None => return false,
+    };
+    debug!("kernel #0", "mount: {} of {} at {}", source, fs.fs_type(), target);
+    let mut mounts = MOUNTS.lock();
+    // mounted by another task in between
+    if mounts.iter().any(|mount| mount.path == components) {
+        return false;
+    }
+    mounts.push(Mount::new(components, fs));
+    true
+}
+
+/// Unmount the file system mounted at `target`, relative paths are relative to the root.
+/// return false if it is not a mount point, it is the root, or it is busy with open files,
+/// working directories or other mount points in it
+pub fn umount(target: &str) -> bool {
+    let components = resolve("/", target);
+    if components.is_empty() {
+        return false;
+    }
+    let mut mounts = MOUNTS.lock();
+    let id = match mounts.iter().position(|mount| mount.path == components) {
+        Some(id) => id,
+        None => return false,
+    };
+    // open files and working directories hold the mount
+    if Arc::strong_count(&mounts[id]) > 1 {
+        return false;
+    }
+    if mounts
+        .iter()
+        .any(|mount| mount.path.len() > components.len() && mount.path.starts_with(&components))
+    {
+        return false;
+    }
+    let mount = mounts.remove(id);
+    drop(mounts);
+    debug!("kernel #0", "umount: {} at {}", mount.fs.fs_type(), target);
+    true
+}
@@ -12,6 +12,8 @@ extern crate bitflags;
 
 use core::arch::global_asm;
 
+use config::MAX_HARTS;
+
 #[path = "boards/qemu.rs"]
 mod board;
 
@@ -34,9 +36,9 @@ pub mod trap;
 global_asm!(include_str!("entry.asm"));
 global_asm!(include_str!("link_app.S"));
 
-// the entry of kernel
+// the entry of kernel, run by the boot hart
 #[no_mangle]
-pub fn rust_main() -> ! {
+pub fn rust_main(hart_id: usize) -> ! {
     clear_bss();
     println!("[kernel] Hello, world!");
     trace!("kernel #0", "Hello, world!");
@@ -54,13 +56,43 @@ pub fn rust_main() -> ! {
     trap::init();
     trap::enable_timer_interrupt();
     timer::set_next_trigger();
+    board::device_init();
+    trap::enable_external_interrupt();
+    fs::init();
     fs::list_apps();
     task::add_initproc();
     info!("kernel #0", "initproc added");
+    start_other_harts(hart_id);
     task::run_tasks();
     panic!("Unreachable in rust_main!");
 }
 
+// the entry of other harts, the kernel is initialized by the boot hart already
+#[no_mangle]
+pub fn rust_main_secondary(hart_id: usize) -> ! {
+    mm::init_secondary();
+    trap::init();
+    trap::enable_timer_interrupt();
+    timer::set_next_trigger();
+    trap::enable_external_interrupt();
+    info!("kernel", "hart {} started", hart_id);
+    task::run_tasks();
+    panic!("Unreachable in rust_main_secondary!");
+}
+
+// start other harts through SBI HSM extension
+fn start_other_harts(boot_hart_id: usize) {
+    extern "C" {
+        fn _start_secondary();
+    }
+    for hart_id in (0..MAX_HARTS).filter(|id| *id != boot_hart_id) {
+        let ret = sbi::hart_start(hart_id, _start_secondary as usize);
+        if ret != 0 {
+            warn!("kernel #0", "failed to start hart {}, error = {}", hart_id, ret as isize);
+        }
+    }
+}
+
 fn clear_bss() {
     extern "C" {
         fn sbss();
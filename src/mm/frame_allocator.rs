@@ -1,6 +1,6 @@
 use super::{PhysAddr, PhysPageNum};
 use crate::config::MEMORY_END;
-use crate::sync::UPSafeCell;
+use crate::sync::SpinNoIrqLock;
 use alloc::vec::Vec;
 use core::fmt::{self, Debug, Formatter};
 
@@ -46,12 +46,19 @@ pub struct StackFrameAllocator {
     end: usize,
     // list of recycled physical page num
     recycled: Vec<usize>,
+    // number of all frames
+    total: usize,
 }
 
 impl StackFrameAllocator {
     pub fn init(&mut self, l: PhysPageNum, r: PhysPageNum) {
         self.current = l.0;
         self.end = r.0;
+        self.total = r.0 - l.0;
+    }
+    // number of frames which can be allocated
+    pub fn free(&self) -> usize {
+        self.end - self.current + self.recycled.len()
     }
 }
 
@@ -61,6 +68,7 @@ impl FrameAllocator for StackFrameAllocator {
             current: 0,
             end: 0,
             recycled: Vec::new(),
+            total: 0,
         }
     }
     fn alloc(&mut self) -> Option<PhysPageNum> {
@@ -91,8 +99,8 @@ type FrameAllocatorImpl = StackFrameAllocator;
 
 lazy_static!{
     // a global frame allocator instance
-    pub static ref FRAME_ALLOCATOR: UPSafeCell<FrameAllocatorImpl> = 
-        unsafe { UPSafeCell::new(FrameAllocatorImpl::new()) };
+    pub static ref FRAME_ALLOCATOR: SpinNoIrqLock<FrameAllocatorImpl> = 
+        SpinNoIrqLock::new(FrameAllocatorImpl::new());
 }
 
 // initialize the frame allocator using `ekernel` and `MEMORY_END`
@@ -100,7 +108,7 @@ pub fn init_frame_allocator() {
     extern "C" {
         fn ekernel();
     }
-    FRAME_ALLOCATOR.exclusive_access().init(
+    FRAME_ALLOCATOR.lock().init(
         PhysAddr::from(ekernel as usize).ceil(),
         PhysAddr::from(MEMORY_END).floor(),
     );
@@ -108,12 +116,18 @@ pub fn init_frame_allocator() {
 
 // allocate a frame
 pub fn frame_alloc() -> Option<FrameTracker> {
-    FRAME_ALLOCATOR.exclusive_access().alloc().map(FrameTracker::new)
+    FRAME_ALLOCATOR.lock().alloc().map(FrameTracker::new)
 }
 
 // deallocate a frame
 pub fn frame_dealloc(ppn: PhysPageNum) {
-    FRAME_ALLOCATOR.exclusive_access().dealloc(ppn);
+    FRAME_ALLOCATOR.lock().dealloc(ppn);
+}
+
+// (free, total) number of frames
+pub fn frame_stats() -> (usize, usize) {
+    let allocator = FRAME_ALLOCATOR.lock();
+    (allocator.free(), allocator.total)
 }
 
 #[allow(unused)]
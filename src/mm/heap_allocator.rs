@@ -14,6 +14,12 @@ pub fn handle_alloc_error(layout: core::alloc::Layout) -> ! {
 // heap space allocated as zero
 static mut HEAP_SPACE: [u8; KERNEL_HEAP_SIZE] = [0; KERNEL_HEAP_SIZE];
 
+// (used, total) bytes of the kernel heap
+pub fn heap_stats() -> (usize, usize) {
+    let heap = HEAP_ALLOCATOR.lock();
+    (heap.stats_alloc_actual(), heap.stats_total_bytes())
+}
+
 // initialize heap allocator use HEAP_SPACE
 pub fn init_heap() {
     unsafe {
@@ -1,5 +1,8 @@
-use crate::config::{MEMORY_END, MMIO, USER_STACK_SIZE, PAGE_SIZE, TRAMPOLINE, TRAP_CONTEXT};
-use crate::sync::UPSafeCell;
+use crate::config::{MAX_HARTS, MEMORY_END, MMIO, MMAP_BASE, PAGE_SIZE, TRAMPOLINE, USER_STACK_BASE};
+use crate::fs::File;
+use crate::sbi::remote_sfence_vma;
+use crate::sync::SpinNoIrqLock;
+use crate::task::hart_id;
 use super::{StepByOne, VPNRange};
 use super::{frame_alloc, FrameTracker};
 use super::{PTEFlags, PageTable, PageTableEntry};
@@ -8,6 +11,7 @@ use alloc::collections::BTreeMap;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 use core::arch::asm;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
 use riscv::register::satp;
 
@@ -26,13 +30,24 @@ extern "C" {
 
 lazy_static!{
     // memory set for kernel space
-    pub static ref KERNEL_SPACE: Arc<UPSafeCell<MemorySet>> = 
-        Arc::new(unsafe { UPSafeCell::new(MemorySet::new_kernel()) });
+    pub static ref KERNEL_SPACE: Arc<SpinNoIrqLock<MemorySet>> = 
+        Arc::new(SpinNoIrqLock::new(MemorySet::new_kernel()));
+}
+
+lazy_static! {
+    // token of the user address space each hart returned to last time
+    static ref HART_TOKENS: [AtomicUsize; MAX_HARTS] = core::array::from_fn(|_| AtomicUsize::new(0));
+}
+
+/// Record the user address space current hart is returning to, it is flushed from the TLB
+/// by `sfence.vma` after this
+pub fn set_hart_token(token: usize) {
+    HART_TOKENS[hart_id()].store(token, Ordering::SeqCst);
 }
 
 /// Get kernel space root ppn
 pub fn kernel_token() -> usize {
-    KERNEL_SPACE.exclusive_access().token()
+    KERNEL_SPACE.lock().token()
 }
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -40,6 +55,8 @@ pub fn kernel_token() -> usize {
 pub enum MapType {
     Identical,
     Framed,
+    // frames are allocated when the page is touched at the first time
+    Lazy,
 }
 
 bitflags! {
@@ -52,6 +69,74 @@ bitflags! {
     }
 }
 
+// file which backs a map area, used by mmap
+#[derive(Clone)]
+pub struct MapFile {
+    pub file: Arc<dyn File + Send + Sync>,
+    // offset in file of the start of the area
+    pub offset: usize,
+    // length of mapped bytes, pages beyond it are zero
+    pub len: usize,
+    // write modification back to file when unmapped
+    pub shared: bool,
+}
+
+// result of a page fault which is resolved by `MemorySet`
+pub enum PageFault {
+    // the page is ready
+    Handled,
+    // a real fault, or there is no memory for the page
+    Invalid,
+    // the page of a file mapping has to be read from the file, reading a file may sleep,
+    // so the caller does it by `FilePage::read` with the memory set unlocked,
+    // then maps it by `MemorySet::map_file_page`
+    ReadFile(FilePage),
+}
+
+// a new frame for page `vpn` of a file mapping, before it is read from the file
+pub struct FilePage {
+    vpn: VirtPageNum,
+    file: Arc<dyn File + Send + Sync>,
+    // offset in file of the page
+    offset: usize,
+    // length of bytes in the file, the rest of the page is zero
+    len: usize,
+    frame: FrameTracker,
+}
+
+impl FilePage {
+    // return false if the file can't be read
+    pub fn read(&self) -> bool {
+        self.file
+            .read_at(self.offset, &mut self.frame.ppn.get_bytes_array()[..self.len])
+            .is_some()
+    }
+}
+
+// touched pages of a shared file mapping to be written back to the file,
+// files may sleep, so `WriteBack::write` is called with the process unlocked
+pub struct WriteBack {
+    map_file: MapFile,
+    // (offset in the area, frame) of the pages
+    pages: Vec<(usize, Arc<FrameTracker>)>,
+}
+
+impl WriteBack {
+    pub fn write(&self) {
+        for (page_offset, frame) in self.pages.iter() {
+            let len = PAGE_SIZE.min(self.map_file.len - page_offset);
+            let written = self
+                .map_file
+                .file
+                .write_at(self.map_file.offset + page_offset, &frame.ppn.get_bytes_array()[..len]);
+            // nobody waits for the result, so it is only logged
+            if written.is_none() {
+                warn!("kernel #0", "failed to write back a page of a file mapping");
+            }
+        }
+    }
+}
+
 // memory set struct, control virtual-memory space
 pub struct MemorySet {
     // the page table of the set
@@ -93,17 +178,136 @@ impl MemorySet {
             None,
         );
     }
-    // Remove `MapArea` that start with `start_va`
-    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) {
-        if let Some((idx, area)) = self
+    // insert an area whose type is lazy
+    // assume that no conflict exists
+    pub fn insert_lazy_area(
+        &mut self,
+        start_va: VirtAddr,
+        end_va: VirtAddr,
+        permission: MapPermission,
+    ) {
+        self.push(
+            MapArea::new(start_va, end_va, MapType::Lazy, permission),
+            None,
+        );
+    }
+    // insert a lazy area for mmap at [start, end), return false if it overlaps with
+    // existed areas or reaches user stacks and trap contexts above `USER_STACK_BASE`
+    pub fn insert_mmap_area(
+        &mut self,
+        start: usize,
+        end: usize,
+        permission: MapPermission,
+        file: Option<MapFile>,
+    ) -> bool {
+        if end > USER_STACK_BASE || start >= end {
+            return false;
+        }
+        let (start_va, end_va) = (VirtAddr::from(start), VirtAddr::from(end));
+        if self.is_overlapped(start_va.floor(), end_va.ceil()) {
+            return false;
+        }
+        let mut map_area = MapArea::new(start_va, end_va, MapType::Lazy, permission);
+        map_area.file = file;
+        self.push(map_area, None);
+        true
+    }
+    // (start, end, permission, if it is a shared file mapping) of all areas
+    pub fn area_info(&self) -> Vec<(VirtAddr, VirtAddr, MapPermission, bool)> {
+        self.areas
+            .iter()
+            .map(|area| {
+                let shared = area.file.as_ref().map_or(false, |file| file.shared);
+                (area.vpn_range.get_start().into(), area.vpn_range.get_end().into(), area.map_perm, shared)
+            })
+            .collect()
+    }
+    // find a free range of `len` bytes between `MMAP_BASE` and user stacks
+    pub fn find_free_area(&self, len: usize) -> Option<VirtAddr> {
+        if len > USER_STACK_BASE - MMAP_BASE {
+            return None;
+        }
+        let page_count = VirtAddr::from(len).ceil().0;
+        let mut start_vpn = VirtAddr::from(MMAP_BASE).floor();
+        loop {
+            let end_vpn = VirtPageNum(start_vpn.0 + page_count);
+            if end_vpn > VirtAddr::from(USER_STACK_BASE).floor() {
+                return None;
+            }
+            if let Some(area) = self.areas.iter().find(|area| {
+                area.vpn_range.get_start() < end_vpn && start_vpn < area.vpn_range.get_end()
+            }) {
+                start_vpn = area.vpn_range.get_end();
+            } else {
+                return Some(start_vpn.into());
+            }
+        }
+    }
+    // Remove the area which is exactly [start_va, end_va), used by munmap.
+    // the removed area still holds its pages, to be written back to its file
+    pub fn remove_mmap_area(&mut self, start_va: VirtAddr, end_va: VirtAddr) -> Option<MapArea> {
+        if self.areas.iter().any(|area| {
+            area.vpn_range.get_start() == start_va.floor() && area.vpn_range.get_end() == end_va.ceil()
+        }) {
+            self.remove_area_with_start_vpn(start_va.floor())
+        } else {
+            None
+        }
+    }
+    // Remove `MapArea` that start with `start_va`, its pages are freed when it is dropped
+    pub fn remove_area_with_start_vpn(&mut self, start_vpn: VirtPageNum) -> Option<MapArea> {
+        let (idx, area) = self
             .areas
             .iter_mut()
             .enumerate()
-            .find(|(_, area)| area.vpn_range.get_start() == start_vpn)
+            .find(|(_, area)| area.vpn_range.get_start() == start_vpn)?;
+        area.unmap(&mut self.page_table);
+        let area = self.areas.remove(idx);
+        self.flush_other_harts();
+        Some(area)
+    }
+    // check whether [start_vpn, end_vpn) overlaps with any area in this set
+    pub fn is_overlapped(&self, start_vpn: VirtPageNum, end_vpn: VirtPageNum) -> bool {
+        self.areas.iter().any(|area| {
+            area.vpn_range.get_start() < end_vpn && start_vpn < area.vpn_range.get_end()
+        })
+    }
+    // shrink the area start with `start` to `new_end`, used by sbrk
+    pub fn shrink_to(&mut self, start: VirtAddr, new_end: VirtAddr) -> bool {
+        if let Some(area) = self
+            .areas
+            .iter_mut()
+            .find(|area| area.vpn_range.get_start() == start.floor())
+        {
+            if new_end.ceil() < area.vpn_range.get_start() {
+                return false;
+            }
+            area.shrink_to(&mut self.page_table, new_end.ceil());
+            self.flush_other_harts();
+            true
+        } else {
+            false
+        }
+    }
+    // extend the area start with `start` to `new_end`, used by sbrk
+    pub fn append_to(&mut self, start: VirtAddr, new_end: VirtAddr) -> bool {
+        let (idx, old_end) = match self
+            .areas
+            .iter()
+            .enumerate()
+            .find(|(_, area)| area.vpn_range.get_start() == start.floor())
         {
-            area.unmap(&mut self.page_table);
-            self.areas.remove(idx);
+            Some((idx, area)) => (idx, area.vpn_range.get_end()),
+            None => return false,
+        };
+        let new_end_vpn = new_end.ceil();
+        if new_end_vpn > VirtAddr::from(USER_STACK_BASE).floor()
+            || (new_end_vpn > old_end && self.is_overlapped(old_end, new_end_vpn))
+        {
+            return false;
         }
+        self.areas[idx].append_to(&mut self.page_table, new_end_vpn);
+        true
     }
     // add a page for trampoline which is not contained in any memory area
     fn map_trampoline(&mut self) {
@@ -216,80 +420,192 @@ impl MemorySet {
                 if ph_flags.is_execute() {
                     map_perm |= MapPermission::X;
                 }
-                let map_area = MapArea::new(start_va, end_va, MapType::Framed, map_perm);
-                max_end_vpn = map_area.vpn_range.get_end();
-                memory_set.push(
-                    map_area, 
-                    Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
-                );
+                // pages only in memory (.bss) are mapped lazily, all of them if
+                // there is nothing in the file
+                let file_end_va: VirtAddr = ((ph.virtual_addr() + ph.file_size()) as usize).into();
+                let lazy_start_vpn = if ph.file_size() > 0 {
+                    file_end_va.ceil()
+                } else {
+                    start_va.floor()
+                };
+                if ph.file_size() > 0 {
+                    let map_area = MapArea::new(
+                        start_va,
+                        VirtAddr::from(lazy_start_vpn).min(end_va),
+                        MapType::Framed,
+                        map_perm,
+                    );
+                    memory_set.push(
+                        map_area,
+                        Some(&elf.input[ph.offset() as usize..(ph.offset() + ph.file_size()) as usize]),
+                    );
+                }
+                if lazy_start_vpn < end_va.ceil() {
+                    memory_set.push(
+                        MapArea::new(lazy_start_vpn.into(), end_va, MapType::Lazy, map_perm),
+                        None,
+                    );
+                }
+                max_end_vpn = end_va.ceil();
             }
         }
         let max_end_va: VirtAddr = max_end_vpn.into();
-        let mut user_stack_bottom: usize = max_end_va.into();
+        let mut heap_bottom: usize = max_end_va.into();
         // guard page
-        user_stack_bottom += PAGE_SIZE;
-        let user_stack_top = user_stack_bottom + USER_STACK_SIZE;
+        heap_bottom += PAGE_SIZE;
+        // used in sbrk, user stacks and trap contexts are mapped for each thread
         memory_set.push(
             MapArea::new(
-                user_stack_bottom.into(),
-                user_stack_top.into(),
-                MapType::Framed,
+                heap_bottom.into(),
+                heap_bottom.into(),
+                MapType::Lazy,
                 MapPermission::R | MapPermission::W | MapPermission::U,
             ),
             None,
         );
-        // used in sbrk
-        memory_set.push(
-            MapArea::new(
-                user_stack_top.into(),
-                user_stack_top.into(),
-                MapType::Framed,
-                MapPermission::R | MapPermission::W | MapPermission::U,
-            ),
-            None,
-        );
-        // map TrapCopntext
-        memory_set.push(
-            MapArea::new(
-                TRAP_CONTEXT.into(),
-                TRAMPOLINE.into(),
-                MapType::Framed,
-                MapPermission::R | MapPermission::W,
-            ),
-            None,
-        );
         (
             memory_set,
-            user_stack_top,
+            heap_bottom,
             elf.header.pt2.entry_point() as usize,
         )
     }
-    // Clone a same `memory_set`
-    pub fn from_existed_user(user_space: &Self) -> Self {
+    // Clone a same `memory_set`, user pages are shared with copy on write
+    pub fn from_existed_user(user_space: &mut Self) -> Self {
         let mut memory_set = Self::new_bare();
         // map trampoline
         memory_set.map_trampoline();
-        // copy data sections/trap_context/user_stack by memory areas
         for area in user_space.areas.iter() {
-            let new_area = MapArea::from_another(area);
-            memory_set.push(new_area, None);
-            // Copy data
-            for vpn in area.vpn_range {
-                let src_ppn = user_space
-                    .translate(vpn)
-                    .unwrap()
-                    .ppn();
-                let dst_ppn = memory_set
-                    .translate(vpn)
-                    .unwrap()
-                    .ppn();
-                dst_ppn
-                    .get_bytes_array()
-                    .copy_from_slice(src_ppn.get_bytes_array());
+            let mut new_area = MapArea::from_another(area);
+            if area.map_type != MapType::Identical && area.map_perm.contains(MapPermission::U) {
+                // share data sections/user_stack, both sides are mapped read-only
+                let mut pte_flags = PTEFlags::from_bits(area.map_perm.bits as u16).unwrap();
+                if area.map_perm.contains(MapPermission::W) && !area.is_shared() {
+                    pte_flags.remove(PTEFlags::W);
+                    pte_flags.insert(PTEFlags::COW);
+                }
+                for (vpn, frame) in area.data_frames.iter() {
+                    if pte_flags.contains(PTEFlags::COW) {
+                        user_space.page_table.set_flags(*vpn, pte_flags);
+                    }
+                    memory_set.page_table.map(*vpn, frame.ppn, pte_flags);
+                    new_area.data_frames.insert(*vpn, Arc::clone(frame));
+                }
+                memory_set.areas.push(new_area);
+            } else {
+                // copy trap_context, it is written by kernel directly
+                memory_set.push(new_area, None);
+                for vpn in area.vpn_range {
+                    let src_ppn = user_space
+                        .translate(vpn)
+                        .unwrap()
+                        .ppn();
+                    let dst_ppn = memory_set
+                        .translate(vpn)
+                        .unwrap()
+                        .ppn();
+                    dst_ppn
+                        .get_bytes_array()
+                        .copy_from_slice(src_ppn.get_bytes_array());
+                }
             }
         }
+        // writable pages of the parent are read-only now
+        user_space.flush_other_harts();
         memory_set
     }
+    // Try to handle a page fault at `va`
+    pub fn handle_page_fault(&mut self, va: VirtAddr, is_write: bool) -> PageFault {
+        let vpn = va.floor();
+        let area = match self
+            .areas
+            .iter_mut()
+            .find(|area| area.vpn_range.get_start() <= vpn && vpn < area.vpn_range.get_end())
+        {
+            Some(area) => area,
+            None => return PageFault::Invalid,
+        };
+        if is_write && !area.map_perm.contains(MapPermission::W) {
+            return PageFault::Invalid;
+        }
+        match self.page_table.translate(vpn) {
+            Some(pte) if pte.is_valid() => {
+                // a stale read-only TLB entry of a page made writable on another hart
+                if is_write && pte.writable() {
+                    return PageFault::Handled;
+                }
+                if !is_write || !pte.is_cow() || !area.copy_on_write(&mut self.page_table, vpn) {
+                    return PageFault::Invalid;
+                }
+                self.flush_other_harts();
+                PageFault::Handled
+            }
+            _ => {
+                if area.map_type != MapType::Lazy {
+                    return PageFault::Invalid;
+                }
+                // first touch of a lazy page
+                area.map_lazy(&mut self.page_table, vpn)
+            }
+        }
+    }
+    // Resolve faults in user buffer [start, start + len) before kernel accesses it
+    // through physical address. it stops at the first page which is invalid or has
+    // to be read from a file, and is called again after the page is read
+    pub fn prepare_user_buffer(&mut self, start: usize, len: usize, is_write: bool) -> PageFault {
+        let end = match start.checked_add(len) {
+            Some(end) => end,
+            None => return PageFault::Invalid,
+        };
+        let start_vpn = VirtAddr::from(start).floor();
+        let end_vpn = VirtAddr::from(end).ceil();
+        for vpn in VPNRange::new(start_vpn, end_vpn) {
+            let need_fault = match self.page_table.translate(vpn) {
+                Some(pte) if pte.is_valid() => is_write && pte.is_cow(),
+                _ => true,
+            };
+            if need_fault {
+                match self.handle_page_fault(vpn.into(), is_write) {
+                    PageFault::Handled => {}
+                    fault => return fault,
+                }
+            }
+        }
+        PageFault::Handled
+    }
+    // map a page of a file mapping which is read from the file. the area may be
+    // unmapped, or the page mapped by another thread while the file was read,
+    // then the page is dropped and the access is tried again
+    pub fn map_file_page(&mut self, page: FilePage) {
+        let vpn = page.vpn;
+        if self.page_table.translate(vpn).map_or(false, |pte| pte.is_valid()) {
+            return;
+        }
+        if let Some(area) = self.areas.iter_mut().find(|area| {
+            area.vpn_range.get_start() <= vpn
+                && vpn < area.vpn_range.get_end()
+                && area.file.as_ref().map_or(false, |map_file| {
+                    Arc::ptr_eq(&map_file.file, &page.file)
+                        && map_file.offset + (vpn.0 - area.vpn_range.get_start().0) * PAGE_SIZE == page.offset
+                })
+        }) {
+            let pte_flags = PTEFlags::from_bits(area.map_perm.bits as u16).unwrap();
+            self.page_table.map(vpn, page.frame.ppn, pte_flags);
+            area.data_frames.insert(vpn, Arc::new(page.frame));
+        }
+    }
+    // mappings are removed or made read-only, flush the TLB of other harts which may run
+    // in this address space. current hart flushes it when it returns to user space
+    fn flush_other_harts(&self) {
+        let token = self.token();
+        // PTEs are written before a hart which records the token later does `sfence.vma`
+        core::sync::atomic::fence(Ordering::SeqCst);
+        let hart_mask = (0..MAX_HARTS)
+            .filter(|hart| *hart != hart_id() && HART_TOKENS[*hart].load(Ordering::SeqCst) == token)
+            .fold(0, |mask, hart| mask | 1 << hart);
+        if hart_mask != 0 {
+            remote_sfence_vma(hart_mask);
+        }
+    }
     pub fn activate(&self) {
         let satp = self.page_table.token();
         unsafe {
@@ -300,7 +616,11 @@ impl MemorySet {
     pub fn translate(&self, vpn: VirtPageNum) -> Option<PageTableEntry> {
         self.page_table.translate(vpn)
     }
-    // Remove all memory_set
+    // pages of all shared file mappings to be written back
+    pub fn write_back(&self) -> Vec<WriteBack> {
+        self.areas.iter().filter_map(MapArea::write_back).collect()
+    }
+    // Remove all memory_set, shared file mappings should be written back before
     pub fn recycle_data_pages(&mut self) {
         self.areas.clear();
     }
@@ -308,9 +628,10 @@ impl MemorySet {
 
 pub struct MapArea {
     vpn_range: VPNRange,
-    data_frames: BTreeMap<VirtPageNum, FrameTracker>,
+    data_frames: BTreeMap<VirtPageNum, Arc<FrameTracker>>,
     map_type: MapType,
     map_perm: MapPermission,
+    file: Option<MapFile>,
 }
 
 impl MapArea {
@@ -327,6 +648,7 @@ impl MapArea {
             data_frames: BTreeMap::new(),
             map_type,
             map_perm,
+            file: None,
         }
     }
     pub fn map_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
@@ -336,33 +658,108 @@ impl MapArea {
                 // map directly
                 ppn = PhysPageNum(vpn.0);
             }
-            MapType::Framed => {
+            MapType::Framed | MapType::Lazy => {
                 // allocate a new frame to map
                 let frame = frame_alloc().unwrap();
                 ppn = frame.ppn;
-                self.data_frames.insert(vpn, frame);
+                self.data_frames.insert(vpn, Arc::new(frame));
             }
         }
-        let pte_flags = PTEFlags::from_bits(self.map_perm.bits).unwrap();
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits as u16).unwrap();
         page_table.map(vpn, ppn, pte_flags);
     }
+    // map the first touched page `vpn` of a lazy area, a page of a file mapping
+    // is left to be read from the file
+    pub fn map_lazy(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> PageFault {
+        let frame = match frame_alloc() {
+            Some(frame) => frame,
+            None => return PageFault::Invalid,
+        };
+        if let Some(map_file) = &self.file {
+            let page_offset = (vpn.0 - self.vpn_range.get_start().0) * PAGE_SIZE;
+            if page_offset < map_file.len {
+                return PageFault::ReadFile(FilePage {
+                    vpn,
+                    file: Arc::clone(&map_file.file),
+                    offset: map_file.offset + page_offset,
+                    len: PAGE_SIZE.min(map_file.len - page_offset),
+                    frame,
+                });
+            }
+        }
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits as u16).unwrap();
+        page_table.map(vpn, frame.ppn, pte_flags);
+        self.data_frames.insert(vpn, Arc::new(frame));
+        PageFault::Handled
+    }
     pub fn map(&mut self, page_table: &mut PageTable) {
+        if self.map_type == MapType::Lazy {
+            // map when page fault occurs
+            return;
+        }
         for vpn in self.vpn_range {
             self.map_one(page_table, vpn);
         }
     }
     #[allow(unused)]
     pub fn unmap_one(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) {
-        if self.map_type == MapType::Framed {
-            self.data_frames.remove(&vpn);
+        match self.map_type {
+            MapType::Identical => {}
+            MapType::Framed => {
+                self.data_frames.remove(&vpn);
+            }
+            MapType::Lazy => {
+                if self.data_frames.remove(&vpn).is_none() {
+                    // never touched
+                    return;
+                }
+            }
         }
         page_table.unmap(vpn);
     }
-    #[allow(unused)]
+    // unmap all pages, their frames are kept until the area is dropped,
+    // so that they can be written back to the file
     pub fn unmap(&mut self, page_table: &mut PageTable) {
-        for vpn in self.vpn_range {
+        match self.map_type {
+            MapType::Identical => self.vpn_range.into_iter().for_each(|vpn| page_table.unmap(vpn)),
+            MapType::Framed | MapType::Lazy => {
+                self.data_frames.keys().for_each(|vpn| page_table.unmap(*vpn))
+            }
+        }
+    }
+    // whether modification of the area is visible to others
+    pub fn is_shared(&self) -> bool {
+        self.file.as_ref().map_or(false, |map_file| map_file.shared)
+    }
+    // touched pages to be written back to file for shared file mapping
+    pub fn write_back(&self) -> Option<WriteBack> {
+        if !self.is_shared() {
+            return None;
+        }
+        let map_file = self.file.clone().unwrap();
+        let pages = self
+            .data_frames
+            .iter()
+            .map(|(vpn, frame)| ((vpn.0 - self.vpn_range.get_start().0) * PAGE_SIZE, Arc::clone(frame)))
+            .filter(|(page_offset, _)| *page_offset < map_file.len)
+            .collect();
+        Some(WriteBack { map_file, pages })
+    }
+    // unmap pages in [new_end, end)
+    pub fn shrink_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
+        for vpn in VPNRange::new(new_end, self.vpn_range.get_end()) {
             self.unmap_one(page_table, vpn);
         }
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
+    }
+    // map pages in [end, new_end)
+    pub fn append_to(&mut self, page_table: &mut PageTable, new_end: VirtPageNum) {
+        if self.map_type != MapType::Lazy {
+            for vpn in VPNRange::new(self.vpn_range.get_end(), new_end) {
+                self.map_one(page_table, vpn);
+            }
+        }
+        self.vpn_range = VPNRange::new(self.vpn_range.get_start(), new_end);
     }
     pub fn copy_data(&mut self, page_table: &mut PageTable, data: &[u8]) {
         assert_eq!(self.map_type, MapType::Framed);
@@ -384,18 +781,42 @@ impl MapArea {
             current_vpn.step();
         }
     }
+    // give `vpn` a private writable frame, copy the data if the frame is shared,
+    // return false if there is no memory
+    pub fn copy_on_write(&mut self, page_table: &mut PageTable, vpn: VirtPageNum) -> bool {
+        let pte_flags = PTEFlags::from_bits(self.map_perm.bits as u16).unwrap();
+        let frame = self.data_frames.get(&vpn).unwrap();
+        if Arc::strong_count(frame) == 1 {
+            // the last owner, just make it writable again
+            page_table.set_flags(vpn, pte_flags);
+        } else {
+            let new_frame = match frame_alloc() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            new_frame
+                .ppn
+                .get_bytes_array()
+                .copy_from_slice(frame.ppn.get_bytes_array());
+            page_table.unmap(vpn);
+            page_table.map(vpn, new_frame.ppn, pte_flags);
+            self.data_frames.insert(vpn, Arc::new(new_frame));
+        }
+        true
+    }
     pub fn from_another(another: &Self) -> Self {
         Self {
             vpn_range: VPNRange::new(another.vpn_range.get_start(), another.vpn_range.get_end()),
             data_frames: BTreeMap::new(),
             map_type: another.map_type,
             map_perm: another.map_perm,
+            file: another.file.clone(),
         }
     }
 }
 
 pub fn remap_test() {
-    let kernel_space = KERNEL_SPACE.exclusive_access();
+    let kernel_space = KERNEL_SPACE.lock();
     let mid_text: VirtAddr = ((stext as usize + etext as usize) / 2).into();
     let mid_rodata: VirtAddr = ((srodata as usize + erodata as usize) / 2).into();
     let mid_data: VirtAddr = ((sdata as usize + edata as usize) / 2).into();
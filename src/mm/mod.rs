@@ -6,8 +6,9 @@ mod page_table;
 
 use     address::VPNRange;
 pub use address::{PhysAddr, PhysPageNum, VirtAddr, VirtPageNum, StepByOne};
-pub use frame_allocator::{frame_alloc, frame_dealloc, FrameTracker};
-pub use memory_set::{MapPermission, MemorySet, KERNEL_SPACE, remap_test, kernel_token};
+pub use frame_allocator::{frame_alloc, frame_dealloc, frame_stats, FrameTracker};
+pub use heap_allocator::heap_stats;
+pub use memory_set::{FilePage, MapFile, MapPermission, MemorySet, PageFault, WriteBack, KERNEL_SPACE, remap_test, kernel_token, set_hart_token};
 use     page_table::PTEFlags;
 pub use page_table::{translated_byte_buffer, PageTableEntry, translate_to_str, translated_refmut,
                     UserBuffer, PageTable, translate_ref};
@@ -16,5 +17,9 @@ pub fn init() {
     heap_allocator::init_heap();
     info!("kernel #0", "heap allocator initialized");
     frame_allocator::init_frame_allocator();
-    KERNEL_SPACE.exclusive_access().activate();
-}
\ No newline at end of file
+    KERNEL_SPACE.lock().activate();
+}
+// enable kernel address space on other harts
+pub fn init_secondary() {
+    KERNEL_SPACE.lock().activate();
+}
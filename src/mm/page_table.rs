@@ -7,7 +7,7 @@ use alloc::vec;
 
 bitflags! {
     // PTE flags
-    pub struct PTEFlags: u8 {
+    pub struct PTEFlags: u16 {
         const V = 1 << 0; // Valid
         const R = 1 << 1; // Readable
         const W = 1 << 2; // Writable
@@ -16,6 +16,7 @@ bitflags! {
         const G = 1 << 5; // 
         const A = 1 << 6; // Accessed
         const D = 1 << 7; // Dirty
+        const COW = 1 << 8; // Copy on write, use the first RSW bit
     }
 }
 
@@ -40,7 +41,8 @@ impl PageTableEntry {
         (self.bits >> 10 & ((1usize << 44) - 1)).into()
     }
     pub fn flags(&self) -> PTEFlags {
-        PTEFlags::from_bits(self.bits as u8).unwrap()
+        // bits above 9 belong to ppn, truncate them
+        PTEFlags::from_bits_truncate(self.bits as u16)
     }
     pub fn is_valid(&self) -> bool {
         (self.flags() & PTEFlags::V) != PTEFlags::empty()
@@ -54,6 +56,9 @@ impl PageTableEntry {
     pub fn executable(&self) -> bool {
         (self.flags() & PTEFlags::X) != PTEFlags::empty()
     }
+    pub fn is_cow(&self) -> bool {
+        (self.flags() & PTEFlags::COW) != PTEFlags::empty()
+    }
 }
 
 // page table structure
@@ -120,6 +125,12 @@ impl PageTable {
         // clear the page
         *pte = PageTableEntry::empty();
     }
+    // change the flags of a mapped page, keep the ppn unchanged
+    pub fn set_flags(&mut self, vpn: VirtPageNum, flags: PTEFlags) {
+        let pte = self.find_pte(vpn).unwrap();
+        assert!(pte.is_valid(), "vpn {:?} is not mapped", vpn);
+        *pte = PageTableEntry::new(pte.ppn(), flags | PTEFlags::V);
+    }
     pub fn from_token(satp: usize) -> Self {
         Self {
             root_ppn: PhysPageNum::from(satp & ((1usize << 44) - 1)),
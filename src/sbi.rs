@@ -1,22 +1,3 @@
-//use core::arch::asm;
-
-pub fn console_putchar(c: usize) {
-    // let ret: usize;
-    // let arg0: usize = c;
-    // let arg1: usize = 0;
-    // let arg2: usize = 0;
-    // let arg3: usize = 0;
-    // let which: usize = 1;
-    // unsafe {
-    //     asm!("ecall",
-    //         in("a0") arg0, in("a1") arg1, in("a2") arg2, in("a3") arg3, in("a7") which,
-    //         lateout("a0") ret,
-    //     );
-    // }
-    #[allow(deprecated)]
-    sbi_rt::legacy::console_putchar(c);
-}
-
 pub fn shutdown(failure: bool) -> ! {
     use sbi_rt::{system_reset, NoReason, Shutdown, SystemFailure};
     if !failure {
@@ -29,4 +10,13 @@ pub fn shutdown(failure: bool) -> ! {
 
 pub fn set_timer(timer: usize) {
     sbi_rt::set_timer(timer as _);
-}
\ No newline at end of file
+}
+// start `hartid` at `start_addr` in supervisor mode, return the SBI error code
+pub fn hart_start(hartid: usize, start_addr: usize) -> usize {
+    sbi_rt::hart_start(hartid, start_addr, 0).error
+}
+// flush all TLB entries on harts in `hart_mask`
+pub fn remote_sfence_vma(hart_mask: usize) {
+    // a size of usize::MAX flushes the whole address space
+    sbi_rt::remote_sfence_vma(hart_mask, 0, 0, usize::MAX);
+}
@@ -0,0 +1,30 @@
+use alloc::sync::Arc;
+
+use super::{irq_restore, irq_save, Mutex, WaitQueue};
+
+// Condition variable for user space
+pub struct Condvar {
+    wait_queue: WaitQueue,
+}
+
+impl Condvar {
+    pub fn new() -> Self {
+        Self {
+            wait_queue: WaitQueue::new(),
+        }
+    }
+    // wake up a waiting thread if exists
+    pub fn signal(&self) {
+        self.wait_queue.wake_one();
+    }
+    // release `mutex` and wait for signal, `mutex` is locked again after waking up
+    pub fn wait(&self, mutex: Arc<dyn Mutex>) {
+        let sie = irq_save();
+        // in the queue before the mutex is unlocked, so a signal after it can't be missed
+        self.wait_queue.wait_after(|| {
+            mutex.unlock();
+        });
+        irq_restore(sie);
+        mutex.lock();
+    }
+}
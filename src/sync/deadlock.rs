@@ -0,0 +1,101 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Banker's algorithm style bookkeeping of one kind of resources in a process,
+// indexed by tid and resource id
+pub struct ResourceTracker {
+    // free instances of each resource
+    available: Vec<usize>,
+    // instances of each resource held by each thread
+    allocation: Vec<Vec<usize>>,
+    // instances of each resource each thread is waiting for
+    need: Vec<Vec<usize>>,
+}
+
+impl ResourceTracker {
+    pub fn new() -> Self {
+        Self {
+            available: Vec::new(),
+            allocation: Vec::new(),
+            need: Vec::new(),
+        }
+    }
+    // make sure the tables cover thread `tid` and resource `id`
+    fn reserve(&mut self, tid: usize, id: usize) {
+        if self.available.len() <= id {
+            self.available.resize(id + 1, 0);
+        }
+        let res_count = self.available.len();
+        if self.allocation.len() <= tid {
+            self.allocation.resize(tid + 1, Vec::new());
+            self.need.resize(tid + 1, Vec::new());
+        }
+        for row in self.allocation.iter_mut().chain(self.need.iter_mut()) {
+            row.resize(res_count, 0);
+        }
+    }
+    // register resource `id` with `count` instances, its old records are dropped
+    pub fn add_resource(&mut self, id: usize, count: usize) {
+        self.reserve(0, id);
+        self.available[id] = count;
+        for row in self.allocation.iter_mut().chain(self.need.iter_mut()) {
+            row[id] = 0;
+        }
+    }
+    // thread `tid` asks for an instance of resource `id`,
+    // if `check` and the request may lead to a deadlock, it is withdrawn and false is returned
+    pub fn request(&mut self, tid: usize, id: usize, check: bool) -> bool {
+        self.reserve(tid, id);
+        self.need[tid][id] += 1;
+        if check && !self.is_safe() {
+            self.need[tid][id] -= 1;
+            return false;
+        }
+        true
+    }
+    // thread `tid` gets an instance of resource `id` it asked for
+    pub fn acquire(&mut self, tid: usize, id: usize) {
+        self.reserve(tid, id);
+        self.need[tid][id] = self.need[tid][id].saturating_sub(1);
+        self.allocation[tid][id] += 1;
+        self.available[id] = self.available[id].saturating_sub(1);
+    }
+    // thread `tid` gives back an instance of resource `id`,
+    // it may not be the one acquired it, such as a semaphore used for signaling
+    pub fn release(&mut self, tid: usize, id: usize) {
+        self.reserve(tid, id);
+        self.allocation[tid][id] = self.allocation[tid][id].saturating_sub(1);
+        self.available[id] += 1;
+    }
+    // forget thread `tid`, resources it holds are never released
+    pub fn remove_thread(&mut self, tid: usize) {
+        if tid < self.allocation.len() {
+            self.allocation[tid].iter_mut().for_each(|v| *v = 0);
+            self.need[tid].iter_mut().for_each(|v| *v = 0);
+        }
+    }
+    // safety check: whether all threads are able to finish in some order
+    fn is_safe(&self) -> bool {
+        let mut work = self.available.clone();
+        let mut finish = vec![false; self.allocation.len()];
+        loop {
+            let next = (0..finish.len()).find(|&tid| {
+                !finish[tid]
+                    && self.need[tid]
+                        .iter()
+                        .zip(work.iter())
+                        .all(|(need, work)| need <= work)
+            });
+            if let Some(tid) = next {
+                // thread `tid` can finish and release all its resources
+                for (work, allocation) in work.iter_mut().zip(self.allocation[tid].iter()) {
+                    *work += allocation;
+                }
+                finish[tid] = true;
+            } else {
+                break;
+            }
+        }
+        finish.iter().all(|&f| f)
+    }
+}
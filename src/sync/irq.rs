@@ -0,0 +1,67 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use riscv::register::sstatus;
+
+use crate::config::MAX_HARTS;
+use crate::task::hart_id;
+
+// disable supervisor interrupts of current hart, return if they were enabled
+pub fn irq_save() -> bool {
+    let sie = sstatus::read().sie();
+    unsafe {
+        sstatus::clear_sie();
+    }
+    sie
+}
+
+// enable supervisor interrupts of current hart if `sie`
+pub fn irq_restore(sie: bool) {
+    if sie {
+        unsafe {
+            sstatus::set_sie();
+        }
+    }
+}
+
+pub fn irq_enable() {
+    unsafe {
+        sstatus::set_sie();
+    }
+}
+
+pub fn irq_disable() {
+    unsafe {
+        sstatus::clear_sie();
+    }
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicUsize = AtomicUsize::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const FALSE: AtomicBool = AtomicBool::new(false);
+// depth of nested `push_off` of each hart
+static IRQ_DEPTH: [AtomicUsize; MAX_HARTS] = [ZERO; MAX_HARTS];
+// if interrupts were enabled before the outermost `push_off` of each hart
+static IRQ_ENABLED: [AtomicBool; MAX_HARTS] = [FALSE; MAX_HARTS];
+
+// disable interrupts, matched with `pop_off`, they can be nested.
+// the hart id is read after interrupts are disabled, so the task can't be moved to
+// another hart in between
+pub fn push_off() {
+    let sie = irq_save();
+    let hart = hart_id();
+    if IRQ_DEPTH[hart].fetch_add(1, Ordering::Relaxed) == 0 {
+        IRQ_ENABLED[hart].store(sie, Ordering::Relaxed);
+    }
+}
+
+// enable interrupts again if it matches the outermost `push_off`
+pub fn pop_off() {
+    let hart = hart_id();
+    assert!(!sstatus::read().sie(), "pop_off: interrupts are enabled");
+    let depth = IRQ_DEPTH[hart].fetch_sub(1, Ordering::Relaxed);
+    assert!(depth > 0, "pop_off: not matched with push_off");
+    if depth == 1 && IRQ_ENABLED[hart].load(Ordering::Relaxed) {
+        irq_enable();
+    }
+}
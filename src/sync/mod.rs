@@ -1,3 +1,15 @@
-mod up;
+mod condvar;
+mod deadlock;
+mod irq;
+mod mutex;
+mod semaphore;
+mod spin;
+mod wait_queue;
 
-pub use up::UPSafeCell;
\ No newline at end of file
+pub use condvar::Condvar;
+pub use deadlock::ResourceTracker;
+pub use irq::{irq_disable, irq_enable, irq_restore, irq_save, pop_off, push_off};
+pub use mutex::{Mutex, MutexBlocking, MutexSpin};
+pub use semaphore::Semaphore;
+pub use spin::{SpinNoIrqLock, SpinNoIrqLockGuard};
+pub use wait_queue::WaitQueue;
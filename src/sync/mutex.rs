@@ -0,0 +1,88 @@
+use super::{irq_restore, irq_save, SpinNoIrqLock, WaitQueue};
+use crate::task::suspend_and_run_next;
+
+// Mutex trait for user space
+pub trait Mutex: Sync + Send {
+    fn lock(&self);
+    // return false if it is not locked
+    fn unlock(&self) -> bool;
+}
+
+// Mutex which yields the CPU while it is locked
+pub struct MutexSpin {
+    locked: SpinNoIrqLock<bool>,
+}
+
+impl MutexSpin {
+    pub fn new() -> Self {
+        Self {
+            locked: SpinNoIrqLock::new(false),
+        }
+    }
+}
+
+impl Mutex for MutexSpin {
+    fn lock(&self) {
+        loop {
+            let mut locked = self.locked.lock();
+            if *locked {
+                drop(locked);
+                suspend_and_run_next();
+                continue;
+            } else {
+                *locked = true;
+                return;
+            }
+        }
+    }
+    fn unlock(&self) -> bool {
+        let mut locked = self.locked.lock();
+        if !*locked {
+            return false;
+        }
+        *locked = false;
+        true
+    }
+}
+
+// Mutex which blocks the waiting threads until it is unlocked
+pub struct MutexBlocking {
+    locked: SpinNoIrqLock<bool>,
+    wait_queue: WaitQueue,
+}
+
+impl MutexBlocking {
+    pub fn new() -> Self {
+        Self {
+            locked: SpinNoIrqLock::new(false),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+}
+
+impl Mutex for MutexBlocking {
+    fn lock(&self) {
+        let sie = irq_save();
+        let mut locked = self.locked.lock();
+        if *locked {
+            // the lock is passed to us when we are woken up,
+            // we are in the queue before `unlock` can see it
+            self.wait_queue.wait_with(locked);
+        } else {
+            *locked = true;
+            drop(locked);
+        }
+        irq_restore(sie);
+    }
+    fn unlock(&self) -> bool {
+        let mut locked = self.locked.lock();
+        if !*locked {
+            return false;
+        }
+        // pass the lock to the waking thread directly
+        if !self.wait_queue.wake_one() {
+            *locked = false;
+        }
+        true
+    }
+}
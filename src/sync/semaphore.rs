@@ -0,0 +1,36 @@
+use super::{irq_restore, irq_save, SpinNoIrqLock, WaitQueue};
+
+// Counting semaphore for user space
+pub struct Semaphore {
+    // available resources, the number of waiting threads if negative
+    count: SpinNoIrqLock<isize>,
+    wait_queue: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(res_count: usize) -> Self {
+        Self {
+            count: SpinNoIrqLock::new(res_count as isize),
+            wait_queue: WaitQueue::new(),
+        }
+    }
+    pub fn up(&self) {
+        let mut count = self.count.lock();
+        *count += 1;
+        if *count <= 0 {
+            self.wait_queue.wake_one();
+        }
+    }
+    pub fn down(&self) {
+        let sie = irq_save();
+        let mut count = self.count.lock();
+        *count -= 1;
+        if *count < 0 {
+            // in the queue before `up` can see the count
+            self.wait_queue.wait_with(count);
+        } else {
+            drop(count);
+        }
+        irq_restore(sie);
+    }
+}
@@ -0,0 +1,97 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "lock_debug")]
+use core::panic::Location;
+
+use super::irq::{pop_off, push_off};
+use crate::task::hart_id;
+
+// owner of an unlocked lock
+const NO_OWNER: usize = usize::MAX;
+
+// Spin lock which disables supervisor interrupts while it is held,
+// so that it can be shared with interrupt handlers, and so that the hart
+// which takes it can't change before it is released.
+// With feature `lock_debug`, the location of the holder is recorded, and locking it
+// twice on the same hart panics instead of spinning forever.
+pub struct SpinNoIrqLock<T: ?Sized> {
+    // the hart holding the lock
+    owner: AtomicUsize,
+    #[cfg(feature = "lock_debug")]
+    holder: UnsafeCell<Option<&'static Location<'static>>>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send> Sync for SpinNoIrqLock<T> {}
+unsafe impl<T: ?Sized + Send> Send for SpinNoIrqLock<T> {}
+
+impl<T> SpinNoIrqLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            owner: AtomicUsize::new(NO_OWNER),
+            #[cfg(feature = "lock_debug")]
+            holder: UnsafeCell::new(None),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinNoIrqLock<T> {
+    // disable interrupts and spin until the lock is acquired,
+    // interrupts are restored when the guard is dropped
+    #[track_caller]
+    pub fn lock(&self) -> SpinNoIrqLockGuard<'_, T> {
+        push_off();
+        let hart = hart_id();
+        while let Err(_owner) =
+            self.owner
+                .compare_exchange_weak(NO_OWNER, hart, Ordering::Acquire, Ordering::Relaxed)
+        {
+            #[cfg(feature = "lock_debug")]
+            if _owner == hart {
+                panic!(
+                    "SpinNoIrqLock: deadlock on hart {}, locked at {}, held since {}",
+                    hart,
+                    Location::caller(),
+                    unsafe { (*self.holder.get()).unwrap() }
+                );
+            }
+            core::hint::spin_loop();
+        }
+        #[cfg(feature = "lock_debug")]
+        unsafe {
+            *self.holder.get() = Some(Location::caller());
+        }
+        SpinNoIrqLockGuard { lock: self }
+    }
+}
+
+pub struct SpinNoIrqLockGuard<'a, T: ?Sized> {
+    lock: &'a SpinNoIrqLock<T>,
+}
+
+impl<T: ?Sized> Deref for SpinNoIrqLockGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinNoIrqLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for SpinNoIrqLockGuard<'_, T> {
+    fn drop(&mut self) {
+        #[cfg(feature = "lock_debug")]
+        unsafe {
+            *self.lock.holder.get() = None;
+        }
+        // release the lock before enabling interrupts
+        self.lock.owner.store(NO_OWNER, Ordering::Release);
+        pop_off();
+    }
+}
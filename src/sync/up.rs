@@ -1,21 +0,0 @@
-use core::cell::{RefCell, RefMut};
-
-// Note: This struct is only safe on uniprocessor systems.
-pub struct UPSafeCell<T> {
-    inner: RefCell<T>,
-}
-
-unsafe impl<T> Sync for UPSafeCell<T> {}
-
-impl<T> UPSafeCell<T> {
-    pub unsafe fn new(value: T) -> Self {
-        Self {
-            inner: RefCell::new(value)
-        }
-    }
-
-    // Panic after multiple times of borrowing
-    pub fn exclusive_access(&self) -> RefMut<'_, T> {
-        self.inner.borrow_mut()
-    }
-}
\ No newline at end of file
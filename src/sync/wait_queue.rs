@@ -0,0 +1,51 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+
+use super::SpinNoIrqLock;
+use crate::task::{block_current_and_run_next, prepare_block_current, wakeup_task, TaskControlBlock};
+
+// Queue of tasks sleeping on an event, they are not in the ready queue until woken up
+pub struct WaitQueue {
+    queue: SpinNoIrqLock<VecDeque<Arc<TaskControlBlock>>>,
+}
+
+impl WaitQueue {
+    pub fn new() -> Self {
+        Self {
+            queue: SpinNoIrqLock::new(VecDeque::new()),
+        }
+    }
+    // block current task and release `guard` after it is in the queue, so that a wakeup
+    // under the lock can't be missed.
+    // interrupts should be disabled before locking, the guard doesn't restore them then
+    pub fn wait_with<G>(&self, guard: G) {
+        self.wait_after(|| drop(guard));
+    }
+    // block current task and call `release` after it is in the queue, for locks which
+    // have no guard. interrupts should be disabled as in `wait_with`
+    pub fn wait_after(&self, release: impl FnOnce()) {
+        let task = prepare_block_current();
+        self.queue.lock().push_back(task);
+        release();
+        block_current_and_run_next();
+    }
+    // wake up the first waiting task, return false if there is none
+    pub fn wake_one(&self) -> bool {
+        let task = self.queue.lock().pop_front();
+        if let Some(task) = task {
+            wakeup_task(task);
+            true
+        } else {
+            false
+        }
+    }
+    // wake up all waiting tasks, return the number of them
+    pub fn wake_all(&self) -> usize {
+        let tasks: VecDeque<_> = core::mem::take(&mut *self.queue.lock());
+        let count = tasks.len();
+        for task in tasks {
+            wakeup_task(task);
+        }
+        count
+    }
+}
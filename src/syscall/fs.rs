@@ -1,22 +1,27 @@
+use alloc::string::String;
 use alloc::sync::Arc;
 
-use crate::fs::make_pipe;
+use crate::fs::{create_dir, join, link, make_pipe, mount, remove_dir, resolve, umount, unlink, Dirent, File, Stat, WorkDir};
 // filesystem-related syscalls
 #[allow(deprecated)]
 use crate::fs::{open_file, OpenFlags};
-use crate::mm::{translate_to_str, translated_byte_buffer, translated_refmut, UserBuffer};
-use crate::task::{current_task, current_user_token};
+use crate::mm::{translated_byte_buffer, translated_refmut, UserBuffer};
+use crate::task::{current_prepare_user_buffer, current_process, current_user_str, current_user_token};
 
 #[allow(unused)]
 const FD_STDERR: usize = 2;
+// `dirfd` of *at syscalls, paths are relative to current working directory
+const AT_FDCWD: isize = -100;
+// flag of unlinkat, remove a directory instead of a file
+const AT_REMOVEDIR: u32 = 0x200;
 
 // write buf of length `len` to a file with `fd`
 pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
     trace!("kernel #0", "Sys_write is called with fd = {}, buf = {}, len = {}",
             fd, buf as usize, len);
     let token = current_user_token();
-    let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
     if fd >= inner.fd_table.len() {
         debug!("kernel #0", "Sys_write: fd out of range");
         return -1;
@@ -28,9 +33,20 @@ pub fn sys_write(fd: usize, buf: *const u8, len: usize) -> isize {
         }
         trace!("kernel #0", "Sys_write: file opened");
         let file = file.clone();
-        // release curernt task TCB manually to avoid multi-borrow
+        // release current process PCB manually to avoid multi-borrow
         drop(inner);
-        file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        // lazy pages in buffer may not be mapped yet
+        if !current_prepare_user_buffer(buf as usize, len, false) {
+            debug!("kernel #0", "Sys_write: invalid user buffer");
+            return -1;
+        }
+        match file.write(UserBuffer::new(translated_byte_buffer(token, buf, len))) {
+            Some(write_size) => write_size as isize,
+            None => {
+                debug!("kernel #0", "Sys_write: I/O error");
+                -1
+            }
+        }
     } else {
         debug!("kernel #0", "Sys_write: fd not opened");
         -1
@@ -43,8 +59,8 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
     trace!("kernel#0", "Sys_read is called with fd = {}, buf = {}, len = {}",
             fd, buf as usize, len);
     let token = current_user_token();
-    let task = current_task().unwrap();
-    let inner = task.inner_exclusive_access();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
     if fd >= inner.fd_table.len() {
         debug!("kernel #0", "Sys_read: fd out of range");
         return -1;
@@ -55,9 +71,20 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
             debug!("kernel #0", "Sys_read: file not readable");
             return -1;
         }
-        // release curernt task TCB manually to avoid multi-borrow
+        // release current process PCB manually to avoid multi-borrow
         drop(inner);
-        file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) as isize
+        // kernel writes the buffer through physical address, resolve page faults first
+        if !current_prepare_user_buffer(buf as usize, len, true) {
+            debug!("kernel #0", "Sys_read: invalid user buffer");
+            return -1;
+        }
+        match file.read(UserBuffer::new(translated_byte_buffer(token, buf, len))) {
+            Some(read_size) => read_size as isize,
+            None => {
+                debug!("kernel #0", "Sys_read: I/O error");
+                -1
+            }
+        }
     } else {
         debug!("kernel #0", "Sys_read: fd not opened");
         -1
@@ -66,11 +93,13 @@ pub fn sys_read(fd: usize, buf: *const u8, len: usize) -> isize {
 
 pub fn sys_open(path: *const u8, flags: u32) -> isize {
     trace!("kernel #0", "Sys_open is called with path = {:?}, flags = {}", path, flags);
-    let task = current_task().unwrap();
-    let token = current_user_token();
-    let path = translate_to_str(token, path);
+    let process = current_process();
+    let path = match user_path(path) {
+        Some(path) => path,
+        None => return -1,
+    };
     if let Some(inode) = open_file(path.as_str(), OpenFlags::from_bits(flags).unwrap()) {
-        let mut inner = task.inner_exclusive_access();
+        let mut inner = process.inner_exclusive_access();
         let fd = inner.alloc_fd();
         trace!("kernel #0", "Sys_open: fd = {}", fd);
         inner.fd_table[fd] = Some(inode);
@@ -83,21 +112,28 @@ pub fn sys_open(path: *const u8, flags: u32) -> isize {
 
 pub fn sys_close(fd: usize) -> isize {
     trace!("kernel#0", "Sys_close is called with fd = {}", fd);
-    let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access();
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
     if fd >= inner.fd_table.len() || inner.fd_table[fd].is_none() {
         debug!("kernel #0", "Sys_close: fd out of range or not opened");
         return -1;
     }
-    inner.fd_table[fd].take();
+    let file = inner.fd_table[fd].take();
+    // the last close may do I/O, which sleeps with the process released
+    drop(inner);
+    drop(file);
     0
 }
 
 pub fn sys_pipe(pipe: *mut usize) -> isize {
     trace!("kernel #0", "Sys_pipe is called with pipe = {:?}", pipe);
-    let task = current_task().unwrap();
+    let process = current_process();
     let token = current_user_token();
-    let mut inner = task.inner_exclusive_access();
+    if !current_prepare_user_buffer(pipe as usize, 2 * core::mem::size_of::<usize>(), true) {
+        debug!("kernel #0", "Sys_pipe: invalid user buffer");
+        return -1;
+    }
+    let mut inner = process.inner_exclusive_access();
     let (pipe_read, pipe_write) = make_pipe();
     let read_fd = inner.alloc_fd();
     inner.fd_table[read_fd] = Some(pipe_read);
@@ -110,8 +146,8 @@ pub fn sys_pipe(pipe: *mut usize) -> isize {
 
 pub fn sys_dup(fd: usize) -> isize {
     trace!("kernel #0", "Sys_dup is called with fd = {}", fd);
-    let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access();
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
     if fd >= inner.fd_table.len() {
         debug!("kernel #0", "Sys_dup: fd out of range");
         return -1;
@@ -123,4 +159,335 @@ pub fn sys_dup(fd: usize) -> isize {
     let new_fd = inner.alloc_fd();
     inner.fd_table[new_fd] = Some(Arc::clone(inner.fd_table[fd].as_ref().unwrap()));
     new_fd as isize
-}
\ No newline at end of file
+}
+// device-specific control of the file `fd`, only terminals support it now
+// return -1 if fd is illegal or the command is not supported
+pub fn sys_ioctl(fd: usize, cmd: usize, arg: usize) -> isize {
+    trace!("kernel #0", "Sys_ioctl is called with fd = {}, cmd = {:#x}", fd, cmd);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    if fd >= inner.fd_table.len() {
+        debug!("kernel #0", "Sys_ioctl: fd out of range");
+        return -1;
+    }
+    if let Some(file) = &inner.fd_table[fd] {
+        let file = file.clone();
+        // the file accesses user memory through current process
+        drop(inner);
+        file.ioctl(cmd, arg)
+    } else {
+        debug!("kernel #0", "Sys_ioctl: fd not opened");
+        -1
+    }
+}
+
+// absolute path of `path` relative to current working directory
+pub fn absolute_path(path: &str) -> String {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    join(&resolve(inner.cwd.path(), path))
+}
+
+// absolute path of the user string `path`, None if it is not accessible
+pub fn user_path(path: *const u8) -> Option<String> {
+    current_user_str(path).map(|path| absolute_path(&path))
+}
+
+// create a directory, return -1 if it exists or can't be created
+pub fn sys_mkdir(path: *const u8) -> isize {
+    let path = match user_path(path) {
+        Some(path) => path,
+        None => return -1,
+    };
+    trace!("kernel #0", "Sys_mkdir is called with path = {}", path);
+    if create_dir(&path) {
+        0
+    } else {
+        -1
+    }
+}
+
+// change current working directory, return -1 if `path` is not a directory
+pub fn sys_chdir(path: *const u8) -> isize {
+    let path = match user_path(path) {
+        Some(path) => path,
+        None => return -1,
+    };
+    trace!("kernel #0", "Sys_chdir is called with path = {}", path);
+    match WorkDir::open(&path) {
+        Some(cwd) => {
+            current_process().inner_exclusive_access().cwd = cwd;
+            0
+        }
+        None => {
+            debug!("kernel #0", "Sys_chdir: not a directory");
+            -1
+        }
+    }
+}
+
+// write current working directory ended with zero to `buf`,
+// return its length without the zero, or -1 if `len` is too small
+pub fn sys_getcwd(buf: *mut u8, len: usize) -> isize {
+    trace!("kernel #0", "Sys_getcwd is called with len = {}", len);
+    let token = current_user_token();
+    let cwd = String::from(current_process().inner_exclusive_access().cwd.path());
+    let cwd_len = cwd.len();
+    if cwd_len + 1 > len {
+        return -1;
+    }
+    if !current_prepare_user_buffer(buf as usize, cwd_len + 1, true) {
+        debug!("kernel #0", "Sys_getcwd: invalid user buffer");
+        return -1;
+    }
+    let bytes = cwd.as_bytes().iter().chain(core::iter::once(&0u8));
+    for (dst, src) in UserBuffer::new(translated_byte_buffer(token, buf, cwd_len + 1))
+        .into_iter()
+        .zip(bytes)
+    {
+        unsafe {
+            dst.write(*src);
+        }
+    }
+    cwd_len as isize
+}
+
+// read at most `count` entries of the directory `fd` to `buf`,
+// return the number of entries read, 0 at the end, or -1 if fd is not a directory
+pub fn sys_getdents(fd: usize, buf: *mut Dirent, count: usize) -> isize {
+    trace!("kernel #0", "Sys_getdents is called with fd = {}, count = {}", fd, count);
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => {
+            debug!("kernel #0", "Sys_getdents: fd not opened");
+            return -1;
+        }
+    };
+    drop(inner);
+    let len = match count.checked_mul(core::mem::size_of::<Dirent>()) {
+        Some(len) => len,
+        None => return -1,
+    };
+    if !current_prepare_user_buffer(buf as usize, len, true) {
+        debug!("kernel #0", "Sys_getdents: invalid user buffer");
+        return -1;
+    }
+    let dirents = match file.getdents(count) {
+        Some(dirents) => dirents,
+        None => return -1,
+    };
+    // entries may cross pages, copy them byte by byte
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            dirents.as_ptr() as *const u8,
+            dirents.len() * core::mem::size_of::<Dirent>(),
+        )
+    };
+    for (dst, src) in UserBuffer::new(translated_byte_buffer(token, buf as *const u8, len))
+        .into_iter()
+        .zip(bytes)
+    {
+        unsafe {
+            dst.write(*src);
+        }
+    }
+    dirents.len() as isize
+}
+
+// write status of the file `fd` to `st`, return -1 if fd is illegal or not in a file system
+pub fn sys_fstat(fd: usize, st: *mut Stat) -> isize {
+    trace!("kernel #0", "Sys_fstat is called with fd = {}", fd);
+    let token = current_user_token();
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => {
+            debug!("kernel #0", "Sys_fstat: fd not opened");
+            return -1;
+        }
+    };
+    drop(inner);
+    if !current_prepare_user_buffer(st as usize, core::mem::size_of::<Stat>(), true) {
+        debug!("kernel #0", "Sys_fstat: invalid user buffer");
+        return -1;
+    }
+    match file.stat() {
+        Some(stat) => {
+            *translated_refmut(token, st) = stat;
+            0
+        }
+        None => -1,
+    }
+}
+
+// move the offset of file `fd` according to `whence`, return the new offset,
+// or -1 if the file is not seekable or the offset would be negative
+pub fn sys_lseek(fd: usize, offset: isize, whence: usize) -> isize {
+    trace!("kernel #0", "Sys_lseek is called with fd = {}, offset = {}, whence = {}", fd, offset, whence);
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => {
+            debug!("kernel #0", "Sys_lseek: fd not opened");
+            return -1;
+        }
+    };
+    drop(inner);
+    match file.lseek(offset, whence) {
+        Some(offset) => offset as isize,
+        None => -1,
+    }
+}
+
+// get file `fd` for pread (`read` is true) and pwrite of `len` bytes at `offset`,
+// and make the user buffer accessible
+fn positioned_file(fd: usize, buf: usize, len: usize, offset: usize, read: bool) -> Option<Arc<dyn File + Send + Sync>> {
+    let process = current_process();
+    let inner = process.inner_exclusive_access();
+    let file = match inner.fd_table.get(fd) {
+        Some(Some(file)) => file.clone(),
+        _ => return None,
+    };
+    drop(inner);
+    // pipes and terminals have no offsets
+    let permitted = if read { file.readable() } else { file.writable() };
+    if !permitted || !file.seekable() || offset.checked_add(len).is_none() {
+        return None;
+    }
+    if !current_prepare_user_buffer(buf, len, read) {
+        return None;
+    }
+    Some(file)
+}
+
+// read file `fd` from `offset` to buf of length `len`, the file offset is not changed
+pub fn sys_pread(fd: usize, buf: *mut u8, len: usize, offset: usize) -> isize {
+    trace!("kernel #0", "Sys_pread is called with fd = {}, len = {}, offset = {}", fd, len, offset);
+    let token = current_user_token();
+    let file = match positioned_file(fd, buf as usize, len, offset, true) {
+        Some(file) => file,
+        None => {
+            debug!("kernel #0", "Sys_pread: fd not readable at an offset");
+            return -1;
+        }
+    };
+    let mut total_read_size = 0usize;
+    for slice in translated_byte_buffer(token, buf, len) {
+        // an I/O error ends the read, it fails if nothing is read
+        let read_size = match file.read_at(offset + total_read_size, slice) {
+            Some(read_size) => read_size,
+            None if total_read_size == 0 => return -1,
+            None => break,
+        };
+        total_read_size += read_size;
+        if read_size < slice.len() {
+            break;
+        }
+    }
+    total_read_size as isize
+}
+
+// write buf of length `len` to file `fd` from `offset`, the file offset is not changed
+pub fn sys_pwrite(fd: usize, buf: *const u8, len: usize, offset: usize) -> isize {
+    trace!("kernel #0", "Sys_pwrite is called with fd = {}, len = {}, offset = {}", fd, len, offset);
+    let token = current_user_token();
+    let file = match positioned_file(fd, buf as usize, len, offset, false) {
+        Some(file) => file,
+        None => {
+            debug!("kernel #0", "Sys_pwrite: fd not writable at an offset");
+            return -1;
+        }
+    };
+    let mut total_write_size = 0usize;
+    for slice in translated_byte_buffer(token, buf, len) {
+        // an I/O error ends the write, it fails if nothing is written
+        let write_size = match file.write_at(offset + total_write_size, slice) {
+            Some(write_size) => write_size,
+            None if total_write_size == 0 => return -1,
+            None => break,
+        };
+        total_write_size += write_size;
+        if write_size < slice.len() {
+            break;
+        }
+    }
+    total_write_size as isize
+}
+
+// create a hard link `new_path` to `old_path`, only `AT_FDCWD` is supported for dirfds
+// return -1 if `old_path` is not a file or `new_path` exists
+pub fn sys_linkat(old_dirfd: isize, old_path: *const u8, new_dirfd: isize, new_path: *const u8, _flags: u32) -> isize {
+    if old_dirfd != AT_FDCWD || new_dirfd != AT_FDCWD {
+        return -1;
+    }
+    let (old_path, new_path) = match (user_path(old_path), user_path(new_path)) {
+        (Some(old_path), Some(new_path)) => (old_path, new_path),
+        _ => return -1,
+    };
+    trace!("kernel #0", "Sys_linkat is called with old_path = {}, new_path = {}", old_path, new_path);
+    if link(&old_path, &new_path) {
+        0
+    } else {
+        -1
+    }
+}
+
+// remove the link `path`, or the empty directory `path` with `AT_REMOVEDIR`,
+// only `AT_FDCWD` is supported for dirfd. return -1 if `path` is not of the type
+pub fn sys_unlinkat(dirfd: isize, path: *const u8, flags: u32) -> isize {
+    if dirfd != AT_FDCWD {
+        return -1;
+    }
+    let path = match user_path(path) {
+        Some(path) => path,
+        None => return -1,
+    };
+    trace!("kernel #0", "Sys_unlinkat is called with path = {}, flags = {:#x}", path, flags);
+    let removed = if flags & AT_REMOVEDIR != 0 {
+        remove_dir(&path)
+    } else {
+        unlink(&path)
+    };
+    if removed {
+        0
+    } else {
+        -1
+    }
+}
+
+// mount a file system of type `fs_type` on device `source` at directory `target`,
+// flags and data are not supported. return -1 if the type is unknown or `target` is in use
+pub fn sys_mount(source: *const u8, target: *const u8, fs_type: *const u8, _flags: u32, _data: usize) -> isize {
+    // pseudo file systems have no devices
+    let source = if source.is_null() { Some(String::new()) } else { current_user_str(source) };
+    let (source, target, fs_type) = match (source, user_path(target), current_user_str(fs_type)) {
+        (Some(source), Some(target), Some(fs_type)) => (source, target, fs_type),
+        _ => return -1,
+    };
+    trace!("kernel #0", "Sys_mount is called with source = {}, target = {}, fs_type = {}", source, target, fs_type);
+    if mount(&source, &target, &fs_type) {
+        0
+    } else {
+        -1
+    }
+}
+
+// unmount the file system at `target`, flags are not supported.
+// return -1 if it is not a mount point or it is busy
+pub fn sys_umount2(target: *const u8, _flags: u32) -> isize {
+    let target = match user_path(target) {
+        Some(target) => target,
+        None => return -1,
+    };
+    trace!("kernel #0", "Sys_umount2 is called with target = {}", target);
+    if umount(&target) {
+        0
+    } else {
+        -1
+    }
+}
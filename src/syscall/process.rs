@@ -2,11 +2,14 @@ use alloc::string::String;
 use alloc::sync::Arc;
 use alloc::vec::Vec;
 
-use crate::fs::{open_file, OpenFlags};
-use crate::mm::{translate_ref, translate_to_str, translated_refmut};
-use crate::timer::get_time_ms;
-use crate::task::{add_task, current_task, current_user_token, exit_current_and_run_next, pid2task, suspend_and_run_next, SignalAction, MAX_SIG};
-use crate::task::SignalFlags;
+use crate::fs::read_file;
+use super::fs::user_path;
+use crate::config::{PAGE_SIZE, USER_STACK_BASE};
+use crate::mm::{translated_refmut, MapFile, MapPermission, VirtAddr};
+use crate::timer::{add_timer, get_time_ms, TimeSpec};
+use crate::sync::{irq_restore, irq_save};
+use crate::task::{block_current_and_run_next, current_prepare_user_buffer, current_process, current_user_ref, current_user_str, prepare_block_current, current_task, current_trap_cx, current_user_token, exit_current_and_run_next, pid2process, remove_from_pid2process, suspend_and_run_next, SignalAction, MAX_SIG};
+use crate::task::{SignalFlags, MIN_PRIORITY};
 
 // task exit and submit an exit code
 pub fn sys_exit(exit_code: i32) -> ! {
@@ -26,43 +29,275 @@ pub fn sys_get_time() -> isize{
     get_time_ms() as isize
 }
 
+// block current thread for `ms` milliseconds, always return 0
+pub fn sys_sleep(ms: usize) -> isize {
+    trace!("kernel #0", "sys_sleep is called with ms = {}", ms);
+    // sleeps too long to end are endless
+    let expire_ms = get_time_ms().saturating_add(ms);
+    let sie = irq_save();
+    add_timer(expire_ms, prepare_block_current());
+    block_current_and_run_next();
+    irq_restore(sie);
+    0
+}
+
+// sleep for the interval in `req`, the remaining time is written to `rem` if it is not null
+// return -1 if the interval is illegal
+pub fn sys_nanosleep(req: *const TimeSpec, rem: *mut TimeSpec) -> isize {
+    trace!("kernel #0", "sys_nanosleep is called");
+    let token = current_user_token();
+    let req = match current_user_ref(req) {
+        Some(req) => req,
+        None => return -1,
+    };
+    if req.tv_nsec >= 1_000_000_000 {
+        return -1;
+    }
+    sys_sleep(req.to_ms());
+    // sleeping is never interrupted, so no time remains
+    if !rem.is_null() {
+        if !current_prepare_user_buffer(rem as usize, core::mem::size_of::<TimeSpec>(), true) {
+            return -1;
+        }
+        *translated_refmut(token, rem) = TimeSpec { tv_sec: 0, tv_nsec: 0 };
+    }
+    0
+}
+
+// set priority of current thread used by stride scheduler, return the priority
+// return -1 if it is less than 2
+pub fn sys_set_priority(prio: isize) -> isize {
+    trace!("kernel #0", "sys_set_priority is called with prio = {}", prio);
+    if prio < MIN_PRIORITY as isize {
+        return -1;
+    }
+    current_task().unwrap().inner_exclusive_access().priority = prio as usize;
+    prio
+}
+
 // get pid
 pub fn sys_getpid() -> isize {
-    current_task().unwrap().pid.0 as isize
+    current_process().getpid() as isize
+}
+
+// move process `pid` to group `pgid`, 0 for `pid` means current process,
+// 0 for `pgid` means the group with id `pid`. return -1 if the process doesn't exist
+pub fn sys_setpgid(pid: usize, pgid: usize) -> isize {
+    trace!("kernel #0", "sys_setpgid is called with pid = {}, pgid = {}", pid, pgid);
+    let process = if pid == 0 {
+        current_process()
+    } else {
+        match pid2process(pid) {
+            Some(process) => process,
+            None => return -1,
+        }
+    };
+    let pgid = if pgid == 0 { process.getpid() } else { pgid };
+    process.inner_exclusive_access().pgid = pgid;
+    0
+}
+
+// get the group of process `pid`, 0 means current process. return -1 if it doesn't exist
+pub fn sys_getpgid(pid: usize) -> isize {
+    let process = if pid == 0 {
+        current_process()
+    } else {
+        match pid2process(pid) {
+            Some(process) => process,
+            None => return -1,
+        }
+    };
+    let pgid = process.inner_exclusive_access().pgid;
+    pgid as isize
+}
+
+// set the program break to `addr`, return the new break,
+// or the current break if `addr` is 0 or illegal
+pub fn sys_brk(addr: usize) -> isize {
+    trace!("kernel #0", "sys_brk is called with addr = {:#x}", addr);
+    let process = current_process();
+    let old_brk = process.inner_exclusive_access().program_brk;
+    if addr == 0 {
+        return old_brk as isize;
+    }
+    let size = (addr as isize).checked_sub(old_brk as isize);
+    if size.and_then(|size| process.change_program_brk(size)).is_some() {
+        addr as isize
+    } else {
+        old_brk as isize
+    }
+}
+
+// change the program break by `size`, return the old break or -1 if failed
+pub fn sys_sbrk(size: i32) -> isize {
+    trace!("kernel #0", "sys_sbrk is called with size = {}", size);
+    if let Some(old_brk) = current_process().change_program_brk(size as isize) {
+        old_brk as isize
+    } else {
+        -1
+    }
+}
+
+const PROT_READ: usize = 1 << 0;
+const PROT_WRITE: usize = 1 << 1;
+const PROT_EXEC: usize = 1 << 2;
+const MAP_SHARED: usize = 0x01;
+const MAP_PRIVATE: usize = 0x02;
+const MAP_FIXED: usize = 0x10;
+const MAP_ANONYMOUS: usize = 0x20;
+
+// map `len` bytes at `start` (chosen by kernel if 0), return the start address or -1.
+// a given `start` is always used as it is, with or without MAP_FIXED
+pub fn sys_mmap(start: usize, len: usize, prot: usize, flags: usize, fd: usize, offset: usize) -> isize {
+    trace!("kernel #0", "sys_mmap is called with start = {:#x}, len = {}, prot = {:#x}, flags = {:#x}",
+            start, len, prot, flags);
+    if len == 0
+        || start % PAGE_SIZE != 0
+        || offset % PAGE_SIZE != 0
+        || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0
+        || prot == 0
+        || (start == 0 && flags & MAP_FIXED != 0)
+    {
+        debug!("kernel #0", "sys_mmap: illegal arguments");
+        return -1;
+    }
+    let shared = match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_SHARED => true,
+        MAP_PRIVATE => false,
+        _ => {
+            debug!("kernel #0", "sys_mmap: either MAP_SHARED or MAP_PRIVATE should be set");
+            return -1;
+        }
+    };
+    let mut map_perm = MapPermission::U;
+    if prot & PROT_READ != 0 {
+        map_perm |= MapPermission::R;
+    }
+    if prot & PROT_WRITE != 0 {
+        // writable page must be readable in riscv
+        map_perm |= MapPermission::R | MapPermission::W;
+    }
+    if prot & PROT_EXEC != 0 {
+        map_perm |= MapPermission::X;
+    }
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let map_file = if flags & MAP_ANONYMOUS != 0 {
+        if shared {
+            debug!("kernel #0", "sys_mmap: shared anonymous mapping is not supported");
+            return -1;
+        }
+        None
+    } else {
+        let file = match inner.fd_table.get(fd) {
+            Some(Some(file)) => file.clone(),
+            _ => {
+                debug!("kernel #0", "sys_mmap: fd not opened");
+                return -1;
+            }
+        };
+        if !file.seekable() || !file.readable() || (shared && map_perm.contains(MapPermission::W) && !file.writable()) {
+            debug!("kernel #0", "sys_mmap: file can't be mapped with such permission");
+            return -1;
+        }
+        Some(MapFile { file, offset, len, shared })
+    };
+    let start = if start == 0 {
+        match inner.memory_set.find_free_area(len) {
+            Some(start_va) => start_va.0,
+            None => return -1,
+        }
+    } else {
+        start
+    };
+    let end = match start.checked_add(len) {
+        Some(end) => end,
+        None => return -1,
+    };
+    if inner.memory_set.insert_mmap_area(start, end, map_perm, map_file) {
+        start as isize
+    } else {
+        debug!("kernel #0", "sys_mmap: overlap with existed areas or user stacks");
+        -1
+    }
+}
+
+// unmap the area mapped by mmap at [start, start + len)
+pub fn sys_munmap(start: usize, len: usize) -> isize {
+    trace!("kernel #0", "sys_munmap is called with start = {:#x}, len = {}", start, len);
+    // user stacks and trap contexts can't be unmapped
+    let end = match start.checked_add(len) {
+        Some(end) if start % PAGE_SIZE == 0 && end <= USER_STACK_BASE => end,
+        _ => return -1,
+    };
+    let process = current_process();
+    let mut inner = process.inner_exclusive_access();
+    let area = inner
+        .memory_set
+        .remove_mmap_area(VirtAddr::from(start), VirtAddr::from(end));
+    drop(inner);
+    match area {
+        Some(area) => {
+            // the file may sleep, it is written with the process unlocked
+            if let Some(write_back) = area.write_back() {
+                write_back.write();
+            }
+            0
+        }
+        None => -1,
+    }
 }
 
 pub fn sys_fork() -> isize {
-    let current_task = current_task().unwrap();
-    let new_task = current_task.fork();
-    let new_pid = new_task.pid.0;
-    // modify trap context of new_task, so it cna return 0 in child process
-    let trap_cx = new_task.inner_exclusive_access().get_trap_cx();
+    let current_process = current_process();
+    if current_process.inner_exclusive_access().thread_count() != 1 {
+        debug!("kernel #0", "sys_fork: only processes with a single thread can fork");
+        return -1;
+    }
+    let new_process = current_process.fork();
+    let new_pid = new_process.getpid();
+    // modify trap context of the main thread of new_process, so it can return 0 in child process
+    let new_process_inner = new_process.inner_exclusive_access();
+    let task = new_process_inner.tasks[0].as_ref().unwrap();
+    let trap_cx = task.inner_exclusive_access().get_trap_cx();
     // return code store in x10
     trap_cx.x[10] = 0;
-    // add new task to scheduler
-    add_task(new_task);
     new_pid as isize
 }
 
 pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
-    let token = current_user_token();
-    let path = translate_to_str(token, path);
+    let path = match user_path(path) {
+        Some(path) => path,
+        None => return -1,
+    };
     let mut arg_vec: Vec<String> = Vec::new();
     loop {
-        let arg_str_ptr = *translate_ref(token, args);
+        let arg_str_ptr = match current_user_ref(args) {
+            Some(arg_str_ptr) => arg_str_ptr,
+            None => return -1,
+        };
         if arg_str_ptr == 0 {
             break;
         }
-        arg_vec.push(translate_to_str(token, arg_str_ptr as *const u8));
+        match current_user_str(arg_str_ptr as *const u8) {
+            Some(arg) => arg_vec.push(arg),
+            None => return -1,
+        }
         unsafe {
             args = args.add(1);
         }
     }
-    if let Some(app_inode) = open_file(path.as_str(), OpenFlags::RDONLY) {
-        let all_data = app_inode.read_all();
-        let task = current_task().unwrap();
+    let process = current_process();
+    if process.inner_exclusive_access().thread_count() != 1 {
+        debug!("kernel #0", "sys_exec: only processes with a single thread can exec");
+        return -1;
+    }
+    if let Some(all_data) = read_file(path.as_str()) {
         let argc = arg_vec.len();
-        task.exec(all_data.as_slice(), arg_vec);
+        if !process.exec(all_data.as_slice(), arg_vec) {
+            debug!("kernel #0", "Sys_exec: arguments or the program don't fit in the address space");
+            return -1;
+        }
         argc as isize
     } else {
         -1
@@ -72,10 +307,12 @@ pub fn sys_exec(path: *const u8, mut args: *const usize) -> isize {
 // return -1, if there's no child process has id equal to pid
 // return -2, if the child process is still running
 pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
-    let task = current_task().unwrap();
+    let process = current_process();
+    // the buffer is prepared with the process unlocked, it is checked when a child is found
+    let prepared = current_prepare_user_buffer(exit_code_ptr as usize, core::mem::size_of::<i32>(), true);
 
-    // access current TaskControlBlock
-    let mut inner = task.inner_exclusive_access();
+    // access current ProcessControlBlock
+    let mut inner = process.inner_exclusive_access();
     // find child process
     if !inner
         .children
@@ -83,21 +320,29 @@ pub fn sys_waitpid(pid: isize, exit_code_ptr: *mut i32) -> isize {
         .any(|p| pid == -1 || pid as usize == p.getpid())
     {
         return -1;
-        // release current TaskControlBlock
+        // release current ProcessControlBlock
     }
     let pair = inner.children.iter().enumerate().find(|(_, p)|
-        // temporarily access child TaskControlBlock
-        { p.inner_exclusive_access().is_zombie() && (pid == -1 || pid as usize == p.getpid()) }
-        // release child TaskControlBlock
+        // temporarily access child ProcessControlBlock
+        { p.inner_exclusive_access().is_zombie && (pid == -1 || pid as usize == p.getpid()) }
+        // release child ProcessControlBlock
     );
     if let Some((idx, _)) = pair {
+        // the child is kept if its exit code can't be written
+        if !prepared {
+            return -1;
+        }
         // remove the process
         let child = inner.children.remove(idx);
-        assert_eq!(Arc::strong_count(&child), 1);
+        // a reader of procfs may hold it for a moment, then it is freed when that is done
+        if Arc::strong_count(&child) > 1 {
+            debug!("kernel #0", "sys_waitpid: process {} is still referenced", child.getpid());
+        }
         let found_pid = child.getpid();
-        // temporarily access child TaskControlBlock
+        remove_from_pid2process(found_pid);
+        // temporarily access child ProcessControlBlock
         let exit_code = child.inner_exclusive_access().exit_code;
-        // release child TaskControlBlock
+        // release child ProcessControlBlock
         *translated_refmut(inner.memory_set.token(), exit_code_ptr) = exit_code;
         found_pid as isize
     } else {
@@ -117,8 +362,9 @@ pub fn sys_shutdown(failure: usize) -> ! {
 }
 
 pub fn sys_sigprocmask(mask: u32) -> isize {
-    if let Some(task) = current_task() {
-        let mut inner = task.inner_exclusive_access();
+    if current_task().is_some() {
+        let process = current_process();
+        let mut inner = process.inner_exclusive_access();
         let old_mask = inner.signal_mask;
         if let Some(flag) = SignalFlags::from_bits(mask) {
             inner.signal_mask = flag;
@@ -150,8 +396,7 @@ pub fn sys_sigaction(
     old_action: *mut SignalAction,
 ) -> isize {
     let token = current_user_token();
-    let task = current_task().unwrap();
-    let mut inner = task.inner_exclusive_access();
+    let process = current_process();
     if signum as usize > MAX_SIG {
         return -1;
     }
@@ -159,9 +404,17 @@ pub fn sys_sigaction(
         if check_sigaction_error(flag, action as usize, old_action as usize) {
             return -1;
         }
+        let action = match current_user_ref(action) {
+            Some(action) => action,
+            None => return -1,
+        };
+        if !current_prepare_user_buffer(old_action as usize, core::mem::size_of::<SignalAction>(), true) {
+            return -1;
+        }
+        let mut inner = process.inner_exclusive_access();
         let prev_action = inner.signal_actions.table[signum as usize];
         *translated_refmut(token, old_action) = prev_action;
-        inner.signal_actions.table[signum as usize] = *translate_ref(token, action);
+        inner.signal_actions.table[signum as usize] = action;
         0
     } else {
         -1
@@ -169,14 +422,14 @@ pub fn sys_sigaction(
 }
 
 pub fn sys_kill(pid: usize, signum: i32) -> isize {
-    if let Some(task) = pid2task(pid) {
+    if let Some(process) = pid2process(pid) {
         if let Some(flag) = SignalFlags::from_bits(1 << signum) {
             // insert the signal if legal
-            let mut task_ref = task.inner_exclusive_access();
-            if task_ref.signals.contains(flag) {
+            let mut process_ref = process.inner_exclusive_access();
+            if process_ref.signals.contains(flag) {
                 return -1;
             }
-            task_ref.signals.insert(flag);
+            process_ref.signals.insert(flag);
             0
         } else {
             -1
@@ -187,11 +440,12 @@ pub fn sys_kill(pid: usize, signum: i32) -> isize {
 }
 
 pub fn sys_sigreturn() -> isize {
-    if let Some(task) = current_task() {
-        let mut inner = task.inner_exclusive_access();
+    if current_task().is_some() {
+        let process = current_process();
+        let mut inner = process.inner_exclusive_access();
         inner.handling_sig = -1;
-        // restore trap context
-        let trap_cx = inner.get_trap_cx();
+        // restore trap context of current thread
+        let trap_cx = current_trap_cx();
         *trap_cx = inner.trap_ctx_backup.unwrap();
         trap_cx.x[10] as isize
     } else {
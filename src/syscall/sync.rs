@@ -0,0 +1,200 @@
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+use crate::sync::{Condvar, Mutex, MutexBlocking, MutexSpin, Semaphore};
+use crate::task::{current_process, current_task};
+
+// returned when the request would lead to a deadlock
+const EDEADLK: isize = -0xDEAD;
+
+fn current_tid() -> usize {
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .res
+        .as_ref()
+        .unwrap()
+        .tid
+}
+
+// put `item` into the first empty slot of `list`, return its id
+fn insert_into_list<T: ?Sized>(list: &mut Vec<Option<Arc<T>>>, item: Arc<T>) -> usize {
+    if let Some(id) = (0..list.len()).find(|id| list[*id].is_none()) {
+        list[id] = Some(item);
+        id
+    } else {
+        list.push(Some(item));
+        list.len() - 1
+    }
+}
+
+// create a mutex in current process, return its id
+pub fn sys_mutex_create(blocking: bool) -> isize {
+    trace!("kernel #0", "sys_mutex_create is called with blocking = {}", blocking);
+    let mutex: Arc<dyn Mutex> = if blocking {
+        Arc::new(MutexBlocking::new())
+    } else {
+        Arc::new(MutexSpin::new())
+    };
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let id = insert_into_list(&mut process_inner.mutex_list, mutex);
+    process_inner.mutex_tracker.add_resource(id, 1);
+    id as isize
+}
+
+// return -1 if the mutex doesn't exist
+// return -0xDEAD if deadlock detection is enabled and locking it would cause a deadlock
+pub fn sys_mutex_lock(mutex_id: usize) -> isize {
+    trace!("kernel #0", "sys_mutex_lock is called with mutex_id = {}", mutex_id);
+    let tid = current_tid();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let mutex = match process_inner.mutex_list.get(mutex_id) {
+        Some(Some(mutex)) => Arc::clone(mutex),
+        _ => return -1,
+    };
+    let check = process_inner.deadlock_detect;
+    if !process_inner.mutex_tracker.request(tid, mutex_id, check) {
+        debug!("kernel #0", "sys_mutex_lock: deadlock detected on mutex {}", mutex_id);
+        return EDEADLK;
+    }
+    // the thread may be blocked, release the process first
+    drop(process_inner);
+    mutex.lock();
+    process.inner_exclusive_access().mutex_tracker.acquire(tid, mutex_id);
+    0
+}
+
+// return -1 if the mutex doesn't exist or it is not locked
+pub fn sys_mutex_unlock(mutex_id: usize) -> isize {
+    trace!("kernel #0", "sys_mutex_unlock is called with mutex_id = {}", mutex_id);
+    let tid = current_tid();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let mutex = match process_inner.mutex_list.get(mutex_id) {
+        Some(Some(mutex)) => Arc::clone(mutex),
+        _ => return -1,
+    };
+    // the process is held, so the woken thread acquires it in the tracker after the release
+    if !mutex.unlock() {
+        return -1;
+    }
+    process_inner.mutex_tracker.release(tid, mutex_id);
+    0
+}
+
+// create a semaphore with `res_count` resources in current process, return its id
+pub fn sys_semaphore_create(res_count: usize) -> isize {
+    trace!("kernel #0", "sys_semaphore_create is called with res_count = {}", res_count);
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let semaphore = Arc::new(Semaphore::new(res_count));
+    let id = insert_into_list(&mut process_inner.semaphore_list, semaphore);
+    process_inner.semaphore_tracker.add_resource(id, res_count);
+    id as isize
+}
+
+// return -1 if the semaphore doesn't exist
+pub fn sys_semaphore_up(sem_id: usize) -> isize {
+    trace!("kernel #0", "sys_semaphore_up is called with sem_id = {}", sem_id);
+    let tid = current_tid();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let sem = match process_inner.semaphore_list.get(sem_id) {
+        Some(Some(sem)) => Arc::clone(sem),
+        _ => return -1,
+    };
+    process_inner.semaphore_tracker.release(tid, sem_id);
+    drop(process_inner);
+    sem.up();
+    0
+}
+
+// return -1 if the semaphore doesn't exist
+// return -0xDEAD if deadlock detection is enabled and waiting on it would cause a deadlock
+pub fn sys_semaphore_down(sem_id: usize) -> isize {
+    trace!("kernel #0", "sys_semaphore_down is called with sem_id = {}", sem_id);
+    let tid = current_tid();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let sem = match process_inner.semaphore_list.get(sem_id) {
+        Some(Some(sem)) => Arc::clone(sem),
+        _ => return -1,
+    };
+    let check = process_inner.deadlock_detect;
+    if !process_inner.semaphore_tracker.request(tid, sem_id, check) {
+        debug!("kernel #0", "sys_semaphore_down: deadlock detected on semaphore {}", sem_id);
+        return EDEADLK;
+    }
+    // the thread may be blocked, release the process first
+    drop(process_inner);
+    sem.down();
+    process.inner_exclusive_access().semaphore_tracker.acquire(tid, sem_id);
+    0
+}
+
+// create a condition variable in current process, return its id
+pub fn sys_condvar_create() -> isize {
+    trace!("kernel #0", "sys_condvar_create is called");
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let condvar = Arc::new(Condvar::new());
+    insert_into_list(&mut process_inner.condvar_list, condvar) as isize
+}
+
+// return -1 if the condition variable doesn't exist
+pub fn sys_condvar_signal(condvar_id: usize) -> isize {
+    trace!("kernel #0", "sys_condvar_signal is called with condvar_id = {}", condvar_id);
+    let process = current_process();
+    let process_inner = process.inner_exclusive_access();
+    let condvar = match process_inner.condvar_list.get(condvar_id) {
+        Some(Some(condvar)) => Arc::clone(condvar),
+        _ => return -1,
+    };
+    drop(process_inner);
+    condvar.signal();
+    0
+}
+
+// return -1 if the condition variable or the mutex doesn't exist
+pub fn sys_condvar_wait(condvar_id: usize, mutex_id: usize) -> isize {
+    trace!(
+        "kernel #0",
+        "sys_condvar_wait is called with condvar_id = {}, mutex_id = {}",
+        condvar_id,
+        mutex_id
+    );
+    let tid = current_tid();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    let condvar = match process_inner.condvar_list.get(condvar_id) {
+        Some(Some(condvar)) => Arc::clone(condvar),
+        _ => return -1,
+    };
+    let mutex = match process_inner.mutex_list.get(mutex_id) {
+        Some(Some(mutex)) => Arc::clone(mutex),
+        _ => return -1,
+    };
+    // the mutex is released during waiting and held again after waking up
+    process_inner.mutex_tracker.release(tid, mutex_id);
+    // the thread will be blocked, release the process first
+    drop(process_inner);
+    condvar.wait(mutex);
+    process.inner_exclusive_access().mutex_tracker.acquire(tid, mutex_id);
+    0
+}
+
+// enable deadlock detection of current process if `enabled` is 1, disable it if 0
+// return -1 for other values
+pub fn sys_enable_deadlock_detect(enabled: usize) -> isize {
+    trace!("kernel #0", "sys_enable_deadlock_detect is called with enabled = {}", enabled);
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    match enabled {
+        0 => process_inner.deadlock_detect = false,
+        1 => process_inner.deadlock_detect = true,
+        _ => return -1,
+    }
+    0
+}
@@ -0,0 +1,87 @@
+use alloc::sync::Arc;
+
+use crate::mm::kernel_token;
+use crate::task::{add_task, current_task, TaskControlBlock};
+use crate::trap::{trap_handler, TrapContext};
+
+// create a thread running `entry(arg)` in current process, return its tid
+pub fn sys_thread_create(entry: usize, arg: usize) -> isize {
+    trace!("kernel #0", "sys_thread_create is called with entry = {:#x}, arg = {:#x}", entry, arg);
+    let task = current_task().unwrap();
+    let process = task.process.upgrade().unwrap();
+    // create a new thread with its own user stack and trap context
+    let new_task = match TaskControlBlock::new(Arc::clone(&process), true) {
+        Some(task) => Arc::new(task),
+        None => {
+            debug!("kernel #0", "sys_thread_create: user stack overlaps with existed areas");
+            return -1;
+        }
+    };
+    let new_task_inner = new_task.inner_exclusive_access();
+    let new_task_res = new_task_inner.res.as_ref().unwrap();
+    let new_task_tid = new_task_res.tid;
+    // add new thread to current process
+    let mut process_inner = process.inner_exclusive_access();
+    let tasks = &mut process_inner.tasks;
+    while tasks.len() < new_task_tid + 1 {
+        tasks.push(None);
+    }
+    tasks[new_task_tid] = Some(Arc::clone(&new_task));
+    drop(process_inner);
+    // prepare trap context of new thread
+    let new_task_trap_cx = new_task_inner.get_trap_cx();
+    *new_task_trap_cx = TrapContext::app_init_context(
+        entry,
+        new_task_res.ustack_top(),
+        kernel_token(),
+        new_task.kernel_stack.get_top(),
+        trap_handler as usize,
+    );
+    // put arg(a0)
+    new_task_trap_cx.x[10] = arg;
+    drop(new_task_inner);
+    // add new thread to scheduler
+    add_task(Arc::clone(&new_task));
+    new_task_tid as isize
+}
+
+// get tid of current thread
+pub fn sys_gettid() -> isize {
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .res
+        .as_ref()
+        .unwrap()
+        .tid as isize
+}
+
+// return -1, if the thread doesn't exist or it is current thread
+// return -2, if the thread is still running
+// otherwise, return the exit code of the thread
+pub fn sys_waittid(tid: usize) -> i32 {
+    let task = current_task().unwrap();
+    let process = task.process.upgrade().unwrap();
+    let current_tid = task.inner_exclusive_access().res.as_ref().unwrap().tid;
+    // a thread cannot wait for itself
+    if current_tid == tid {
+        return -1;
+    }
+    // don't hold current thread while accessing the process, the process may be exiting
+    // and accessing its threads on other harts
+    let mut process_inner = process.inner_exclusive_access();
+    let exit_code = match process_inner.tasks.get(tid) {
+        Some(Some(waited_task)) => waited_task.inner_exclusive_access().exit_code,
+        _ => return -1,
+    };
+    if let Some(exit_code) = exit_code {
+        // the kernel stack of the thread is released here
+        process_inner.tasks[tid] = None;
+        // the tid may be reused, forget its records for deadlock detection
+        process_inner.mutex_tracker.remove_thread(tid);
+        process_inner.semaphore_tracker.remove_thread(tid);
+        exit_code
+    } else {
+        -2
+    }
+}
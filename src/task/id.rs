@@ -0,0 +1,225 @@
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use lazy_static::*;
+use crate::mm::{KERNEL_SPACE, MapPermission, MemorySet, PhysPageNum, VirtAddr};
+use crate::sync::SpinNoIrqLock;
+use crate::config::{TRAMPOLINE, PAGE_SIZE, KERNEL_STACK_SIZE, TRAP_CONTEXT_BASE, USER_STACK_BASE, USER_STACK_SIZE};
+use super::process::ProcessControlBlock;
+
+// Allocator for ids which can be recycled, used by pid, kernel stack and tid
+pub struct RecycleAllocator {
+    current: usize,
+    recycled: Vec<usize>,
+}
+
+impl RecycleAllocator {
+    // Create a new RecycleAllocator
+    pub fn new() -> Self {
+        RecycleAllocator {
+            current: 0,
+            recycled: Vec::new(),
+        }
+    }
+    // Allocate an id
+    pub fn alloc(&mut self) -> usize {
+        if let Some(id) = self.recycled.pop() {
+            id
+        } else {
+            self.current += 1;
+            self.current - 1
+        }
+    }
+    // Recycle an id
+    pub fn dealloc(&mut self, id: usize) {
+        assert!(id < self.current);
+        assert!(
+            !self.recycled.contains(&id),
+            "id {} has already been deallocated!",
+            id
+        );
+        self.recycled.push(id);
+    }
+}
+
+// bind pid lifetime to PidHandler
+// when pidhandler is dropped, pid will be deallocated automatically
+pub struct PidHandler(pub usize);
+
+// Create global instances of id allocators
+lazy_static! {
+    pub static ref PID_ALLOCATOR: SpinNoIrqLock<RecycleAllocator> = 
+        SpinNoIrqLock::new(RecycleAllocator::new());
+    pub static ref KSTACK_ALLOCATOR: SpinNoIrqLock<RecycleAllocator> = 
+        SpinNoIrqLock::new(RecycleAllocator::new());
+}
+
+impl Drop for PidHandler {
+    fn drop(&mut self) {
+        trace!("kernel #0", "drop pid {}", self.0);
+        PID_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+pub fn pid_alloc() -> PidHandler {
+    PidHandler(PID_ALLOCATOR.lock().alloc())
+}
+
+pub fn kernel_stack_position(kstack_id: usize) -> (usize, usize) {
+    let top = TRAMPOLINE - kstack_id * (KERNEL_STACK_SIZE + PAGE_SIZE);
+    let bottom = top - KERNEL_STACK_SIZE;
+    (bottom, top)
+}
+
+// Kernel stack of a thread
+pub struct KernelStack(pub usize);
+
+// Allocate a kernel stack in kernel space
+pub fn kstack_alloc() -> KernelStack {
+    let kstack_id = KSTACK_ALLOCATOR.lock().alloc();
+    let (kernel_stack_bottom, kernel_stack_top) = kernel_stack_position(kstack_id);
+    KERNEL_SPACE.lock().insert_framed_area(
+        kernel_stack_bottom.into(),
+        kernel_stack_top.into(),
+        MapPermission::R | MapPermission::W,
+    );
+    KernelStack(kstack_id)
+}
+
+impl KernelStack {
+    #[allow(unused)]
+    // Push a value on top of kernel stack
+    pub fn push_on_top<T>(&self, value: T) -> *mut T
+    where
+        T: Sized,
+    {
+        let kernel_stack_top = self.get_top();
+        let ptr_mut = (kernel_stack_top - core::mem::size_of::<T>()) as *mut T;
+        unsafe {
+            *ptr_mut = value;
+        }
+        ptr_mut
+    }
+    // Get the address of the top of kernel stack
+    pub fn get_top(&self) -> usize {
+        let (_, kernel_stack_top) = kernel_stack_position(self.0);
+        kernel_stack_top
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let (kernel_stack_bottom, _) = kernel_stack_position(self.0);
+        let kernel_stack_bottom_va: VirtAddr = kernel_stack_bottom.into();
+        KERNEL_SPACE
+            .lock()
+            .remove_area_with_start_vpn(kernel_stack_bottom_va.into());
+        KSTACK_ALLOCATOR.lock().dealloc(self.0);
+    }
+}
+
+// the bottom of trap context of thread `tid` in user space
+fn trap_cx_bottom_from_tid(tid: usize) -> usize {
+    TRAP_CONTEXT_BASE - tid * PAGE_SIZE
+}
+
+// the bottom of user stack of thread `tid`, there is a guard page between stacks
+fn ustack_bottom_from_tid(tid: usize) -> usize {
+    USER_STACK_BASE + tid * (PAGE_SIZE + USER_STACK_SIZE)
+}
+
+// map user stack and trap context of thread `tid` in `memory_set`,
+// return false if they overlap with existed areas
+fn map_user_res(memory_set: &mut MemorySet, tid: usize) -> bool {
+    let ustack_bottom = ustack_bottom_from_tid(tid);
+    let ustack_top = ustack_bottom + USER_STACK_SIZE;
+    let trap_cx_bottom = trap_cx_bottom_from_tid(tid);
+    let trap_cx_top = trap_cx_bottom + PAGE_SIZE;
+    if ustack_top > trap_cx_bottom
+        || memory_set.is_overlapped(VirtAddr::from(ustack_bottom).floor(), VirtAddr::from(ustack_top).ceil())
+        || memory_set.is_overlapped(VirtAddr::from(trap_cx_bottom).floor(), VirtAddr::from(trap_cx_top).ceil())
+    {
+        return false;
+    }
+    // user stack is mapped lazily
+    memory_set.insert_lazy_area(
+        ustack_bottom.into(),
+        ustack_top.into(),
+        MapPermission::R | MapPermission::W | MapPermission::U,
+    );
+    // trap context is written by kernel directly, so it can't be lazy
+    memory_set.insert_framed_area(
+        trap_cx_bottom.into(),
+        trap_cx_top.into(),
+        MapPermission::R | MapPermission::W,
+    );
+    true
+}
+
+// Resources of a thread in user space: tid, user stack and trap context
+// they are released when it is dropped
+pub struct TaskUserRes {
+    pub tid: usize,
+    pub process: Weak<ProcessControlBlock>,
+}
+
+impl TaskUserRes {
+    // Allocate a tid, and map user stack and trap context if `alloc_user_res`.
+    // return None if they overlap with existed areas
+    pub fn new(process: Arc<ProcessControlBlock>, alloc_user_res: bool) -> Option<Self> {
+        let mut process_inner = process.inner_exclusive_access();
+        let tid = process_inner.alloc_tid();
+        if alloc_user_res && !map_user_res(&mut process_inner.memory_set, tid) {
+            process_inner.dealloc_tid(tid);
+            return None;
+        }
+        Some(Self {
+            tid,
+            process: Arc::downgrade(&process),
+        })
+    }
+    // Map user stack and trap context in `memory_set`, which will be the address space
+    // of process. return false if they overlap with existed areas
+    pub fn alloc_user_res(&self, memory_set: &mut MemorySet) -> bool {
+        map_user_res(memory_set, self.tid)
+    }
+    // Unmap user stack and trap context
+    fn dealloc_user_res(&self) {
+        let process = self.process.upgrade().unwrap();
+        let mut process_inner = process.inner_exclusive_access();
+        let ustack_bottom_va: VirtAddr = ustack_bottom_from_tid(self.tid).into();
+        process_inner
+            .memory_set
+            .remove_area_with_start_vpn(ustack_bottom_va.into());
+        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.tid).into();
+        process_inner
+            .memory_set
+            .remove_area_with_start_vpn(trap_cx_bottom_va.into());
+    }
+    // Get the physical page number of trap context
+    pub fn trap_cx_ppn(&self) -> PhysPageNum {
+        let process = self.process.upgrade().unwrap();
+        let process_inner = process.inner_exclusive_access();
+        let trap_cx_bottom_va: VirtAddr = trap_cx_bottom_from_tid(self.tid).into();
+        process_inner
+            .memory_set
+            .translate(trap_cx_bottom_va.into())
+            .unwrap()
+            .ppn()
+    }
+    // Get the address of trap context in user space
+    pub fn trap_cx_user_va(&self) -> usize {
+        trap_cx_bottom_from_tid(self.tid)
+    }
+    // Get the top of user stack
+    pub fn ustack_top(&self) -> usize {
+        ustack_bottom_from_tid(self.tid) + USER_STACK_SIZE
+    }
+}
+
+impl Drop for TaskUserRes {
+    fn drop(&mut self) {
+        self.dealloc_user_res();
+        let process = self.process.upgrade().unwrap();
+        process.inner_exclusive_access().dealloc_tid(self.tid);
+    }
+}
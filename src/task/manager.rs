@@ -1,60 +1,306 @@
-use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc};
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, vec::Vec};
 use lazy_static::*;
 
-use super::task::TaskControlBlock;
-use crate::sync::UPSafeCell;
+use super::process::ProcessControlBlock;
+use super::task::{TaskControlBlock, TaskControlBlockInner, TaskStatus, BIG_STRIDE};
+use crate::sync::SpinNoIrqLock;
+use crate::timer::get_time_ms;
 
-pub struct TaskManager {
-    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+// Scheduling policy, which decides the order of ready tasks
+pub trait Scheduler {
+    // Add a ready task
+    fn add(&mut self, task: Arc<TaskControlBlock>);
+    // Remove the next task to run and return it, return None if there is no ready task
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>>;
+    // Remove a task from ready tasks if it exists
+    fn remove(&mut self, task: Arc<TaskControlBlock>);
+    // Add a task woken up after blocking
+    fn wakeup(&mut self, task: Arc<TaskControlBlock>) {
+        self.add(task);
+    }
+    // Called on every timer interrupt
+    fn tick(&mut self) {}
+    // Timer ticks a task can run before it is preempted
+    fn time_slice(&self, _task: &TaskControlBlockInner) -> usize {
+        1
+    }
+}
+
+// find `task` in `queue` by address
+fn position_in(queue: &VecDeque<Arc<TaskControlBlock>>, task: &Arc<TaskControlBlock>) -> Option<usize> {
+    queue
+        .iter()
+        .position(|t| Arc::as_ptr(t) == Arc::as_ptr(task))
 }
 
 // A simple FIFO scheduler
+#[allow(unused)]
+pub struct RoundRobinScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+#[allow(unused)]
+impl RoundRobinScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for RoundRobinScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.ready_queue.pop_front()
+    }
+    fn remove(&mut self, task: Arc<TaskControlBlock>) {
+        if let Some(id) = position_in(&self.ready_queue, &task) {
+            self.ready_queue.remove(id);
+        }
+    }
+}
+
+// Stride scheduler, the task with the smallest pass runs next,
+// and its pass grows by `BIG_STRIDE / priority`
+#[allow(unused)]
+pub struct StrideScheduler {
+    ready_queue: VecDeque<Arc<TaskControlBlock>>,
+}
+
+#[allow(unused)]
+impl StrideScheduler {
+    pub fn new() -> Self {
+        Self {
+            ready_queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Scheduler for StrideScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        self.ready_queue.push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        let mut min: Option<(usize, u64)> = None;
+        for (id, task) in self.ready_queue.iter().enumerate() {
+            let pass = task.inner_exclusive_access().pass;
+            // strides are not larger than BIG_STRIDE / 2, so passes can be compared
+            // by their difference even if they overflow
+            if min.map_or(true, |(_, min_pass)| (pass.wrapping_sub(min_pass) as i64) < 0) {
+                min = Some((id, pass));
+            }
+        }
+        let task = self.ready_queue.remove(min?.0)?;
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.pass = task_inner.pass.wrapping_add(BIG_STRIDE / task_inner.priority as u64);
+        drop(task_inner);
+        Some(task)
+    }
+    fn remove(&mut self, task: Arc<TaskControlBlock>) {
+        if let Some(id) = position_in(&self.ready_queue, &task) {
+            self.ready_queue.remove(id);
+        }
+    }
+}
+
+// number of priority levels of MLFQ, level 0 is the highest
+const MLFQ_LEVELS: usize = 3;
+// time slice of each level in timer ticks
+const MLFQ_TIME_SLICES: [usize; MLFQ_LEVELS] = [1, 2, 4];
+// all tasks are moved to the highest level every `MLFQ_BOOST_MS` milliseconds.
+// it is wall-clock time, since every hart calls `tick` on its own timer interrupts
+const MLFQ_BOOST_MS: usize = 1000;
+
+// Multi-level feedback queue scheduler, tasks using up their time slices are demoted,
+// tasks woken up after blocking are promoted
+pub struct MLFQScheduler {
+    queues: [VecDeque<Arc<TaskControlBlock>>; MLFQ_LEVELS],
+    // time of last priority boost in milliseconds
+    last_boost_ms: usize,
+}
+
+#[allow(unused)]
+impl MLFQScheduler {
+    pub fn new() -> Self {
+        Self {
+            queues: core::array::from_fn(|_| VecDeque::new()),
+            last_boost_ms: 0,
+        }
+    }
+}
+
+impl Scheduler for MLFQScheduler {
+    fn add(&mut self, task: Arc<TaskControlBlock>) {
+        let mut task_inner = task.inner_exclusive_access();
+        if task_inner.slice_ticks >= MLFQ_TIME_SLICES[task_inner.level] {
+            // the whole time slice is used, demote it
+            task_inner.level = (task_inner.level + 1).min(MLFQ_LEVELS - 1);
+            task_inner.slice_ticks = 0;
+        }
+        let level = task_inner.level;
+        drop(task_inner);
+        self.queues[level].push_back(task);
+    }
+    fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
+        self.queues.iter_mut().find_map(|queue| queue.pop_front())
+    }
+    fn remove(&mut self, task: Arc<TaskControlBlock>) {
+        for queue in self.queues.iter_mut() {
+            if let Some(id) = position_in(queue, &task) {
+                queue.remove(id);
+                return;
+            }
+        }
+    }
+    fn wakeup(&mut self, task: Arc<TaskControlBlock>) {
+        // the task gives up the CPU for I/O, promote it
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.level = task_inner.level.saturating_sub(1);
+        task_inner.slice_ticks = 0;
+        let level = task_inner.level;
+        drop(task_inner);
+        self.queues[level].push_back(task);
+    }
+    fn tick(&mut self) {
+        let now = get_time_ms();
+        if now - self.last_boost_ms < MLFQ_BOOST_MS {
+            return;
+        }
+        // priority boost, prevent tasks in low levels from starvation
+        self.last_boost_ms = now;
+        for level in 1..MLFQ_LEVELS {
+            while let Some(task) = self.queues[level].pop_front() {
+                let mut task_inner = task.inner_exclusive_access();
+                task_inner.level = 0;
+                task_inner.slice_ticks = 0;
+                drop(task_inner);
+                self.queues[0].push_back(task);
+            }
+        }
+    }
+    fn time_slice(&self, task: &TaskControlBlockInner) -> usize {
+        MLFQ_TIME_SLICES[task.level]
+    }
+}
+
+// scheduling policy chosen at build time by cargo features
+#[cfg(not(any(feature = "stride", feature = "mlfq")))]
+type SchedulerImpl = RoundRobinScheduler;
+#[cfg(feature = "stride")]
+type SchedulerImpl = StrideScheduler;
+#[cfg(all(feature = "mlfq", not(feature = "stride")))]
+type SchedulerImpl = MLFQScheduler;
+
+pub struct TaskManager {
+    scheduler: SchedulerImpl,
+}
+
 impl TaskManager {
     // Create an empty TaskManager
     pub fn new() -> Self {
         Self {
-            ready_queue: VecDeque::new(),
+            scheduler: SchedulerImpl::new(),
         }
     }
-    // Add a task to TaskManager
+    // Add a task to TaskManager, blocked tasks are never scheduled
     pub fn add(&mut self, task: Arc<TaskControlBlock>) {
-        self.ready_queue.push_back(task);
+        assert!(task.inner_exclusive_access().task_status != TaskStatus::Blocked);
+        self.scheduler.add(task);
     }
-    // Remove the first task and return it, return None if TaskManager is empty
+    // Remove the next task and return it, return None if TaskManager is empty
     pub fn fetch(&mut self) -> Option<Arc<TaskControlBlock>> {
-        self.ready_queue.pop_front()
+        self.scheduler.fetch()
+    }
+    // Remove a task from ready tasks if it exists
+    pub fn remove(&mut self, task: Arc<TaskControlBlock>) {
+        self.scheduler.remove(task);
+    }
+    // Add a task woken up after blocking
+    pub fn wakeup(&mut self, task: Arc<TaskControlBlock>) {
+        assert!(task.inner_exclusive_access().task_status != TaskStatus::Blocked);
+        self.scheduler.wakeup(task);
+    }
+    // Account a timer tick for `task` which is running,
+    // return true if it has used up its time slice.
+    // a task being blocked is never preempted, it switches out by itself
+    pub fn tick(&mut self, task: &Arc<TaskControlBlock>) -> bool {
+        self.scheduler.tick();
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.slice_ticks += 1;
+        task_inner.task_status == TaskStatus::Running
+            && task_inner.slice_ticks >= self.scheduler.time_slice(&task_inner)
     }
 }
 
 // Create a global instance of TaskManager
 lazy_static!{
-    pub static ref TASK_MANAGER: UPSafeCell<TaskManager> = 
-        unsafe { UPSafeCell::new(TaskManager::new()) };
-    pub static ref PID2TCB: UPSafeCell<BTreeMap<usize, Arc<TaskControlBlock>>> = 
-        unsafe { UPSafeCell::new(BTreeMap::new()) };
+    pub static ref TASK_MANAGER: SpinNoIrqLock<TaskManager> = 
+        SpinNoIrqLock::new(TaskManager::new());
+    pub static ref PID2PCB: SpinNoIrqLock<BTreeMap<usize, Arc<ProcessControlBlock>>> = 
+        SpinNoIrqLock::new(BTreeMap::new());
 }
 
 // Wrapper of add
 pub fn add_task(task: Arc<TaskControlBlock>) {
-    PID2TCB
-        .exclusive_access()
-        .insert(task.getpid(), Arc::clone(&task));
-    TASK_MANAGER.exclusive_access().add(task);
+    TASK_MANAGER.lock().add(task);
+}
+
+// Put a blocked task back to ready queue
+pub fn wakeup_task(task: Arc<TaskControlBlock>) {
+    let mut task_inner = task.inner_exclusive_access();
+    // the thread has exited while it was blocked, e.g. its process exited
+    if task_inner.res.is_none() {
+        return;
+    }
+    task_inner.task_status = TaskStatus::Ready;
+    drop(task_inner);
+    TASK_MANAGER.lock().wakeup(task);
+}
+
+// Wrapper of tick
+pub fn tick_task(task: &Arc<TaskControlBlock>) -> bool {
+    TASK_MANAGER.lock().tick(task)
+}
+
+// Wrapper of remove
+pub fn remove_task(task: Arc<TaskControlBlock>) {
+    TASK_MANAGER.lock().remove(task);
 }
 
 // Wrapper of fetch
 pub fn fetch_task() -> Option<Arc<TaskControlBlock>> {
-    TASK_MANAGER.exclusive_access().fetch()
+    TASK_MANAGER.lock().fetch()
 }
 
-pub fn pid2task(pid: usize) -> Option<Arc<TaskControlBlock>> {
-    let map = PID2TCB.exclusive_access();
+pub fn pid2process(pid: usize) -> Option<Arc<ProcessControlBlock>> {
+    let map = PID2PCB.lock();
     map.get(&pid).map(Arc::clone)
 }
 
-pub fn remove_from_pid2task(pid: usize) {
-    let mut map = PID2TCB.exclusive_access();
+// pids of all processes
+pub fn all_pids() -> Vec<usize> {
+    PID2PCB.lock().keys().cloned().collect()
+}
+
+// all processes in group `pgid`
+pub fn processes_in_group(pgid: usize) -> Vec<Arc<ProcessControlBlock>> {
+    // the map is released before locking processes
+    let processes: Vec<_> = PID2PCB.lock().values().cloned().collect();
+    processes
+        .into_iter()
+        .filter(|process| process.inner_exclusive_access().pgid == pgid)
+        .collect()
+}
+
+pub fn insert_into_pid2process(pid: usize, process: Arc<ProcessControlBlock>) {
+    PID2PCB.lock().insert(pid, process);
+}
+
+pub fn remove_from_pid2process(pid: usize) {
+    let mut map = PID2PCB.lock();
     if map.remove(&pid).is_none() {
-        panic!("Task with pid {} not found", pid);
+        panic!("Process with pid {} not found", pid);
     }
-}
\ No newline at end of file
+}
@@ -1,32 +1,41 @@
 // module about task manager, including starting and switching tasks
 
-use crate::{fs::{open_file, OpenFlags}, sbi::shutdown};
+use crate::{config::PAGE_SIZE, fs::{read_file, WorkDir}, sbi::shutdown, sync::{irq_disable, irq_restore, irq_save}};
 
-use self::{context::TaskContext, manager::remove_from_pid2task, task::{TaskStatus, TaskControlBlock}};
+use self::{context::TaskContext, id::TaskUserRes, manager::{processes_in_group, remove_task, tick_task}};
 
 mod context;
 mod switch;
 
 #[allow(clippy::module_inception)]
 mod task;
-mod pid;
+mod id;
 mod manager;
+mod process;
 mod processor;
 mod signal;
 mod action;
+use alloc::string::String;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::Ordering;
 use lazy_static::lazy_static;
+use crate::mm::{translate_ref, translated_byte_buffer, FilePage, PageFault, WriteBack};
 pub use processor::{
-    current_task, current_trap_cx, current_user_token, run_tasks, scheduler, take_current_task,
-    Processor
+    current_process, hart_id, current_task, current_trap_cx, current_trap_cx_user_va, current_user_token,
+    run_tasks, scheduler, take_current_task, Processor
 };
-pub use manager::{ add_task, pid2task };
+pub use manager::{ add_task, all_pids, pid2process, remove_from_pid2process, wakeup_task };
+pub use process::ProcessControlBlock;
+pub use task::{TaskControlBlock, TaskStatus, MIN_PRIORITY};
 pub use signal::{ MAX_SIG, SignalFlags };
 pub use action::{ SignalAction, SignalActions };
 
 pub const IDLE_PID: usize = 0;
 
 pub fn suspend_and_run_next() {
+    // not preempted while switching out, the task may go on with another hart
+    let sie = irq_save();
     let task = take_current_task().unwrap();
 
     // get current TaskControlBlock
@@ -34,121 +43,318 @@ pub fn suspend_and_run_next() {
     let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
     // Change status
     task_inner.task_status = TaskStatus::Ready;
+    task_inner.stop_running();
     drop(task_inner);
     // drop current TaskControlBlock
 
     add_task(task);
     // goto scheduling
     scheduler(task_cx_ptr);
+    irq_restore(sie);
+}
+
+// mark current task as blocked and return it, it should be put into a wait queue
+// and then call `block_current_and_run_next`.
+// it is marked before being put into the wait queue, since other harts may wake it up at once.
+// interrupts must be disabled until it is blocked, or it may be preempted and be put into
+// the ready queue while it is in the wait queue
+pub fn prepare_block_current() -> Arc<TaskControlBlock> {
+    let task = current_task().unwrap();
+    task.inner_exclusive_access().task_status = TaskStatus::Blocked;
+    task
+}
+
+// block current task, it is not in ready queue and waits to be woken up by `wakeup_task`
+pub fn block_current_and_run_next() {
+    assert!(!riscv::register::sstatus::read().sie(), "block with interrupts enabled");
+    let task = take_current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let task_cx_ptr = &mut task_inner.task_cx as *mut TaskContext;
+    // the status is set in `prepare_block_current`, it may be woken up already
+    task_inner.stop_running();
+    drop(task_inner);
+    scheduler(task_cx_ptr);
+}
+
+// account a timer tick for current task, return true if it should be preempted.
+// there is no current task if the hart is idle or switching tasks
+pub fn current_tick() -> bool {
+    current_task().map_or(false, |task| tick_task(&task))
 }
 
 pub fn exit_current_and_run_next(exit_code: i32) {
+    let task = current_task().unwrap();
+    let process = task.process.upgrade().unwrap();
+    if task.inner_exclusive_access().res.as_ref().unwrap().tid == 0 {
+        // files may sleep, which needs a current task, so the main thread writes back
+        // shared file mappings and closes files before it leaves the CPU
+        let mut process_inner = process.inner_exclusive_access();
+        let write_back = process_inner.memory_set.write_back();
+        let fd_table = core::mem::take(&mut process_inner.fd_table);
+        drop(process_inner);
+        write_back.iter().for_each(WriteBack::write);
+        drop(fd_table);
+    }
+    drop(process);
+    drop(task);
+    // never returns, interrupts are enabled again by the next task
+    irq_disable();
     let task = take_current_task().unwrap();
+    let mut task_inner = task.inner_exclusive_access();
+    let process = task.process.upgrade().unwrap();
+    let tid = task_inner.res.as_ref().unwrap().tid;
+    // record exit code, and release user resources of the thread.
+    // releasing them locks the process, so the thread is released first
+    task_inner.exit_code = Some(exit_code);
+    let res = task_inner.res.take();
+    drop(task_inner);
+    drop(res);
+    drop(task);
+    // the process exits when its main thread exits
+    if tid == 0 {
+        let pid = process.getpid();
+        if pid == IDLE_PID {
+            info!("kernel #0", "Idle process exited with exit_code {}, shutdown ...", exit_code);
+            if exit_code != 0{
+                shutdown(true)
+            } else {
+                shutdown(false)
+            }
+        }
+
+        // it stays in pid2process as a zombie until its parent reaps it,
+        // so that procfs shows it with its exit code
 
-    let pid = task.getpid();
-    if pid == IDLE_PID {
-        info!("kernel #0", "Idle task exited with exit_code {}, shutdown ...", exit_code);
-        if exit_code != 0{
-            shutdown(true)
-        } else {
-            shutdown(false)
+        // access current ProcessControlBlock
+        let mut process_inner = process.inner_exclusive_access();
+        process_inner.is_zombie = true;
+        process_inner.exit_code = exit_code;
+
+        // access initproc ProcessControlBlock
+        {
+            let mut initproc_inner = INITPROC.inner_exclusive_access();
+            for child in process_inner.children.iter() {
+                child.inner_exclusive_access().parent = Some(Arc::downgrade(&INITPROC));
+                initproc_inner.children.push(child.clone());
+            }
         }
-    }
+        // release initproc ProcessControlBlock
 
-    //remove from pid2task
-    remove_from_pid2task(task.getpid());
+        drop(process_inner);
 
-    // access current TaskControlBlock
-    let mut inner = task.inner_exclusive_access();
-    inner.task_status = TaskStatus::Zombie;
-    inner.exit_code = exit_code;
+        // take user resources of other threads, and remove them from scheduler.
+        // threads running on other harts are waited until they leave the CPU,
+        // they exit by themselves when they find the process is a zombie
+        let mut recycle_res = Vec::<TaskUserRes>::new();
+        loop {
+            let process_inner = process.inner_exclusive_access();
+            let mut running = false;
+            for task in process_inner.tasks.iter().skip(1).flatten() {
+                remove_task(Arc::clone(task));
+                let mut task_inner = task.inner_exclusive_access();
+                if task.on_cpu.load(Ordering::Acquire) {
+                    running = true;
+                } else if let Some(res) = task_inner.res.take() {
+                    recycle_res.push(res);
+                }
+            }
+            // dealloc_user_res needs to access ProcessControlBlock
+            drop(process_inner);
+            recycle_res.clear();
+            if !running {
+                break;
+            }
+            core::hint::spin_loop();
+        }
 
-    // access initproc TaskControlBlock
-    {
-        let mut initproc_inner = INITPROC.inner_exclusive_access();
-        for child in inner.children.iter() {
-            child.inner_exclusive_access().parent = Some(Arc::downgrade(&INITPROC));
-            initproc_inner.children.push(child.clone());
+        let mut process_inner = process.inner_exclusive_access();
+        process_inner.children.clear();
+        // dealloc user space
+        process_inner.memory_set.recycle_data_pages();
+        // drop file descriptors
+        process_inner.fd_table.clear();
+        // release the mount of the working directory like open files
+        process_inner.cwd = WorkDir::root();
+        // drop synchronization primitives, with threads blocked on them
+        process_inner.mutex_list.clear();
+        process_inner.semaphore_list.clear();
+        process_inner.condvar_list.clear();
+        // keep the main thread, its kernel stack is being used now
+        while process_inner.tasks.len() > 1 {
+            process_inner.tasks.pop();
         }
     }
-    // release initproc TaskControlBlock
-
-    inner.children.clear();
-    // dealloc user space
-    inner.memory_set.recycle_data_pages();
-    // drop file descriptors
-    inner.fd_table.clear();
-    drop(inner);
-    // release current TaskControlBlock
-    drop(task);
+    // release current ProcessControlBlock
+    drop(process);
     // no TaskContext neede to save, use an empty one
     let mut _unused = TaskContext::zero_init();
     scheduler(&mut _unused as *mut _);
 }
 
 lazy_static!{
-    pub static ref INITPROC: Arc<TaskControlBlock> = Arc::new({
-        let inode = open_file("initproc", OpenFlags::RDONLY).unwrap();
-        let v = inode.read_all();
-        TaskControlBlock::new(v.as_slice())
-});
+    pub static ref INITPROC: Arc<ProcessControlBlock> = {
+        let v = read_file("initproc").unwrap();
+        ProcessControlBlock::new(v.as_slice())
+    };
 }
 
 pub fn add_initproc() {
-    add_task(INITPROC.clone());
+    // the main thread of initproc is added to scheduler when it is created
+    let _initproc = INITPROC.clone();
+}
+
+// if the process of current thread has exited on another hart
+pub fn current_process_exited() -> bool {
+    current_process().inner_exclusive_access().is_zombie
 }
 
 pub fn check_signal_error_of_current() -> Option<(i32, &'static str)> {
-    let task = current_task().unwrap();
-    let task_inner = task.inner_exclusive_access();
-    task_inner.signals.check_error()
+    let process = current_process();
+    let process_inner = process.inner_exclusive_access();
+    process_inner.signals.check_error()
+}
+
+// if current process has signals not masked, blocking system calls return early
+// to handle them
+pub fn current_signal_pending() -> bool {
+    let process = current_process();
+    let process_inner = process.inner_exclusive_access();
+    !(process_inner.signals - process_inner.signal_mask).is_empty()
+}
+
+// send `signal` to all processes in group `pgid`,
+// initproc never receives signals from the terminal
+pub fn send_signal_to_group(pgid: usize, signal: SignalFlags) {
+    for process in processes_in_group(pgid) {
+        if process.getpid() != IDLE_PID {
+            process.inner_exclusive_access().signals |= signal;
+        }
+    }
 }
 
 pub fn current_add_signal(signal: SignalFlags) {
-    let task = current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access();
-    task_inner.signals |= signal;
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
+    process_inner.signals |= signal;
+}
+
+// try to resolve a page fault of current task, return false if it can't be handled
+pub fn current_handle_page_fault(va: usize, is_write: bool) -> bool {
+    let process = current_process();
+    let fault = process.inner_exclusive_access().memory_set.handle_page_fault(va.into(), is_write);
+    match fault {
+        PageFault::Handled => true,
+        PageFault::Invalid => false,
+        PageFault::ReadFile(page) => map_file_page(&process, page),
+    }
+}
+
+// read a page of a file mapping and map it, return false if the file can't be read.
+// the file may sleep, so the process is unlocked while it is read
+fn map_file_page(process: &ProcessControlBlock, page: FilePage) -> bool {
+    if !page.read() {
+        return false;
+    }
+    process.inner_exclusive_access().memory_set.map_file_page(page);
+    true
+}
+
+// Resolve faults in user buffer [start, start + len) of current process before kernel
+// accesses it through physical address, return false if some page is invalid.
+// current process must not be locked
+pub fn current_prepare_user_buffer(start: usize, len: usize, is_write: bool) -> bool {
+    let process = current_process();
+    loop {
+        let fault = process
+            .inner_exclusive_access()
+            .memory_set
+            .prepare_user_buffer(start, len, is_write);
+        match fault {
+            PageFault::Handled => return true,
+            PageFault::Invalid => return false,
+            PageFault::ReadFile(page) => {
+                if !map_file_page(&process, page) {
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+// copy a `T` at `ptr` of current user space, None if it is invalid
+pub fn current_user_ref<T: Copy + 'static>(ptr: *const T) -> Option<T> {
+    if !current_prepare_user_buffer(ptr as usize, core::mem::size_of::<T>(), false) {
+        return None;
+    }
+    Some(*translate_ref(current_user_token(), ptr))
+}
+
+// copy the string ended with zero at `ptr` of current user space, None if it is invalid.
+// its length is unknown, so pages are prepared one by one
+pub fn current_user_str(ptr: *const u8) -> Option<String> {
+    let token = current_user_token();
+    let mut string = String::new();
+    let mut va = ptr as usize;
+    loop {
+        let len = PAGE_SIZE - va % PAGE_SIZE;
+        if !current_prepare_user_buffer(va, len, false) {
+            return None;
+        }
+        let page = translated_byte_buffer(token, va as *const u8, len).pop().unwrap();
+        match page.iter().position(|c| *c == 0) {
+            Some(end) => {
+                string.extend(page[..end].iter().map(|c| *c as char));
+                return Some(string);
+            }
+            None => string.extend(page.iter().map(|c| *c as char)),
+        }
+        va += len;
+    }
 }
 
 fn call_kernel_signal_handler(signal: SignalFlags) {
-    let task = current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
     match signal {
         SignalFlags::SIGSTOP => {
-            task_inner.frozen = true;
-            task_inner.signals ^= SignalFlags::SIGSTOP;
+            process_inner.frozen = true;
+            process_inner.signals ^= SignalFlags::SIGSTOP;
         }
         SignalFlags::SIGCONT => {
-            if task_inner.signals.contains(SignalFlags::SIGCONT) {
-                task_inner.signals ^= SignalFlags::SIGCONT;
-                task_inner.frozen = false;
+            if process_inner.signals.contains(SignalFlags::SIGCONT) {
+                process_inner.signals ^= SignalFlags::SIGCONT;
+                process_inner.frozen = false;
             }
         }
         _ => {
-            task_inner.killed = true;
+            process_inner.killed = true;
         }
     }
 }
 
 fn call_user_signal_handler(sig: usize, signal: SignalFlags) {
-    let task = current_task().unwrap();
-    let mut task_inner = task.inner_exclusive_access();
+    let process = current_process();
+    let mut process_inner = process.inner_exclusive_access();
 
-    let handler = task_inner.signal_actions.table[sig].handler;
+    let handler = process_inner.signal_actions.table[sig].handler;
     if handler != 0 {
         // user handler exists
-        task_inner.handling_sig = sig as isize;
-        task_inner.signals ^= signal;
+        process_inner.handling_sig = sig as isize;
+        process_inner.signals ^= signal;
 
-        //backup trap context
-        let trap_cx = task_inner.get_trap_cx();
-        task_inner.trap_ctx_backup = Some(*trap_cx);
+        //backup trap context of current thread
+        let trap_cx = current_trap_cx();
+        process_inner.trap_ctx_backup = Some(*trap_cx);
 
         //modify trapcontext
         trap_cx.sepc = handler;
 
         // put args(a0)
         trap_cx.x[10] = sig;
+    } else if signal == SignalFlags::SIGTSTP {
+        // stop the process by default, until SIGCONT
+        process_inner.frozen = true;
+        process_inner.signals ^= signal;
     } else {
         info!("kernel #0", "task/call_user_signal_handler: default action: ignore it or kill process");
     }
@@ -156,18 +362,18 @@ fn call_user_signal_handler(sig: usize, signal: SignalFlags) {
 
 fn check_pending_signals() {
     for sig in 0..(MAX_SIG + 1) {
-        let task = current_task().unwrap();
-        let task_inner = task.inner_exclusive_access();
+        let process = current_process();
+        let process_inner = process.inner_exclusive_access();
         let signal = SignalFlags::from_bits(1 << sig).unwrap();
         // if the signal is received and is not masked
-        if task_inner.signals.contains(signal) && (!task_inner.signal_mask.contains(signal)) {
+        if process_inner.signals.contains(signal) && (!process_inner.signal_mask.contains(signal)) {
             let mut masked = true;
-            let handling_sig = task_inner.handling_sig;
+            let handling_sig = process_inner.handling_sig;
             if handling_sig == -1 {
                 masked = false;
             } else {
                 let handling_sig = handling_sig as usize;
-                if !task_inner.signal_actions.table[handling_sig]
+                if !process_inner.signal_actions.table[handling_sig]
                     .mask
                     .contains(signal)
                 {
@@ -175,8 +381,8 @@ fn check_pending_signals() {
                 }
             }
             if !masked {
-                drop(task_inner);
-                drop(task);
+                drop(process_inner);
+                drop(process);
                 let kernel_sig = SignalFlags::SIGKILL 
                     | SignalFlags::SIGSTOP 
                     | SignalFlags::SIGCONT
@@ -198,9 +404,9 @@ pub fn handle_signals() {
     loop {
         check_pending_signals();
         let (frozen, killed) = {
-            let task = current_task().unwrap();
-            let task_inner = task.inner_exclusive_access();
-            (task_inner.frozen, task_inner.killed)
+            let process = current_process();
+            let process_inner = process.inner_exclusive_access();
+            (process_inner.frozen, process_inner.killed)
         };
         if !frozen || killed {
             break;
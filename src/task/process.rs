@@ -0,0 +1,337 @@
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+use super::action::SignalActions;
+use super::add_task;
+use super::id::{pid_alloc, PidHandler, RecycleAllocator};
+use super::manager::insert_into_pid2process;
+use super::signal::SignalFlags;
+use super::task::TaskControlBlock;
+use crate::config::USER_STACK_SIZE;
+use crate::fs::{File, Stdin, Stdout, WorkDir};
+use crate::mm::{translated_refmut, MemorySet, PageFault, VirtAddr, WriteBack, KERNEL_SPACE};
+use crate::sync::{Condvar, Mutex, ResourceTracker, Semaphore, SpinNoIrqLock, SpinNoIrqLockGuard};
+use crate::trap::{trap_handler, TrapContext};
+
+// Process control block, owns the address space, fd table and signal state
+// shared by all threads in it
+pub struct ProcessControlBlock {
+    // immutable
+    pub pid: PidHandler,
+    // mutable
+    inner: SpinNoIrqLock<ProcessControlBlockInner>,
+}
+
+pub struct ProcessControlBlockInner {
+    pub is_zombie: bool,
+    pub memory_set: MemorySet,
+    // the top of the task data, also the bottom of heap
+    pub base_size: usize,
+    // the program break, heap is [base_size, program_brk)
+    pub program_brk: usize,
+    pub parent: Option<Weak<ProcessControlBlock>>,
+    pub children: Vec<Arc<ProcessControlBlock>>,
+    // process group, signals from the terminal are sent to its foreground group
+    pub pgid: usize,
+    // current working directory
+    pub cwd: WorkDir,
+    pub exit_code: i32,
+    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
+    pub signals: SignalFlags,
+    pub signal_mask: SignalFlags,
+    /// the signal which is being handling
+    pub handling_sig: isize,
+    /// signal actions table
+    pub signal_actions: SignalActions,
+    /// if the process is killed
+    pub killed: bool,
+    /// if the process is frozen by a signal
+    pub frozen: bool,
+    pub trap_ctx_backup: Option<TrapContext>,
+    /// threads of the process, indexed by tid
+    pub tasks: Vec<Option<Arc<TaskControlBlock>>>,
+    pub task_res_allocator: RecycleAllocator,
+    /// synchronization primitives of the process, indexed by their ids
+    pub mutex_list: Vec<Option<Arc<dyn Mutex>>>,
+    pub semaphore_list: Vec<Option<Arc<Semaphore>>>,
+    pub condvar_list: Vec<Option<Arc<Condvar>>>,
+    /// if deadlock detection is enabled
+    pub deadlock_detect: bool,
+    /// banker's algorithm tables of mutexes and semaphores
+    pub mutex_tracker: ResourceTracker,
+    pub semaphore_tracker: ResourceTracker,
+}
+
+impl ProcessControlBlockInner {
+    pub fn get_user_token(&self) -> usize {
+        self.memory_set.token()
+    }
+    pub fn alloc_fd(&mut self) -> usize {
+        if let Some(fd) = (0..self.fd_table.len()).find(|fd| self.fd_table[*fd].is_none()) {
+            fd
+        } else {
+            self.fd_table.push(None);
+            self.fd_table.len() - 1
+        }
+    }
+    pub fn alloc_tid(&mut self) -> usize {
+        self.task_res_allocator.alloc()
+    }
+    pub fn dealloc_tid(&mut self, tid: usize) {
+        self.task_res_allocator.dealloc(tid)
+    }
+    pub fn thread_count(&self) -> usize {
+        self.tasks.iter().filter(|task| task.is_some()).count()
+    }
+    pub fn get_task(&self, tid: usize) -> Arc<TaskControlBlock> {
+        self.tasks[tid].as_ref().unwrap().clone()
+    }
+}
+
+impl ProcessControlBlock {
+    pub fn inner_exclusive_access(&self) -> SpinNoIrqLockGuard<'_, ProcessControlBlockInner> {
+        self.inner.lock()
+    }
+    pub fn getpid(&self) -> usize {
+        self.pid.0
+    }
+    // create a new process with its main thread, used to create initprocess
+    pub fn new(elf_data: &[u8]) -> Arc<Self> {
+        // load elf data to a memory set
+        let (memory_set, heap_bottom, entry_point) = MemorySet::from_elf(elf_data);
+        // alloc a pid for the process
+        let pid_handle = pid_alloc();
+        let pid = pid_handle.0;
+        let process = Arc::new(Self {
+            pid: pid_handle,
+            inner: SpinNoIrqLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                base_size: heap_bottom,
+                program_brk: heap_bottom,
+                parent: None,
+                children: Vec::new(),
+                pgid: pid,
+                cwd: WorkDir::root(),
+                exit_code: 0,
+                fd_table: vec![
+                    // 0 -> stdin
+                    Some(Arc::new(Stdin)),
+                    // 1 -> stdout
+                    Some(Arc::new(Stdout)),
+                    // 2 -> stderr
+                    Some(Arc::new(Stdout)),
+                ],
+                signals: SignalFlags::empty(),
+                signal_mask: SignalFlags::empty(),
+                handling_sig: -1,
+                signal_actions: SignalActions::default(),
+                killed: false,
+                frozen: false,
+                trap_ctx_backup: None,
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                deadlock_detect: false,
+                mutex_tracker: ResourceTracker::new(),
+                semaphore_tracker: ResourceTracker::new(),
+            }),
+        });
+        // create the main thread, user stack and trap context are allocated here
+        let task = Arc::new(TaskControlBlock::new(Arc::clone(&process), true).unwrap());
+        //prepare TrapContext in user space
+        let task_inner = task.inner_exclusive_access();
+        let trap_cx = task_inner.get_trap_cx();
+        let ustack_top = task_inner.res.as_ref().unwrap().ustack_top();
+        let kernel_stack_top = task.kernel_stack.get_top();
+        drop(task_inner);
+        *trap_cx = TrapContext::app_init_context(
+            entry_point,
+            ustack_top,
+            KERNEL_SPACE.lock().token(),
+            kernel_stack_top,
+            trap_handler as usize,
+        );
+        // add the main thread to the process
+        process.inner_exclusive_access().tasks.push(Some(Arc::clone(&task)));
+        insert_into_pid2process(process.getpid(), Arc::clone(&process));
+        // add the main thread to scheduler
+        add_task(task);
+        process
+    }
+    // only support processes with a single thread.
+    // return false if the arguments don't fit in the user stack, the program overlaps
+    // with it, or there is no memory for them, then the process is unchanged
+    pub fn exec(self: &Arc<Self>, elf_data: &[u8], args: Vec<String>) -> bool {
+        assert_eq!(self.inner_exclusive_access().thread_count(), 1);
+        let args_size = (args.len() + 1) * core::mem::size_of::<usize>()
+            + args.iter().map(|arg| arg.len() + 1).sum::<usize>()
+            + core::mem::size_of::<usize>();
+        if args_size > USER_STACK_SIZE {
+            return false;
+        }
+        // make a memory set using new elf data, with user stack and trap context
+        // of the main thread
+        let (mut memory_set, heap_bottom, entry_point) = MemorySet::from_elf(elf_data);
+        let task = self.inner_exclusive_access().get_task(0);
+        let task_inner = task.inner_exclusive_access();
+        let res = task_inner.res.as_ref().unwrap();
+        if !res.alloc_user_res(&mut memory_set) {
+            debug!("kernel #0", "exec: the program overlaps with the user stack");
+            return false;
+        }
+        let mut user_sp = res.ustack_top();
+        drop(task_inner);
+        // push arguement to user stack
+        // user stack is lazy, map the pages used by arguments first
+        if !matches!(
+            memory_set.prepare_user_buffer(user_sp - args_size, args_size, true),
+            PageFault::Handled
+        ) {
+            debug!("kernel #0", "exec: no memory for arguments");
+            return false;
+        }
+        let token = memory_set.token();
+        let mut inner = self.inner_exclusive_access();
+        // shared mappings of the old address space are written back after it is unlocked
+        let write_back = inner.memory_set.write_back();
+        inner.memory_set = memory_set;
+        inner.base_size = heap_bottom;
+        inner.program_brk = heap_bottom;
+        drop(inner);
+        write_back.iter().for_each(WriteBack::write);
+        let mut task_inner = task.inner_exclusive_access();
+        task_inner.trap_cx_ppn = task_inner.res.as_mut().unwrap().trap_cx_ppn();
+        drop(task_inner);
+        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
+        let argv_base = user_sp;
+        // argv stores the address for the address of each args
+        let mut argv: Vec<_> = (0..=args.len())
+            .map(|arg| {
+                translated_refmut(
+                        token,
+                        (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize,
+                )
+            })
+            .collect();
+        // end with 0
+        *argv[args.len()] = 0;
+        for i in 0..args.len() {
+            user_sp -= args[i].len() + 1;
+            *argv[i] = user_sp;
+            let mut p = user_sp;
+            // put the arguement to stack
+            for c in args[i].as_bytes() {
+                *translated_refmut(token, p as *mut u8) = *c;
+                p += 1;
+            }
+            // end with 0
+            *translated_refmut(token, p as *mut u8) = 0;
+        }
+        user_sp -= user_sp % core::mem::size_of::<usize>();
+        let mut trap_cx = TrapContext::app_init_context(
+            entry_point,
+            user_sp,
+            KERNEL_SPACE.lock().token(),
+            task.kernel_stack.get_top(),
+            trap_handler as usize,
+        );
+        trap_cx.x[10] = args.len();
+        trap_cx.x[11] = argv_base;
+        *task.inner_exclusive_access().get_trap_cx() = trap_cx;
+        true
+    }
+    // change the program break by `size`, return the old break if succeeded
+    pub fn change_program_brk(&self, size: isize) -> Option<usize> {
+        let mut inner = self.inner_exclusive_access();
+        let heap_bottom = inner.base_size;
+        let old_brk = inner.program_brk;
+        let new_brk = (old_brk as isize).checked_add(size)?;
+        if new_brk < heap_bottom as isize {
+            return None;
+        }
+        let result = if size < 0 {
+            inner
+                .memory_set
+                .shrink_to(VirtAddr(heap_bottom), VirtAddr(new_brk as usize))
+        } else {
+            inner
+                .memory_set
+                .append_to(VirtAddr(heap_bottom), VirtAddr(new_brk as usize))
+        };
+        if result {
+            inner.program_brk = new_brk as usize;
+            Some(old_brk)
+        } else {
+            None
+        }
+    }
+    // only support processes with a single thread
+    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
+        // get parent ProcessControlBlock
+        let mut parent_inner = self.inner_exclusive_access();
+        assert_eq!(parent_inner.thread_count(), 1);
+        // copy user space, include the user stack and trap context of the main thread
+        let memory_set = MemorySet::from_existed_user(&mut parent_inner.memory_set);
+        // alloc a pid
+        let pid_handle = pid_alloc();
+        // copy fd table
+        let mut new_fd_table: Vec<Option<Arc<dyn File + Send + Sync>>> = Vec::new();
+        for fd in parent_inner.fd_table.iter() {
+            if let Some(file) = fd {
+                new_fd_table.push(Some(file.clone()));
+            } else {
+                new_fd_table.push(None);
+            }
+        }
+        let child = Arc::new(Self {
+            pid: pid_handle,
+            inner: SpinNoIrqLock::new(ProcessControlBlockInner {
+                is_zombie: false,
+                memory_set,
+                base_size: parent_inner.base_size,
+                program_brk: parent_inner.program_brk,
+                parent: Some(Arc::downgrade(self)),
+                children: Vec::new(),
+                // inherit the process group
+                pgid: parent_inner.pgid,
+                cwd: parent_inner.cwd.clone(),
+                exit_code: 0,
+                fd_table: new_fd_table,
+                // inherit the signal_mask and signal_action
+                signal_mask: parent_inner.signal_mask,
+                signals: SignalFlags::empty(),
+                handling_sig: -1,
+                signal_actions: parent_inner.signal_actions.clone(),
+                killed: false,
+                frozen: false,
+                trap_ctx_backup: None,
+                tasks: Vec::new(),
+                task_res_allocator: RecycleAllocator::new(),
+                mutex_list: Vec::new(),
+                semaphore_list: Vec::new(),
+                condvar_list: Vec::new(),
+                deadlock_detect: false,
+                mutex_tracker: ResourceTracker::new(),
+                semaphore_tracker: ResourceTracker::new(),
+            }),
+        });
+        // add child
+        parent_inner.children.push(Arc::clone(&child));
+        drop(parent_inner);
+        // create main thread of child process, user resources are copied already
+        let task = Arc::new(TaskControlBlock::new(Arc::clone(&child), false).unwrap());
+        child.inner_exclusive_access().tasks.push(Some(Arc::clone(&task)));
+        // modify kernel_sp in trap_cx
+        let trap_cx = task.inner_exclusive_access().get_trap_cx();
+        trap_cx.kernel_sp = task.kernel_stack.get_top();
+        insert_into_pid2process(child.getpid(), Arc::clone(&child));
+        // add the main thread of child to scheduler
+        add_task(task);
+        child
+    }
+}
@@ -1,10 +1,13 @@
 
 use alloc::sync::Arc;
+use core::arch::asm;
+use core::sync::atomic::Ordering;
 use lazy_static::lazy_static;
 
-use crate::{sync::UPSafeCell, trap::TrapContext};
+use crate::{config::MAX_HARTS, sync::{irq_disable, irq_enable, pop_off, push_off, SpinNoIrqLock, SpinNoIrqLockGuard}, trap::TrapContext};
 
-use super::{task::{TaskControlBlock, TaskStatus}, context::TaskContext, manager::fetch_task, switch::__switch};
+use super::{task::TaskControlBlock, context::TaskContext, manager::fetch_task, switch::__switch};
+use super::process::ProcessControlBlock;
 
 
 // Processor managment structure
@@ -37,28 +40,48 @@ impl Processor {
     }
 }
 
-// Create a global instance of Processor
+// Create a Processor for each hart
 lazy_static!{
-    pub static ref PROCESSOR: UPSafeCell<Processor> = 
-        unsafe { UPSafeCell::new(Processor::new()) };
+    pub static ref PROCESSORS: [SpinNoIrqLock<Processor>; MAX_HARTS] =
+        core::array::from_fn(|_| SpinNoIrqLock::new(Processor::new()));
+}
+
+// Get the id of current hart, which is kept in tp
+pub fn hart_id() -> usize {
+    let hart_id: usize;
+    unsafe {
+        asm!("mv {}, tp", out(reg) hart_id);
+    }
+    hart_id
+}
+
+// Get the Processor of current hart
+fn current_processor() -> SpinNoIrqLockGuard<'static, Processor> {
+    // the task can't be preempted and moved to another hart before its processor is locked
+    push_off();
+    let processor = PROCESSORS[hart_id()].lock();
+    pop_off();
+    processor
 }
 
 // Wrapper of take_current
 pub fn take_current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().take_current()
+    current_processor().take_current()
 }
 
 // Wrapper of current
 pub fn current_task() -> Option<Arc<TaskControlBlock>> {
-    PROCESSOR.exclusive_access().current()
+    current_processor().current()
+}
+
+// Get the process of current task
+pub fn current_process() -> Arc<ProcessControlBlock> {
+    current_task().unwrap().process.upgrade().unwrap()
 }
 
 // Get the token of current task
 pub fn current_user_token() -> usize {
-    current_task()
-        .unwrap()
-        .inner_exclusive_access()
-        .get_user_token()
+    current_task().unwrap().get_user_token()
 }
 
 // Get the trap context of current task
@@ -69,30 +92,62 @@ pub fn current_trap_cx() -> &'static mut TrapContext {
         .get_trap_cx() as &mut TrapContext
 }
 
+// Get the address of trap context of current task in user space
+pub fn current_trap_cx_user_va() -> usize {
+    current_task()
+        .unwrap()
+        .inner_exclusive_access()
+        .res
+        .as_ref()
+        .unwrap()
+        .trap_cx_user_va()
+}
+
 // Loop `fetch_task` to get the process that needs to run, and switch the process
 // through `__switch`
 pub fn run_tasks() {
     loop {
-        let mut processor = PROCESSOR.exclusive_access();
+        let mut processor = current_processor();
         if let Some(task) = fetch_task() {
+            // the task may be still switching out on another hart,
+            // wait until its task context is saved
+            while task.on_cpu.load(Ordering::Acquire) {
+                core::hint::spin_loop();
+            }
             let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
             // get next task's TaskControlBlock
             let mut task_inner = task.inner_exclusive_access();
+            // its process has exited and its user resources are recycled
+            if task_inner.res.is_none() {
+                continue;
+            }
+            // set with task inner held, so that an exiting process can see it
+            task.on_cpu.store(true, Ordering::Relaxed);
             let next_task_cx_ptr = &task_inner.task_cx as *const TaskContext;
-            task_inner.task_status = TaskStatus::Running;
+            task_inner.start_running();
             drop(task_inner);
-            processor.current = Some(task);
+            processor.current = Some(Arc::clone(&task));
             drop(processor);
             unsafe {
+                // kernel stacks may be remapped by other harts, flush stale translations
+                asm!("sfence.vma");
                 __switch(idle_task_cx_ptr, next_task_cx_ptr);
             }
+            // back to idle, the task can run on other harts now
+            task.on_cpu.store(false, Ordering::Release);
+        } else {
+            drop(processor);
+            // idle runs with interrupts disabled, open a window to take pending
+            // interrupts, which may wake up sleeping tasks
+            irq_enable();
+            irq_disable();
         }
     }
 }
 
 // Return to idle control flow for new scheduling
 pub fn scheduler(switch_task_cx_ptr: *mut TaskContext) {
-    let mut processor = PROCESSOR.exclusive_access();
+    let mut processor = current_processor();
     let idle_task_cx_ptr = processor.get_idle_task_cx_ptr();
     drop(processor);
     unsafe {
@@ -1,250 +1,115 @@
-use core::cell::RefMut;
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::AtomicBool;
 
-use alloc::string::String;
-use alloc::sync::Weak;
-use alloc::sync::Arc;
-use alloc::vec;
-use alloc::vec::Vec;
-
-use super::action::SignalActions;
 use super::context::TaskContext;
-use super::signal::SignalFlags;
-use crate::config::TRAP_CONTEXT;
-use crate::fs::{ File, Stdin, Stdout };
-use crate::mm::translated_refmut;
-use crate::mm::{MemorySet, PhysPageNum, KERNEL_SPACE, VirtAddr};
-use crate::sync::UPSafeCell;
-use crate::trap::{TrapContext, trap_handler};
-use super::pid::{PidHandler, KernelStack, pid_alloc};
+use super::id::{kstack_alloc, KernelStack, TaskUserRes};
+use super::process::ProcessControlBlock;
+use crate::mm::PhysPageNum;
+use crate::sync::{SpinNoIrqLock, SpinNoIrqLockGuard};
+use crate::timer::get_time_ms;
+use crate::trap::TrapContext;
 
+// Thread control block, the unit of scheduling
 pub struct TaskControlBlock {
     // immutable
-    pub pid: PidHandler,
+    pub process: Weak<ProcessControlBlock>,
     pub kernel_stack: KernelStack,
+    // set when a hart switches to it, cleared after its task context is saved
+    pub on_cpu: AtomicBool,
     // mutable
-    inner: UPSafeCell<TaskControlBlockInner>,
+    inner: SpinNoIrqLock<TaskControlBlockInner>,
 }
 
 pub struct TaskControlBlockInner {
+    // tid, user stack and trap context in user space
+    pub res: Option<TaskUserRes>,
     // the physcal page number of trap context
     pub trap_cx_ppn: PhysPageNum,
-    // the top of the task data
-    pub base_size: usize,
     pub task_cx: TaskContext,
     pub task_status: TaskStatus,
-    pub memory_set: MemorySet,
-    pub parent: Option<Weak<TaskControlBlock>>,
-    pub children: Vec<Arc<TaskControlBlock>>,
-    pub exit_code: i32,
-    pub fd_table: Vec<Option<Arc<dyn File + Send + Sync>>>,
-    pub signals: SignalFlags,
-    pub signal_mask: SignalFlags,
-    /// the signal which is being handling
-    pub handling_sig: isize,
-    /// signal actions table
-    pub signal_actions: SignalActions,
-    /// if the task is killed
-    pub killed: bool,
-    /// if the task is frozen by a signal
-    pub frozen: bool,
-    pub trap_ctx_backup: Option<TrapContext>,
+    // set when the thread exits
+    pub exit_code: Option<i32>,
+    // used by stride scheduler, the stride is `BIG_STRIDE / priority`
+    pub priority: usize,
+    pub pass: u64,
+    // level of multi-level feedback queue
+    pub level: usize,
+    // timer ticks used in current time slice
+    pub slice_ticks: usize,
+    // total CPU time in milliseconds
+    pub cpu_time_ms: usize,
+    // the time it starts running last time
+    pub last_run_ms: usize,
 }
+
+#[allow(unused)]
+pub const BIG_STRIDE: u64 = 0x1_0000_0000;
+pub const DEFAULT_PRIORITY: usize = 16;
+// priority should be at least 2, so that a stride is not larger than `BIG_STRIDE / 2`
+pub const MIN_PRIORITY: usize = 2;
 // Used to represent the status of a task
 #[derive(Copy, Clone, PartialEq)]
 pub enum TaskStatus {
     Ready,
     Running,
-    Zombie,
+    Blocked,
 }
 
 impl TaskControlBlockInner {
     pub fn get_trap_cx(&self) -> &'static mut TrapContext {
         self.trap_cx_ppn.get_mut()
     }
-    pub fn get_user_token(&self) -> usize {
-        self.memory_set.token()
-    }
+    #[allow(unused)]
     fn get_status(&self) -> TaskStatus {
         self.task_status
     }
-    pub fn is_zombie(&self) -> bool {
-        self.get_status() == TaskStatus::Zombie
+    // called when the task is switched to
+    pub fn start_running(&mut self) {
+        self.task_status = TaskStatus::Running;
+        self.last_run_ms = get_time_ms();
     }
-    pub fn alloc_fd(&mut self) -> usize {
-        if let Some(fd) = (0..self.fd_table.len()).find(|fd| self.fd_table[*fd].is_none()) {
-            fd
-        } else {
-            self.fd_table.push(None);
-            self.fd_table.len() - 1
-        }
+    // called when the task gives up the CPU, account the time it has run
+    pub fn stop_running(&mut self) {
+        self.cpu_time_ms += get_time_ms() - self.last_run_ms;
     }
 }
 
 impl TaskControlBlock {
-    pub fn inner_exclusive_access(&self) -> RefMut<'_, TaskControlBlockInner> {
-        self.inner.exclusive_access()
-    }
-    pub fn getpid(&self) -> usize {
-        self.pid.0
+    pub fn inner_exclusive_access(&self) -> SpinNoIrqLockGuard<'_, TaskControlBlockInner> {
+        self.inner.lock()
     }
-    // create a new task, used to create initprocess
-    pub fn new(elf_data: &[u8]) -> Self {
-        // load elf data to a memory set
-        let (memory_set, user_sp, entry_point) = MemorySet::from_elf(elf_data);
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        // alloc a pid and a kernel stack for the task
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
+    // create a new thread in `process`, map user stack and trap context if `alloc_user_res`.
+    // return None if they overlap with existed areas
+    pub fn new(process: Arc<ProcessControlBlock>, alloc_user_res: bool) -> Option<Self> {
+        let res = TaskUserRes::new(Arc::clone(&process), alloc_user_res)?;
+        let trap_cx_ppn = res.trap_cx_ppn();
+        // alloc a kernel stack for the thread
+        let kernel_stack = kstack_alloc();
         let kernel_stack_top = kernel_stack.get_top();
         // push a task context which goes to trap_return to the top of kernel stack
-        let task_control_block = Self{
-            pid: pid_handle,
+        Some(Self {
+            process: Arc::downgrade(&process),
             kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: user_sp,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    memory_set,
-                    parent: None,
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: vec![
-                        // 0 -> stdin
-                        Some(Arc::new(Stdin)),
-                        // 1 -> stdout
-                        Some(Arc::new(Stdout)),
-                        // 2 -> stderr
-                        Some(Arc::new(Stdout)),
-                    ],
-                    signals: SignalFlags::empty(),
-                    signal_mask: SignalFlags::empty(),
-                    handling_sig: -1,
-                    signal_actions: SignalActions::default(),
-                    killed: false,
-                    frozen: false,
-                    trap_ctx_backup: None,
-                })
-            },
-        };
-        //prepare TrapContext in user space
-        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
-        *trap_cx = TrapContext::app_init_context(
-            entry_point,
-            user_sp,
-            KERNEL_SPACE.exclusive_access().token(),
-            kernel_stack_top,
-            trap_handler as usize,
-        );
-        task_control_block
+            on_cpu: AtomicBool::new(false),
+            inner: SpinNoIrqLock::new(TaskControlBlockInner {
+                res: Some(res),
+                trap_cx_ppn,
+                task_cx: TaskContext::goto_trap_return(kernel_stack_top),
+                task_status: TaskStatus::Ready,
+                exit_code: None,
+                priority: DEFAULT_PRIORITY,
+                pass: 0,
+                level: 0,
+                slice_ticks: 0,
+                cpu_time_ms: 0,
+                last_run_ms: 0,
+            }),
+        })
     }
-    pub fn exec(&self, elf_data: &[u8], args: Vec<String>) {
-        // make a memory set using new elf data
-        let (memory_set, mut user_sp, entry_point) = MemorySet::from_elf(elf_data);
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into()) 
-            .unwrap()
-            .ppn();
-        // push arguement to user stack
-        user_sp -= (args.len() + 1) * core::mem::size_of::<usize>();
-        let argv_base = user_sp;
-        // argv stores the address for the address of each args
-        let mut argv: Vec<_> = (0..=args.len())
-            .map(|arg| {
-                translated_refmut(
-                        memory_set.token(),
-                        (argv_base + arg * core::mem::size_of::<usize>()) as *mut usize,
-                )
-            })
-            .collect();
-        // end with 0
-        *argv[args.len()] = 0;
-        for i in 0..args.len() {
-            user_sp -= args[i].len() + 1;
-            *argv[i] = user_sp;
-            let mut p = user_sp;
-            // put the arguement to stack
-            for c in args[i].as_bytes() {
-                *translated_refmut(memory_set.token(), p as *mut u8) = *c;
-                p += 1;
-            }
-            // end with 0
-            *translated_refmut(memory_set.token(), p as *mut u8) = 0;
-        }
-        user_sp -= user_sp % core::mem::size_of::<usize>();
-        // access inner 
-        let mut inner = self.inner_exclusive_access();
-        inner.memory_set = memory_set;
-        inner.trap_cx_ppn = trap_cx_ppn;
-        let mut trap_cx = TrapContext::app_init_context(
-            entry_point,
-            user_sp,
-            KERNEL_SPACE.exclusive_access().token(),
-            self.kernel_stack.get_top(),
-            trap_handler as usize,
-        );
-        trap_cx.x[10] = args.len();
-        trap_cx.x[11] = argv_base;
-        *inner.get_trap_cx() = trap_cx;
-        // release inner automatically
-    }
-    pub fn fork(self: &Arc<Self>) -> Arc<Self> {
-        // get parent TaskControlBlock
-        let mut parent_inner = self.inner_exclusive_access();
-        // copy user space
-        let memory_set = MemorySet::from_existed_user(&parent_inner.memory_set);
-        let trap_cx_ppn = memory_set
-            .translate(VirtAddr::from(TRAP_CONTEXT).into())
-            .unwrap()
-            .ppn();
-        // alloc a pid and a kernel stack in kernel space
-        let pid_handle = pid_alloc();
-        let kernel_stack = KernelStack::new(&pid_handle);
-        let kernel_stack_top = kernel_stack.get_top();
-        // copy fd table
-        let mut new_fd_table: Vec<Option<Arc<dyn File + Send + Sync>>> = Vec::new();
-        for fd in parent_inner.fd_table.iter() {
-            if let Some(file) = fd {
-                new_fd_table.push(Some(file.clone()));
-            } else {
-                new_fd_table.push(None);
-            }
-        }
-        let task_control_block = Arc::new(TaskControlBlock {
-            pid: pid_handle,
-            kernel_stack,
-            inner: unsafe {
-                UPSafeCell::new(TaskControlBlockInner {
-                    trap_cx_ppn,
-                    base_size: parent_inner.base_size,
-                    task_cx: TaskContext::goto_trap_return(kernel_stack_top),
-                    task_status: TaskStatus::Ready,
-                    memory_set,
-                    parent: Some(Arc::downgrade(self)),
-                    children: Vec::new(),
-                    exit_code: 0,
-                    fd_table: new_fd_table,
-                    // inherit the signal_mask and signal_action
-                    signal_mask: parent_inner.signal_mask,
-                    signals: SignalFlags::empty(),
-                    handling_sig: -1,
-                    signal_actions: parent_inner.signal_actions.clone(),
-                    killed: false,
-                    frozen: false,
-                    trap_ctx_backup: None,
-                })
-            },
-        });
-        // add child
-        parent_inner.children.push(task_control_block.clone());
-        // get child TaskControlBlock and modify kernel_sp in trap_cx
-        let trap_cx = task_control_block.inner_exclusive_access().get_trap_cx();
-        trap_cx.kernel_sp = kernel_stack_top;
-        //return 
-        task_control_block
+    // Get the user token of the process
+    pub fn get_user_token(&self) -> usize {
+        let process = self.process.upgrade().unwrap();
+        let inner = process.inner_exclusive_access();
+        inner.memory_set.token()
     }
-}
\ No newline at end of file
+}
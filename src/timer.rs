@@ -1,11 +1,19 @@
+use core::cmp::Ordering;
+
 use crate::config::CLOCK_FREQ;
 use crate::sbi::set_timer;
+use crate::sync::SpinNoIrqLock;
+use crate::task::{wakeup_task, TaskControlBlock};
+use alloc::collections::BinaryHeap;
+use alloc::sync::Arc;
+use lazy_static::*;
 use riscv::register::time;
 
 
 const TICKS_PER_SEC: usize = 100;
 const MSEC_PER_SEC: usize = 1000;
-const USEC_PER_SEC: usize = 1_0000_000;
+const USEC_PER_SEC: usize = 1_000_000;
+const NSEC_PER_MSEC: usize = 1_000_000;
 #[allow(unused)]
 const INF: usize = usize::MAX;
 
@@ -34,4 +42,71 @@ pub fn set_next_trigger() {
 // close timer
 pub fn close_timer() {
     set_timer(INF);
-}
\ No newline at end of file
+}
+
+// time interval used by nanosleep
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct TimeSpec {
+    pub tv_sec: usize,
+    pub tv_nsec: usize,
+}
+
+impl TimeSpec {
+    // convert to milliseconds, rounded up, intervals too long to count are the longest
+    pub fn to_ms(&self) -> usize {
+        self.tv_sec
+            .saturating_mul(MSEC_PER_SEC)
+            .saturating_add((self.tv_nsec + NSEC_PER_MSEC - 1) / NSEC_PER_MSEC)
+    }
+}
+
+// a task sleeping until `expire_ms`
+pub struct TimerCondVar {
+    pub expire_ms: usize,
+    pub task: Arc<TaskControlBlock>,
+}
+
+impl PartialEq for TimerCondVar {
+    fn eq(&self, other: &Self) -> bool {
+        self.expire_ms == other.expire_ms
+    }
+}
+impl Eq for TimerCondVar {}
+impl PartialOrd for TimerCondVar {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for TimerCondVar {
+    // reversed, so that the earliest timer is on the top of `BinaryHeap`
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.expire_ms.cmp(&self.expire_ms)
+    }
+}
+
+lazy_static! {
+    // min-heap of sleeping tasks ordered by expire time
+    static ref TIMERS: SpinNoIrqLock<BinaryHeap<TimerCondVar>> =
+        SpinNoIrqLock::new(BinaryHeap::new());
+}
+
+// wake up `task` at `expire_ms`, the task should be blocked after calling it
+pub fn add_timer(expire_ms: usize, task: Arc<TaskControlBlock>) {
+    TIMERS
+        .lock()
+        .push(TimerCondVar { expire_ms, task });
+}
+
+// wake up all tasks whose timers have expired
+pub fn check_timer() {
+    let current_ms = get_time_ms();
+    let mut timers = TIMERS.lock();
+    while let Some(timer) = timers.peek() {
+        if timer.expire_ms > current_ms {
+            break;
+        }
+        let timer = timers.pop().unwrap();
+        wakeup_task(timer.task);
+    }
+}
@@ -16,6 +16,8 @@ pub struct TrapContext {
     pub kernel_sp: usize,
     // Addr of trap_handler function
     pub trap_handler: usize,
+    // tp of kernel, the id of the hart running this thread, saved in `__restore`
+    pub kernel_tp: usize,
 }
 
 impl TrapContext {
@@ -40,6 +42,7 @@ impl TrapContext {
             kernel_satp,
             kernel_sp,
             trap_handler,
+            kernel_tp: 0,
         };
         cx.set_sp(sp);
         cx
@@ -1,12 +1,14 @@
 mod context;
 
-use crate::config::{TRAMPOLINE, TRAP_CONTEXT};
+use crate::config::TRAMPOLINE;
+use crate::mm::set_hart_token;
+use crate::sync::{irq_disable, irq_enable};
 use crate::syscall::syscall;
 use crate::task::{
-    check_signal_error_of_current, current_add_signal, current_trap_cx, current_user_token,
-    exit_current_and_run_next, handle_signals, suspend_and_run_next, SignalFlags,
+    check_signal_error_of_current, current_add_signal, current_process_exited, current_tick, current_handle_page_fault, current_trap_cx,
+    current_trap_cx_user_va, current_user_token, exit_current_and_run_next, handle_signals, suspend_and_run_next, SignalFlags,
 };
-use crate::timer::set_next_trigger;
+use crate::timer::{check_timer, set_next_trigger};
 use core::arch::{asm, global_asm};
 use riscv::register::{
     mtvec::TrapMode,
@@ -21,8 +23,11 @@ pub fn init() {
 }
 
 fn set_kernel_trap_entry() {
+    extern "C" {
+        fn __kerneltrap();
+    }
     unsafe {
-        stvec::write(trap_from_kernel as usize, TrapMode::Direct);
+        stvec::write(__kerneltrap as usize, TrapMode::Direct);
     }
 }
 
@@ -38,6 +43,13 @@ pub fn enable_timer_interrupt() {
     }
 }
 
+// interrupts of devices routed by PLIC
+pub fn enable_external_interrupt() {
+    unsafe {
+        sie::set_sext();
+    }
+}
+
 #[no_mangle]
 pub fn trap_handler() -> ! {
     set_kernel_trap_entry();
@@ -48,18 +60,29 @@ pub fn trap_handler() -> ! {
             // jump to next instruction anyway
             let mut cx = current_trap_cx();
             cx.sepc += 4;
+            // system calls may take a long time, they can be preempted by timer interrupts
+            irq_enable();
             // get system call return value
-            let result = syscall(cx.x[17], [cx.x[10], cx.x[11], cx.x[12]]);
+            let result = syscall(
+                cx.x[17],
+                [cx.x[10], cx.x[11], cx.x[12], cx.x[13], cx.x[14], cx.x[15]],
+            );
             // cx is changed during sys_exec, so we have to call it again
             cx = current_trap_cx();
             cx.x[10] = result as usize;
         }
+        Trap::Exception(Exception::StorePageFault)
+        | Trap::Exception(Exception::LoadPageFault)
+        | Trap::Exception(Exception::InstructionPageFault) => {
+            // lazy page is touched or copy on write page is written
+            let is_write = scause.cause() == Trap::Exception(Exception::StorePageFault);
+            if !current_handle_page_fault(stval, is_write) {
+                current_add_signal(SignalFlags::SIGSEGV);
+            }
+        }
         Trap::Exception(Exception::StoreFault)
-        | Trap::Exception(Exception::StorePageFault)
         | Trap::Exception(Exception::InstructionFault)
-        | Trap::Exception(Exception::InstructionPageFault)
-        | Trap::Exception(Exception::LoadFault)
-        | Trap::Exception(Exception::LoadPageFault) => {
+        | Trap::Exception(Exception::LoadFault) => {
             /*
             println!(
                 "[kernel] {:?} in application, bad addr = {:#x}, bad instruction = {:#x}, kernel killed it.",
@@ -74,8 +97,10 @@ pub fn trap_handler() -> ! {
             current_add_signal(SignalFlags::SIGILL);
         }
         Trap::Interrupt(Interrupt::SupervisorTimer) => {
-            set_next_trigger();
-            suspend_and_run_next();
+            timer_interrupt();
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            external_interrupt();
         }
         _ => {
             panic!(
@@ -94,14 +119,21 @@ pub fn trap_handler() -> ! {
         println!("[kernel] {}", msg);
         exit_current_and_run_next(errno);
     }
+    // the main thread has exited on another hart
+    if current_process_exited() {
+        exit_current_and_run_next(-9);
+    }
     trap_return();
 }
 
 #[no_mangle]
 pub fn trap_return() -> ! {
+    // a trap after stvec is set to the trampoline can't be handled in kernel
+    irq_disable();
     set_user_trap_entry();
-    let trap_cx_ptr = TRAP_CONTEXT;
+    let trap_cx_ptr = current_trap_cx_user_va();
     let user_satp = current_user_token();
+    set_hart_token(user_satp);
     extern "C" {
         fn __alltraps();
         fn __restore();
@@ -119,11 +151,42 @@ pub fn trap_return() -> ! {
     }
 }
 
+// handle timer interrupts from both user and kernel
+fn timer_interrupt() {
+    set_next_trigger();
+    // wake up sleeping tasks whose time is up
+    check_timer();
+    // switch to other tasks if time slice is used up
+    if current_tick() {
+        suspend_and_run_next();
+    }
+}
+
+// handle external interrupts from both user and kernel
+fn external_interrupt() {
+    crate::board::irq_handler();
+}
+
+// called by `__kerneltrap` with interrupts disabled,
+// registers of the interrupted kernel code are saved on current kernel stack
 #[no_mangle]
-pub fn trap_from_kernel() -> ! {
-    use riscv::register::sepc;
-    println!("stval = {:#x}, sepc = {:#x}", stval::read(), sepc::read());
-    panic!("a trap {:?} from kernel!", scause::read().cause());
+pub fn kernel_trap_handler() {
+    let scause = scause::read();
+    match scause.cause() {
+        Trap::Interrupt(Interrupt::SupervisorTimer) => {
+            // the current task is preempted in kernel, it goes on after `__kerneltrap` when
+            // it is scheduled again, maybe on another hart
+            timer_interrupt();
+        }
+        Trap::Interrupt(Interrupt::SupervisorExternal) => {
+            external_interrupt();
+        }
+        _ => {
+            use riscv::register::sepc;
+            println!("stval = {:#x}, sepc = {:#x}", stval::read(), sepc::read());
+            panic!("a trap {:?} from kernel!", scause.cause());
+        }
+    }
 }
 
 pub use context::TrapContext;